@@ -1,7 +1,9 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::future::Future;
 use std::process::{Child, Command};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
 use anyhow::Result;
@@ -10,11 +12,13 @@ use eframe::emath::Align2;
 use egui::{Context, WidgetText};
 use egui_toast::{Toast, ToastKind, ToastOptions, Toasts};
 use reqwest::Client;
+use futures::StreamExt;
 use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
 use nexus_client::client;
 use nexus_common::non_api_structs::UserData;
 use nexus_client::client::*;
-use nexus_common::{FriendRequest, FriendRequestUuid, UnfriendRequest, Username};
+use nexus_common::{FriendPresence, FriendRequest, FriendRequestUuid, PresenceStatus, PresenceUpdate, Profile, RegisterError, SessionToken, UnfriendRequest, Username};
 
 fn main() -> Result<()> {
     let server_runner = ServerRunner::new();
@@ -25,8 +29,13 @@ fn main() -> Result<()> {
     let rt = tokio::runtime::Runtime::new().unwrap();
     let _enter = rt.enter();
     rt.block_on(async {
-        nexus_client::add_user(&Client::new(), Username::from("malek.localhost:8000").unwrap()).await.unwrap();
-        nexus_client::add_user(&Client::new(), Username::from("lyuma.localhost:9000").unwrap()).await.unwrap();
+        for (username, password) in [("malek@localhost:8000", "malek"), ("lyuma@localhost:9000", "lyuma")] {
+            if let Err(error) = nexus_client::register(&Client::new(), username.parse::<Username>().unwrap(), password, None).await {
+                if error.downcast_ref::<RegisterError>() != Some(&RegisterError::UsernameTaken) {
+                    panic!("{error}");
+                }
+            }
+        }
     });
     eframe::run_native(
         "Nexus Social",
@@ -66,17 +75,28 @@ impl Drop for ServerRunner {
 
 struct MyApp {
     username_entry: String,
+    password_entry: String,
     username: Option<Username>,
+    token: Option<SessionToken>,
     user_data: UserData,
     friend_request_str: String,
     runtime: Option<Runtime>,
     client: Client,
     toasts: Toasts,
+    /// Set by the events task whenever the server reports a change, so the next frame refreshes.
+    events_changed: Arc<AtomicBool>,
+    events_task: Option<JoinHandle<()>>,
+    heartbeat_task: Option<JoinHandle<()>>,
+    friends_presence: Vec<FriendPresence>,
+    /// Profiles of friends and of everyone with a pending friend request, for their display names.
+    profiles: HashMap<Username, Profile>,
 }
 impl MyApp {
     pub fn new(runtime: Runtime) -> Self {
         Self {
             username_entry: "".to_string(),
+            password_entry: "".to_string(),
+            token: None,
             runtime: Some(runtime),
             user_data:Default::default(),
             client: Default::default(),
@@ -85,30 +105,86 @@ impl MyApp {
                 .anchor(Align2::RIGHT_BOTTOM, (10.0, 10.0))
                 .direction(egui::Direction::TopDown),
             friend_request_str: "".to_string(),
+            events_changed: Default::default(),
+            events_task: None,
+            heartbeat_task: None,
+            friends_presence: vec![],
+            profiles: HashMap::new(),
         }
     }
-    fn refresh(&mut self, username: &Username) {
+    /// Follows the user's events stream in the background, reconnecting from the last cursor seen
+    /// whenever the connection drops.
+    fn listen(&mut self, runtime: &Runtime, ctx: &Context, username: Username, token: SessionToken) {
+        let (client, changed, ctx) = (self.client.clone(), self.events_changed.clone(), ctx.clone());
+        self.events_task = Some(runtime.spawn(async move {
+            let mut cursor = None;
+            loop {
+                if let Ok(stream) = client::events(&client, &token, &username, cursor).await {
+                    let mut stream = Box::pin(stream);
+                    while let Some(Ok(event)) = stream.next().await {
+                        cursor = Some(event.cursor);
+                        changed.store(true, Ordering::Relaxed);
+                        ctx.request_repaint();
+                    }
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }));
+    }
+    /// Goes online and keeps the presence alive until logout.
+    fn go_online(&mut self, runtime: &Runtime, username: Username, token: SessionToken) {
+        let client = self.client.clone();
+        self.heartbeat_task = Some(runtime.spawn(async move {
+            let online = PresenceUpdate { status: PresenceStatus::Online, activity: None, instance: None };
+            let _ = client::set_presence(&client, &token, &username, online.clone()).await;
+            loop {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                // The presence ran out while the heartbeats couldn't get through, so set it again.
+                if client::heartbeat(&client, &token, &username).await.is_err() {
+                    let _ = client::set_presence(&client, &token, &username, online.clone()).await;
+                }
+            }
+        }));
+    }
+    fn refresh(&mut self, username: &Username, token: &SessionToken) {
         let runtime = self.runtime.take().unwrap();
         runtime.block_on(async {
-            if let Err(error) = self.sync_data(username).await {
+            if let Err(error) = self.sync_data(username, token).await {
                 self.add_error(error.to_string());
             }
         });
         self.runtime.replace(runtime);
     }
-    async fn sync_data(&mut self, username: &Username) -> Result<()> {
-        self.user_data.sent_friend_requests = client::sent_friend_requests(&self.client, username).await?.into_iter().collect();
-        self.user_data.rec_friend_requests = client::rec_friend_requests(&self.client, username).await?.into_iter().collect();
+    async fn sync_data(&mut self, username: &Username, token: &SessionToken) -> Result<()> {
+        self.user_data.sent_friend_requests = client::sent_friend_requests(&self.client, token, username).await?.into_iter().collect();
+        self.user_data.rec_friend_requests = client::rec_friend_requests(&self.client, token, username).await?.into_iter().collect();
         self.user_data.friend_requests.clear();
         for f in self.user_data.sent_friend_requests.clone() {
-            self.user_data.friend_requests.insert(f.clone(), client::get_friend_request(&self.client, username, f).await?);
+            self.user_data.friend_requests.insert(f.clone(), client::get_friend_request(&self.client, token, username, f).await?);
         }
         for f in self.user_data.rec_friend_requests.clone() {
-            self.user_data.friend_requests.insert(f.clone(), client::get_friend_request(&self.client, username, f).await?);
+            self.user_data.friend_requests.insert(f.clone(), client::get_friend_request(&self.client, token, username, f).await?);
+        }
+        self.user_data.friends = client::get_friends(&self.client, token, username).await?;
+        self.friends_presence = client::get_friends_presence(&self.client, token, username).await?;
+        let mut users = self.user_data.friends.clone();
+        users.extend(self.user_data.friend_requests.values().flat_map(|f| [f.from.clone(), f.to.clone()]).filter(|user| user != username));
+        self.profiles.clear();
+        for user in users {
+            // A profile that can't be fetched just means showing the raw username.
+            if let Ok(profile) = client::get_user_profile(&self.client, token, username, &user).await {
+                self.profiles.insert(user, profile);
+            }
         }
-        self.user_data.friends = client::get_friends(&self.client, username).await?;
         Ok(())
     }
+    /// The display name of `user` if we know it, with the full username to tell people apart.
+    fn display_name(&self, user: &Username) -> String {
+        match self.profiles.get(user).and_then(|profile| profile.display_name.as_ref()) {
+            Some(name) => format!("{name} ({user})"),
+            None => user.to_string(),
+        }
+    }
     fn add_error(&mut self, error: String) {
         self.toasts.add(Toast {
             kind: ToastKind::Error,
@@ -123,12 +199,26 @@ impl MyApp {
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &Context, frame: &mut Frame) {
         let mut errors = vec![];
-        let mut need_refresh = false;
+        let mut need_refresh = self.events_changed.swap(false, Ordering::Relaxed);
         let runtime = self.runtime.take().unwrap();
         egui::CentralPanel::default().show(ctx, |ui| {
-        if let Some(username) = self.username.clone() {
+        if let (Some(username), Some(token)) = (self.username.clone(), self.token.clone()) {
             if ui.button("logout").clicked() {
+                if let Some(task) = self.heartbeat_task.take() {
+                    task.abort();
+                }
+                runtime.block_on(async {
+                    let offline = PresenceUpdate { status: PresenceStatus::Offline, activity: None, instance: None };
+                    let _ = client::set_presence(&self.client, &token, &username, offline).await;
+                    if let Err(error) = client::logout(&self.client, &token, &username).await {
+                        errors.push(error.to_string());
+                    }
+                });
                 self.username.take();
+                self.token.take();
+                if let Some(task) = self.events_task.take() {
+                    task.abort();
+                }
                 return;
             }
             if ui.button("refresh").clicked() {
@@ -136,16 +226,16 @@ impl eframe::App for MyApp {
             }
             ui.text_edit_singleline(&mut self.friend_request_str);
             if ui.button("send friend request").clicked() {
-                match Username::from(&self.friend_request_str) {
-                    None => self.add_error(String::from("friend request username invalid")),
-                    Some(friend_request_username) => {
+                match self.friend_request_str.parse::<Username>() {
+                    Err(error) => self.add_error(format!("friend request username invalid: {error}")),
+                    Ok(friend_request_username) => {
                         runtime.block_on(async {
                             let friend_request = FriendRequest {
                                 from: username.clone(),
                                 to: friend_request_username,
                                 uuid: FriendRequestUuid(uuid::Uuid::new_v4().to_string()),
                             };
-                            match send_friend_request(&self.client.clone(), friend_request).await {
+                            match send_friend_request(&self.client.clone(), &token, friend_request).await {
                                 Ok(_) => {}
                                 Err(error) => self.add_error(error.to_string()),
                             };
@@ -160,13 +250,20 @@ impl eframe::App for MyApp {
                         ui.group(|ui| {
                             if ui.button("unfriend").clicked() {
                                 runtime.block_on(async {
-                                   if let Err(error) = client::unfriend(&self.client, &username, friend).await {
+                                   if let Err(error) = client::unfriend(&self.client, &token, &username, friend).await {
                                        errors.push(error.to_string());
                                    }
                                 });
                                 need_refresh = true;
                             }
-                            ui.label(format!("{}{}", friend.username, friend.website));
+                            ui.label(self.display_name(friend));
+                            if let Some(friend_presence) = self.friends_presence.iter().find(|p| &p.user == friend) {
+                                let presence = &friend_presence.presence;
+                                ui.label(match &presence.activity {
+                                    Some(activity) => format!("{:?}: {activity}", presence.status),
+                                    None => format!("{:?}", presence.status),
+                                });
+                            }
                         });
                     }
                 });
@@ -174,7 +271,15 @@ impl eframe::App for MyApp {
                     for f in &self.user_data.sent_friend_requests {
                         if let Some(f2) = self.user_data.friend_requests.get(f) {
                             ui.group(|ui| {
-                                ui.label(format!("{:#?}", f2));
+                                if ui.button("cancel").clicked() {
+                                    need_refresh = true;
+                                    runtime.block_on(async {
+                                        if let Err(error) = client::cancel_friend_request(&self.client, &token, &username, f2.uuid.clone()).await {
+                                            errors.push(error.to_string());
+                                        }
+                                    });
+                                }
+                                ui.label(format!("to {}", self.display_name(&f2.to)));
                             });
                         }
                     }
@@ -186,7 +291,7 @@ impl eframe::App for MyApp {
                                if ui.button("accept").clicked() {
                                    need_refresh = true;
                                    runtime.block_on(async {
-                                      if let Err(error) = client::accept_friend_request(&self.client, &username, f2.uuid.clone()).await {
+                                      if let Err(error) = client::accept_friend_request(&self.client, &token, &username, f2.uuid.clone()).await {
                                           errors.push(error.to_string());
                                       }
                                    });
@@ -194,12 +299,12 @@ impl eframe::App for MyApp {
                                if ui.button("deny").clicked() {
                                    need_refresh = true;
                                    runtime.block_on(async {
-                                       if let Err(error) = client::deny_friend_request(&self.client, &username, f2.uuid.clone()).await {
+                                       if let Err(error) = client::deny_friend_request(&self.client, &token, &username, f2.uuid.clone()).await {
                                            errors.push(error.to_string());
                                        }
                                    });
                                }
-                               ui.label(format!("{:#?}", f2));
+                               ui.label(format!("from {}", self.display_name(&f2.from)));
                            });
                        }
                    }
@@ -207,14 +312,34 @@ impl eframe::App for MyApp {
             });
         } else {
             ui.text_edit_singleline(&mut self.username_entry);
+            ui.add(egui::TextEdit::singleline(&mut self.password_entry).password(true));
+            if ui.button("register").clicked() {
+                match self.username_entry.parse::<Username>() {
+                    Err(error) => errors.push(error.to_string()),
+                    Ok(username) => {
+                        if let Err(error) = runtime.block_on(nexus_client::register(&self.client, &username, self.password_entry.clone(), None)) {
+                            errors.push(error.to_string());
+                        }
+                    }
+                }
+            }
             if ui.button("login").clicked() {
-                match Username::from(&self.username_entry) {
-                    None => {
-                        self.add_error(String::from("username did not parse"));
+                match self.username_entry.parse::<Username>() {
+                    Err(error) => {
+                        self.add_error(error.to_string());
                     }
-                    Some(username) => {
-                        self.username.replace(username.clone());
-                        need_refresh = true;
+                    Ok(username) => {
+                        match runtime.block_on(client::login(&self.client, &username, self.password_entry.clone())) {
+                            Ok(token) => {
+                                self.listen(&runtime, ctx, username.clone(), token.clone());
+                                self.go_online(&runtime, username.clone(), token.clone());
+                                self.username.replace(username.clone());
+                                self.token.replace(token);
+                                self.password_entry.clear();
+                                need_refresh = true;
+                            }
+                            Err(error) => errors.push(error.to_string()),
+                        }
                     }
                 }
             }
@@ -222,8 +347,8 @@ impl eframe::App for MyApp {
         });
         self.runtime.replace(runtime);
         if need_refresh {
-            if let Some(username) = self.username.clone() {
-                self.refresh(&username);
+            if let (Some(username), Some(token)) = (self.username.clone(), self.token.clone()) {
+                self.refresh(&username, &token);
             }
         }
         for error in errors {
@@ -1,124 +1,372 @@
 use std::ffi::{c_char, CStr, CString};
-use std::process::{Child, Command};
+use std::process::Child;
+#[cfg(test)]
+use std::process::Command;
+#[cfg(test)]
 use std::thread;
-use std::time::Duration;
+#[cfg(test)]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(test)]
 use anyhow::Context;
+#[cfg(test)]
+use futures::StreamExt;
 use reqwest::Client;
-use nexus_common::{FriendRequest, FriendRequestUuid, Invite, InviteUuid, Username};
-use crate::client::{accept_friend_request, deny_friend_request, get_friend_request, get_friends, get_invite, get_rec_invites, get_sent_invites, rec_friend_requests, remove_invite, send_friend_request, send_invite, sent_friend_requests, unfriend};
+use nexus_common::api::{self, Endpoint};
+use nexus_common::{RegisterError, Registration, Username};
+#[cfg(test)]
+use nexus_common::{ApiError, BlockList, BlockTarget, Defederation, AvatarFormat, AvatarMeta, AvatarUuid, AvatarVisibility, Url, DeliveryStatus, EventKind, FieldVisibility, FriendRequestPolicy, InvitePolicy, PrivacySettings, Profile, ProfileSettings, ProfileVisibility, FriendRequest, InstanceRef, InstanceRegistration, InstanceVisibility, InviteStatus, PresenceStatus, PresenceUpdate, FriendRequestUuid, Invite, InviteUuid};
+#[cfg(test)]
+use crate::client::{events, deny_domain, get_federation, reset_domain, block, get_blocks, unblock, get_privacy, set_privacy, get_profile, get_user_profile, set_profile, download_avatar, get_avatar_blobs, remove_avatar_blob, upload_avatar_blob, HashMismatch, ingest_avatar, get_avatars, get_user_avatar, publish_avatar, remove_avatar, set_avatar_visibility, set_default_avatar, cancel_friend_request, accept_invite, decline_invite, resolve_invite, close_instance, get_friends_instances, get_instances, register_instance, update_player_count, get_friends_presence, get_presence, heartbeat, login, set_presence, get_outbox, dismiss_delivery, retry_delivery, accept_friend_request, deny_friend_request, get_friend_request, get_friends, get_invite, get_rec_invites, get_sent_invites, rec_friend_requests, remove_invite, send_friend_request, send_invite, sent_friend_requests, unfriend};
 
 pub mod client {
-    use reqwest::Client;
-    use nexus_common::{FriendRequest, FriendRequestUuid, Invite, InviteUuid, UnfriendRequest, Username};
+    use reqwest::{Client, RequestBuilder};
+    use std::fmt::{Display, Formatter};
+    use nexus_common::{ApiError, AvatarBlob, AvatarBlobs, AvatarFormat, AvatarMeta, AvatarRequest, AvatarUuid, AvatarVisibility, Avatars, BlockList, BlockTarget, Credentials, Defederation, Delivery, Event, FederationPolicy, FriendInstance, FriendPresence, FriendRequest, FriendRequestUuid, Instance, InstanceRef, InstanceRegistration, Invite, InviteUuid, PlayerCountUpdate, Presence, PresenceUpdate, PrivacySettings, Profile, ProfileSettings, SessionToken, UnfriendRequest, Username};
+    use nexus_common::api::{self, Endpoint};
+    use nexus_common::api::client_server::*;
+    use nexus_common::discovery::{Discovery, Resolver};
+    use std::sync::OnceLock;
     use anyhow::Result;
-    use futures::StreamExt;
+    use futures::Stream;
+    use sha2::{Digest, Sha256};
     use crate::username_t;
 
-    pub async fn get_friends(client: &Client, username: impl AsRef<Username>) -> Result<Vec<Username>> {
-        Ok(client.get(username.as_ref().to_url().0 + "/private/get/friends")
-            .send()
-            .await?
-            .json::<_>()
-            .await?)
+    /// Calls endpoint `E` on `username`'s home server. `params` fill any path parameters after
+    /// `:username`. Failures the server reports come back as an [`ApiError`], which callers can get
+    /// at with `downcast_ref`.
+    pub async fn call<E: Endpoint>(client: &Client, token: Option<&SessionToken>, username: &Username, params: &[&str], request: &E::Request) -> Result<E::Response> {
+        let mut builder = request_builder::<E>(client, token, username, params).await?;
+        if E::METHOD == api::Method::Post {
+            builder = builder.json(request);
+        }
+        let bytes = send(builder).await?.bytes().await?;
+        // Endpoints responding with `()` send an empty body.
+        Ok(serde_json::from_slice(if bytes.is_empty() { b"null" } else { &bytes })?)
     }
-    pub async fn send_invite(client: &Client, invite: Invite) -> Result<()> {
-        client.post(invite.from.to_url().0 + "/private/post/send-invite")
-            .json(&invite)
-            .send()
-            .await?;
-        Ok(())
+
+    async fn request_builder<E: Endpoint>(client: &Client, token: Option<&SessionToken>, username: &Username, params: &[&str]) -> Result<RequestBuilder> {
+        let params = [&[username.username.as_str()], params].concat();
+        let url = discover(client, &username.website).await?.url(&E::path(&params));
+        let builder = match E::METHOD {
+            api::Method::Get => client.get(url),
+            api::Method::Post => client.post(url),
+        };
+        Ok(match token {
+            Some(token) => builder.bearer_auth(&token.0),
+            None => builder,
+        })
     }
-    pub async fn remove_invite(client: &Client, username: impl AsRef<Username>, invite_uuid: InviteUuid) -> Result<()> {
-        client.post(username.as_ref().to_url().0 + "/private/post/remove-invite")
-            .json(&invite_uuid)
+    async fn send(builder: RequestBuilder) -> Result<reqwest::Response> {
+        let response = builder
+            .header(api::PROTOCOL_VERSION_HEADER, api::PROTOCOL_VERSION)
             .send()
-            .await?;
-        Ok(())
+            .await
+            .map_err(|e| ApiError::RemoteUnreachable(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(error_from_response(response).await.into());
+        }
+        Ok(response)
     }
-    pub async fn get_rec_invites(client: &Client, username: impl AsRef<Username>) -> Result<Vec<InviteUuid>> {
-        Ok(client.get(username.as_ref().to_url().0 + "/private/get/rec-invites")
-            .send()
-            .await?
-            .json::<_>()
-            .await?)
+
+    /// Looks up the server hosting `domain`'s users. Answers are cached for the whole process.
+    pub async fn discover(client: &Client, domain: &str) -> Result<Discovery> {
+        static RESOLVER: OnceLock<Resolver> = OnceLock::new();
+        Ok(RESOLVER.get_or_init(Resolver::default).resolve(client, domain).await?)
     }
-    pub async fn get_sent_invites(client: &Client, username: impl AsRef<Username>) -> Result<Vec<InviteUuid>> {
-        Ok(client.get(username.as_ref().to_url().0 + "/private/get/sent-invites")
-            .send()
-            .await?
-            .json::<_>()
-            .await?)
-    }
-    pub async fn get_invite(client: &Client, username: impl AsRef<Username>, invite_uuid: InviteUuid) -> Result<Invite> {
-        Ok(client.get(username.as_ref().to_url().0 + "/private/get/invite/" + &invite_uuid.0)
-            .send().await?
-            .json::<_>()
-            .await?)
-    }
-    pub async fn send_friend_request(client: &Client, friend_request: FriendRequest) -> Result<()> {
-        client.post(friend_request.from.to_url().0 + "/private/post/send-friend-request")
-            .json(&friend_request)
-            .send()
-            .await?;
-        Ok(())
+    /// Reads the [`ApiError`] a server sent with a non-2xx response, falling back to one matching
+    /// the status code if the body isn't one.
+    async fn error_from_response(response: reqwest::Response) -> ApiError {
+        let status = response.status().as_u16();
+        let text = response.text().await.unwrap_or_default();
+        serde_json::from_str(&text).unwrap_or_else(|_| ApiError::from_status(status, text))
     }
-    pub async fn rec_friend_requests(client: &Client, username: impl AsRef<Username>) -> Result<Vec<FriendRequestUuid>> {
-        Ok(client.get(username.as_ref().to_url().0 + "/private/get/rec-friend-requests")
-            .send()
-            .await?
-            .json::<_>()
-            .await?)
+
+    pub async fn login(client: &Client, username: impl AsRef<Username>, password: impl Into<String>) -> Result<SessionToken> {
+        call::<api::Login>(client, None, username.as_ref(), &[], &Credentials { password: password.into() }).await
     }
-    pub async fn sent_friend_requests(client: &Client, username: impl AsRef<Username>) -> Result<Vec<FriendRequestUuid>> {
-        Ok(client.get(username.as_ref().to_url().0 + "/private/get/sent-friend-requests")
-            .send()
-            .await?
-            .json::<_>()
-            .await?)
+    pub async fn logout(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<()> {
+        call::<Logout>(client, Some(token), username.as_ref(), &[], &()).await
     }
-    pub async fn get_friend_request(client: &Client, username: impl AsRef<Username>, fuuid: FriendRequestUuid) -> Result<FriendRequest> {
-        Ok(client.get(username.as_ref().to_url().0 + "/private/get/friend-request/" + &fuuid.0)
-            .send()
-            .await?
-            .json::<_>()
-            .await?)
-    }
-    pub async fn accept_friend_request(client: &Client, username: impl AsRef<Username>, fuuid: FriendRequestUuid) -> Result<()> {
-        client
-            .post(username.as_ref().to_url().0 + "/private/post/accept-friend-request")
-            .json(&fuuid)
-            .send()
-            .await?;
-        Ok(())
+    /// Federation messages the user's server hasn't managed to deliver yet, or gave up on.
+    pub async fn get_outbox(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Vec<Delivery>> {
+        call::<GetOutbox>(client, Some(token), username.as_ref(), &[], &()).await
     }
-    pub async fn deny_friend_request(client: &Client, username: impl AsRef<Username>, fuuid: FriendRequestUuid) -> Result<()> {
-        client
-            .post(username.as_ref().to_url().0 + "/private/post/deny-friend-request")
-            .json(&fuuid)
-            .send()
-            .await?;
-        Ok(())
+    pub async fn dismiss_delivery(client: &Client, token: &SessionToken, username: impl AsRef<Username>, id: String) -> Result<()> {
+        call::<DismissDelivery>(client, Some(token), username.as_ref(), &[], &id).await
+    }
+    pub async fn retry_delivery(client: &Client, token: &SessionToken, username: impl AsRef<Username>, id: String) -> Result<()> {
+        call::<RetryDelivery>(client, Some(token), username.as_ref(), &[], &id).await
+    }
+    /// Opens `username`'s events stream. With the cursor of the last event handled it first
+    /// replays everything after it, without one only new events arrive. The stream ends when the
+    /// connection drops, reconnect with the last cursor seen to carry on without missing anything.
+    pub async fn events(client: &Client, token: &SessionToken, username: impl AsRef<Username>, cursor: Option<u64>) -> Result<impl Stream<Item = Result<Event>>> {
+        let mut builder = request_builder::<GetEvents>(client, Some(token), username.as_ref(), &[]).await?;
+        if let Some(cursor) = cursor {
+            builder = builder.header("last-event-id", cursor.to_string());
+        }
+        let response = send(builder).await?;
+        Ok(futures::stream::unfold((Some(response), Vec::new()), |(mut response, mut buffer)| async move {
+            loop {
+                // Messages end with a blank line. Keep-alives are comments with no data.
+                if let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+                    let message = String::from_utf8_lossy(&buffer.drain(..end + 2).collect::<Vec<u8>>()).into_owned();
+                    let data = message.lines()
+                        .filter_map(|line| line.strip_prefix("data:"))
+                        .map(str::trim_start)
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    if data.is_empty() {
+                        continue;
+                    }
+                    return Some((serde_json::from_str(&data).map_err(Into::into), (response, buffer)));
+                }
+                match response.as_mut()?.chunk().await {
+                    Ok(Some(chunk)) => buffer.extend(chunk.iter().filter(|&&byte| byte != b'\r')),
+                    Ok(None) => return None,
+                    Err(error) => return Some((Err(error.into()), (None, buffer))),
+                }
+            }
+        }))
+    }
+    pub async fn get_friends(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Vec<Username>> {
+        call::<GetFriends>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    pub async fn get_presence(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Presence> {
+        call::<GetPresence>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    /// Sets the user's presence and shares it with their friends. It expires unless the client
+    /// calls [`heartbeat`] regularly, about every 30 seconds with the server's default settings.
+    pub async fn set_presence(client: &Client, token: &SessionToken, username: impl AsRef<Username>, update: PresenceUpdate) -> Result<()> {
+        call::<SetPresence>(client, Some(token), username.as_ref(), &[], &update).await
+    }
+    pub async fn heartbeat(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<()> {
+        call::<Heartbeat>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    pub async fn get_friends_presence(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Vec<FriendPresence>> {
+        call::<GetFriendsPresence>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    /// Registers an instance hosted by `username`, for game servers. Call [`update_player_count`]
+    /// regularly or the server drops the instance, after ten minutes with its default settings.
+    pub async fn register_instance(client: &Client, token: &SessionToken, username: impl AsRef<Username>, registration: InstanceRegistration) -> Result<Instance> {
+        call::<RegisterInstance>(client, Some(token), username.as_ref(), &[], &registration).await
+    }
+    pub async fn update_player_count(client: &Client, token: &SessionToken, username: impl AsRef<Username>, instance: InstanceRef, players: u32) -> Result<()> {
+        call::<UpdatePlayerCount>(client, Some(token), username.as_ref(), &[], &PlayerCountUpdate { instance, players }).await
+    }
+    pub async fn close_instance(client: &Client, token: &SessionToken, username: impl AsRef<Username>, instance: InstanceRef) -> Result<()> {
+        call::<CloseInstance>(client, Some(token), username.as_ref(), &[], &instance).await
+    }
+    pub async fn get_instances(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Vec<Instance>> {
+        call::<GetInstances>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    pub async fn get_friends_instances(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Vec<FriendInstance>> {
+        call::<GetFriendsInstances>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    pub async fn get_avatars(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Avatars> {
+        call::<GetAvatars>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    pub async fn publish_avatar(client: &Client, token: &SessionToken, username: impl AsRef<Username>, avatar: AvatarMeta) -> Result<()> {
+        call::<PublishAvatar>(client, Some(token), username.as_ref(), &[], &avatar).await
+    }
+    /// Has the server download and check the avatar before publishing it. Returns what was
+    /// published, including the file's hash and any VRM metadata.
+    pub async fn ingest_avatar(client: &Client, token: &SessionToken, username: impl AsRef<Username>, avatar: AvatarMeta) -> Result<AvatarMeta> {
+        call::<IngestAvatar>(client, Some(token), username.as_ref(), &[], &avatar).await
+    }
+    pub async fn get_avatar_blobs(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<AvatarBlobs> {
+        call::<GetAvatarBlobs>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    /// Stores an avatar file on the user's server. Publish it with the returned link and hash,
+    /// or ingest it to have a VRM checked first.
+    pub async fn upload_avatar_blob(client: &Client, token: &SessionToken, username: impl AsRef<Username>, file: Vec<u8>) -> Result<AvatarBlob> {
+        let builder = request_builder::<UploadAvatarBlob>(client, Some(token), username.as_ref(), &[]).await?;
+        Ok(send(builder.header(reqwest::header::CONTENT_TYPE, "application/octet-stream").body(file)).await?.json().await?)
+    }
+    pub async fn remove_avatar_blob(client: &Client, token: &SessionToken, username: impl AsRef<Username>, hash: String) -> Result<()> {
+        call::<RemoveAvatarBlob>(client, Some(token), username.as_ref(), &[], &hash).await
+    }
+    /// Downloads an avatar's file, resuming with range requests if the connection drops. When the
+    /// avatar has a hash the file is checked against it, and a mismatch comes back as a
+    /// [`HashMismatch`], which callers can get at with `downcast_ref`.
+    pub async fn download_avatar(client: &Client, avatar: &AvatarMeta) -> Result<Vec<u8>> {
+        const ATTEMPTS: usize = 3;
+        let mut file = Vec::new();
+        for attempt in 1..=ATTEMPTS {
+            let mut builder = client.get(&avatar.link.0);
+            if !file.is_empty() {
+                builder = builder.header(reqwest::header::RANGE, format!("bytes={}-", file.len()));
+            }
+            let mut response = send(builder).await?;
+            // Servers without range support send the whole file again.
+            if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+                file.clear();
+            }
+            let finished = loop {
+                match response.chunk().await {
+                    Ok(Some(chunk)) => file.extend_from_slice(&chunk),
+                    Ok(None) => break true,
+                    Err(error) if attempt == ATTEMPTS => return Err(error.into()),
+                    Err(_) => break false,
+                }
+            };
+            if finished {
+                break;
+            }
+        }
+        if let Some(expected) = &avatar.hash {
+            let actual = Sha256::digest(&file).iter().map(|b| format!("{b:02x}")).collect::<String>();
+            if actual != *expected {
+                return Err(HashMismatch { expected: expected.clone(), actual }.into());
+            }
+        }
+        Ok(file)
+    }
+    /// A downloaded avatar file isn't the one the avatar was published with.
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub struct HashMismatch {
+        pub expected: String,
+        pub actual: String,
+    }
+    impl Display for HashMismatch {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "avatar file has hash {}, expected {}", self.actual, self.expected)
+        }
+    }
+    impl std::error::Error for HashMismatch {}
+    pub async fn remove_avatar(client: &Client, token: &SessionToken, username: impl AsRef<Username>, uuid: AvatarUuid) -> Result<()> {
+        call::<RemoveAvatar>(client, Some(token), username.as_ref(), &[], &uuid).await
+    }
+    pub async fn set_default_avatar(client: &Client, token: &SessionToken, username: impl AsRef<Username>, uuid: AvatarUuid) -> Result<()> {
+        call::<SetDefaultAvatar>(client, Some(token), username.as_ref(), &[], &uuid).await
+    }
+    pub async fn set_avatar_visibility(client: &Client, token: &SessionToken, username: impl AsRef<Username>, visibility: AvatarVisibility) -> Result<()> {
+        call::<SetAvatarVisibility>(client, Some(token), username.as_ref(), &[], &visibility).await
+    }
+    /// `user`'s avatar in the first of `formats` they have one in, or their default avatar if
+    /// `formats` is empty.
+    pub async fn get_user_avatar(client: &Client, token: &SessionToken, username: impl AsRef<Username>, user: impl AsRef<Username>, formats: Vec<AvatarFormat>) -> Result<AvatarMeta> {
+        call::<GetUserAvatar>(client, Some(token), username.as_ref(), &[], &AvatarRequest { user: user.as_ref().clone(), formats }).await
+    }
+    pub async fn get_blocks(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<BlockList> {
+        call::<GetBlocks>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    /// Blocks a user or a whole domain, unfriending them. What they send afterwards is dropped
+    /// without them being told.
+    pub async fn block(client: &Client, token: &SessionToken, username: impl AsRef<Username>, target: BlockTarget) -> Result<()> {
+        call::<Block>(client, Some(token), username.as_ref(), &[], &target).await
+    }
+    pub async fn unblock(client: &Client, token: &SessionToken, username: impl AsRef<Username>, target: BlockTarget) -> Result<()> {
+        call::<Unblock>(client, Some(token), username.as_ref(), &[], &target).await
+    }
+    /// Only for the server's admins, like the other federation functions.
+    pub async fn get_federation(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<FederationPolicy> {
+        call::<api::admin::GetFederation>(client, Some(token), username.as_ref(), &[], &()).await
     }
-    pub async fn unfriend(client: &Client, username: impl AsRef<Username>, friend: impl AsRef<Username>) -> Result<()> {
+    pub async fn allow_domain(client: &Client, token: &SessionToken, username: impl AsRef<Username>, domain: impl Into<String>) -> Result<()> {
+        call::<api::admin::AllowDomain>(client, Some(token), username.as_ref(), &[], &domain.into()).await
+    }
+    pub async fn deny_domain(client: &Client, token: &SessionToken, username: impl AsRef<Username>, defederation: Defederation) -> Result<()> {
+        call::<api::admin::DenyDomain>(client, Some(token), username.as_ref(), &[], &defederation).await
+    }
+    pub async fn reset_domain(client: &Client, token: &SessionToken, username: impl AsRef<Username>, domain: impl Into<String>) -> Result<()> {
+        call::<api::admin::ResetDomain>(client, Some(token), username.as_ref(), &[], &domain.into()).await
+    }
+    pub async fn get_privacy(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<PrivacySettings> {
+        call::<GetPrivacy>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    /// Changes who may send the user friend requests and invites. Refused ones fail on the
+    /// sender's side with a [`ApiError::RemoteRejected`] saying why.
+    pub async fn set_privacy(client: &Client, token: &SessionToken, username: impl AsRef<Username>, privacy: PrivacySettings) -> Result<()> {
+        call::<SetPrivacy>(client, Some(token), username.as_ref(), &[], &privacy).await
+    }
+    pub async fn get_profile(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<ProfileSettings> {
+        call::<GetProfile>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    /// Replaces the user's profile. Their friends hear about it through their events streams.
+    pub async fn set_profile(client: &Client, token: &SessionToken, username: impl AsRef<Username>, settings: ProfileSettings) -> Result<()> {
+        call::<SetProfile>(client, Some(token), username.as_ref(), &[], &settings).await
+    }
+    /// `user`'s profile, with only the fields the user may see.
+    pub async fn get_user_profile(client: &Client, token: &SessionToken, username: impl AsRef<Username>, user: impl AsRef<Username>) -> Result<Profile> {
+        call::<GetUserProfile>(client, Some(token), username.as_ref(), &[], user.as_ref()).await
+    }
+    pub async fn send_invite(client: &Client, token: &SessionToken, invite: Invite) -> Result<()> {
+        call::<SendInvite>(client, Some(token), &invite.from, &[], &invite).await
+    }
+    pub async fn remove_invite(client: &Client, token: &SessionToken, username: impl AsRef<Username>, invite_uuid: InviteUuid) -> Result<()> {
+        call::<RemoveInvite>(client, Some(token), username.as_ref(), &[], &invite_uuid).await
+    }
+    pub async fn get_rec_invites(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Vec<InviteUuid>> {
+        call::<GetRecInvites>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    pub async fn get_sent_invites(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Vec<InviteUuid>> {
+        call::<GetSentInvites>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    pub async fn get_invite(client: &Client, token: &SessionToken, username: impl AsRef<Username>, invite_uuid: InviteUuid) -> Result<Invite> {
+        call::<GetInvite>(client, Some(token), username.as_ref(), &[&invite_uuid.0], &()).await
+    }
+    /// Where a received invite leads, including the address to join at.
+    pub async fn resolve_invite(client: &Client, token: &SessionToken, username: impl AsRef<Username>, invite_uuid: InviteUuid) -> Result<Instance> {
+        call::<ResolveInvite>(client, Some(token), username.as_ref(), &[&invite_uuid.0], &()).await
+    }
+    /// Accepts a received invite, telling its sender, and returns where to join.
+    pub async fn accept_invite(client: &Client, token: &SessionToken, username: impl AsRef<Username>, invite_uuid: InviteUuid) -> Result<Instance> {
+        call::<AcceptInvite>(client, Some(token), username.as_ref(), &[], &invite_uuid).await
+    }
+    pub async fn decline_invite(client: &Client, token: &SessionToken, username: impl AsRef<Username>, invite_uuid: InviteUuid) -> Result<()> {
+        call::<DeclineInvite>(client, Some(token), username.as_ref(), &[], &invite_uuid).await
+    }
+    pub async fn send_friend_request(client: &Client, token: &SessionToken, friend_request: FriendRequest) -> Result<()> {
+        call::<SendFriendRequest>(client, Some(token), &friend_request.from, &[], &friend_request).await
+    }
+    pub async fn rec_friend_requests(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Vec<FriendRequestUuid>> {
+        call::<GetRecFriendRequests>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    pub async fn sent_friend_requests(client: &Client, token: &SessionToken, username: impl AsRef<Username>) -> Result<Vec<FriendRequestUuid>> {
+        call::<GetSentFriendRequests>(client, Some(token), username.as_ref(), &[], &()).await
+    }
+    pub async fn get_friend_request(client: &Client, token: &SessionToken, username: impl AsRef<Username>, fuuid: FriendRequestUuid) -> Result<FriendRequest> {
+        call::<GetFriendRequest>(client, Some(token), username.as_ref(), &[&fuuid.0], &()).await
+    }
+    pub async fn accept_friend_request(client: &Client, token: &SessionToken, username: impl AsRef<Username>, fuuid: FriendRequestUuid) -> Result<()> {
+        call::<AcceptFriendRequest>(client, Some(token), username.as_ref(), &[], &fuuid).await
+    }
+    pub async fn deny_friend_request(client: &Client, token: &SessionToken, username: impl AsRef<Username>, fuuid: FriendRequestUuid) -> Result<()> {
+        call::<DenyFriendRequest>(client, Some(token), username.as_ref(), &[], &fuuid).await
+    }
+    pub async fn cancel_friend_request(client: &Client, token: &SessionToken, username: impl AsRef<Username>, fuuid: FriendRequestUuid) -> Result<()> {
+        call::<CancelFriendRequest>(client, Some(token), username.as_ref(), &[], &fuuid).await
+    }
+    pub async fn unfriend(client: &Client, token: &SessionToken, username: impl AsRef<Username>, friend: impl AsRef<Username>) -> Result<()> {
         let username = username.as_ref();
-        client
-            .post(username.to_url().0 + "/private/post/unfriend")
-            .json(&UnfriendRequest{ from: username.clone(), to: friend.as_ref().clone() })
-            .send()
-            .await?;
-        Ok(())
+        call::<Unfriend>(client, Some(token), username, &[], &UnfriendRequest{ from: username.clone(), to: friend.as_ref().clone() }).await
     }
 }
 
-pub async fn add_user(client: &Client, username: impl AsRef<Username>) -> anyhow::Result<()> {
+/// Creates an account on `username`'s home server. Rejections come back as a [`RegisterError`],
+/// which callers can get at with `downcast_ref`.
+pub async fn register(client: &Client, username: impl AsRef<Username>, password: impl Into<String>, invite_code: Option<String>) -> anyhow::Result<()> {
     let username = username.as_ref();
-    client.get(String::from("http://") + &username.website + "/add-user/" + &username.username)
+    let response = client.post(client::discover(client, &username.website).await?.url(api::Register::PATH))
+        .header(api::PROTOCOL_VERSION_HEADER, api::PROTOCOL_VERSION)
+        .json(&Registration { username: username.username.clone(), password: password.into(), invite_code })
         .send().await?;
+    if response.status().is_client_error() {
+        if let Ok(error) = response.json::<RegisterError>().await {
+            return Err(error.into());
+        }
+        anyhow::bail!("registration was rejected");
+    }
+    response.error_for_status()?;
     Ok(())
 }
 
 #[test]
 fn test() {
+    // In memory storage, so every run starts from empty servers.
+    let config = std::env::temp_dir().join("nexus-client-test-config.json");
+    std::fs::write(&config, r#"{ "storage": { "backend": "memory" }, "presence": { "ttl_secs": 3 }, "invites": { "sweep_interval_secs": 1 }, "blobs": { "max_upload_bytes": 4096, "quota_bytes": 6144 }, "avatars": { "allow_private_links": true }, "admins": ["malek"], "rate_limits": { "max_pending_per_domain": 3 } }"#).unwrap();
     Command::new("cargo")
         .arg("build")
         .arg("-p")
@@ -130,6 +378,7 @@ fn test() {
         .arg("nexus-server")
         .arg("--")
         .arg("8000")
+        .env("NEXUS_CONFIG", &config)
         .spawn().unwrap();
     let server2 = Command::new("cargo")
         .arg("run")
@@ -137,6 +386,7 @@ fn test() {
         .arg("nexus-server")
         .arg("--")
         .arg("9000")
+        .env("NEXUS_CONFIG", &config)
         .spawn().unwrap();
     thread::sleep(Duration::from_secs(1));
     tokio::runtime::Runtime::new()
@@ -158,74 +408,401 @@ impl Drop for ServerRunner {
     }
 }
 
+#[cfg(test)]
 async fn wrapper(server_runner: ServerRunner) {
     actual_test().await.unwrap();
 }
 
+/// Serves `body` to every request on a random local port, returning its url.
+#[cfg(test)]
+async fn serve_file(body: Vec<u8>) -> anyhow::Result<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/avatar.vrm", listener.local_addr()?);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request).await;
+            let _ = stream.write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).as_bytes()).await;
+            let _ = stream.write_all(&body).await;
+        }
+    });
+    Ok(url)
+}
+
+#[cfg(test)]
 async fn actual_test() -> anyhow::Result<()> {
+    use nexus_common::fixtures::vrm_model;
     let client = Client::new();
 
-    let malek = Username::from("malek.localhost:8000").unwrap();
-    let lyuma = Username::from("lyuma.localhost:9000").unwrap();
+    let malek = "malek@localhost:8000".parse::<Username>()?;
+    let lyuma = "lyuma@localhost:9000".parse::<Username>()?;
+
+    register(&client, &malek, "malek-password", None).await?;
+    register(&client, &lyuma, "lyuma-password", None).await?;
+    let taken = register(&client, &malek, "other-password", None).await.unwrap_err();
+    assert_eq!(taken.downcast_ref::<RegisterError>(), Some(&RegisterError::UsernameTaken));
+    let invalid = register(&client, &Username { username: String::from("Not Valid"), website: malek.website.clone() }, "password", None).await.unwrap_err();
+    assert_eq!(invalid.downcast_ref::<RegisterError>(), Some(&RegisterError::InvalidUsername));
 
-    add_user(&client, &malek).await?;
-    add_user(&client, &lyuma).await?;
+    let discovery = client::discover(&client, &malek.website).await?;
+    assert_eq!(discovery.server, "http://localhost:8000");
+    assert!(discovery.supports("friends"));
+
+    let wrong_password = login(&client, &malek, "wrong-password").await.unwrap_err();
+    assert!(matches!(wrong_password.downcast_ref::<ApiError>(), Some(ApiError::Unauthorized(_))));
+    // The default config allows 10 login attempts per user per minute.
+    let guessed = "guessed@localhost:8000".parse::<Username>()?;
+    for _ in 0..10 {
+        assert!(login(&client, &guessed, "guess").await.is_err());
+    }
+    let limited = login(&client, &guessed, "guess").await.unwrap_err();
+    assert!(matches!(limited.downcast_ref::<ApiError>(), Some(ApiError::TooManyRequests(_))));
+    let malek_token = login(&client, &malek, "malek-password").await?;
+    let lyuma_token = login(&client, &lyuma, "lyuma-password").await?;
+    let wrong_user = get_friends(&client, &lyuma_token, &malek).await.unwrap_err();
+    assert!(matches!(wrong_user.downcast_ref::<ApiError>(), Some(ApiError::Unauthorized(_))));
+    let missing = get_friend_request(&client, &malek_token, &malek, FriendRequestUuid(String::from("missing"))).await.unwrap_err();
+    assert!(matches!(missing.downcast_ref::<ApiError>(), Some(ApiError::NotFound(_))));
+    let malformed = client.post(format!("http://{}/malek/private/post/accept-friend-request", malek.website))
+        .bearer_auth(&malek_token.0)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body("{")
+        .send().await?;
+    assert_eq!(malformed.status(), reqwest::StatusCode::BAD_REQUEST);
+    assert!(matches!(malformed.json::<ApiError>().await?, ApiError::BadRequest(_)));
 
     let fuuid = FriendRequestUuid(String::from("0"));
 
-    let friends = get_friends(&client, &malek).await?;
+    let friends = get_friends(&client, &malek_token, &malek).await?;
     assert_eq!(friends.len(), 0);
 
+    let forged = client.post(format!("http://{}/lyuma/public/post/send-friend-request", lyuma.website))
+        .json(&FriendRequest { from: malek.clone(), to: lyuma.clone(), uuid: FriendRequestUuid(String::from("forged")) })
+        .send().await?;
+    assert_eq!(forged.status(), reqwest::StatusCode::UNAUTHORIZED);
+    assert_eq!(rec_friend_requests(&client, &lyuma_token, &lyuma).await?.len(), 0);
+
     let friend_request = FriendRequest {
         from: malek.clone(),
         to: lyuma.clone(),
         uuid: fuuid.clone(),
     };
 
-    send_friend_request(&client, friend_request.clone()).await?;
-    let s = sent_friend_requests(&client, &malek).await?;
+    let mut lyuma_events = Box::pin(events(&client, &lyuma_token, &lyuma, None).await?);
+    send_friend_request(&client, &malek_token, friend_request.clone()).await?;
+    let received = tokio::time::timeout(Duration::from_secs(5), lyuma_events.next()).await?.context("stream ended")??;
+    assert_eq!(received.kind, EventKind::FriendRequestReceived(friend_request.clone()));
+    let s = sent_friend_requests(&client, &malek_token, &malek).await?;
     assert_eq!(s.len(), 1);
     assert_eq!(s.first().unwrap().0, fuuid.0);
-    let s = rec_friend_requests(&client, &lyuma).await?;
+    let s = rec_friend_requests(&client, &lyuma_token, &lyuma).await?;
     assert_eq!(s.len(), 1);
     assert_eq!(s.first().unwrap().0, fuuid.0);
-    let friend_request2 = get_friend_request(&client, &lyuma, s.first().unwrap().clone()).await?;
+    let friend_request2 = get_friend_request(&client, &lyuma_token, &lyuma, s.first().unwrap().clone()).await?;
     assert_eq!(friend_request2, friend_request);
-    accept_friend_request(&client, &lyuma, fuuid.clone()).await?;
-    assert_eq!(get_friends(&client, &malek).await?.first().with_context(|| "empty")?.clone(), lyuma);
-    assert_eq!(get_friends(&client, &lyuma).await?.first().with_context(|| "empty")?.clone(), malek);
+    accept_friend_request(&client, &lyuma_token, &lyuma, fuuid.clone()).await?;
+    assert_eq!(get_friends(&client, &malek_token, &malek).await?.first().with_context(|| "empty")?.clone(), lyuma);
+    assert_eq!(get_friends(&client, &lyuma_token, &lyuma).await?.first().with_context(|| "empty")?.clone(), malek);
+
+    let in_world = PresenceUpdate { status: PresenceStatus::Online, activity: Some(String::from("building")), instance: Some(InstanceRef { world_id: String::from("lobby"), instance_id: String::from("1"), domain: malek.website.clone() }) };
+    set_presence(&client, &malek_token, &malek, in_world.clone()).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let seen = get_friends_presence(&client, &lyuma_token, &lyuma).await?;
+    assert_eq!(seen[0].user, malek);
+    assert_eq!((seen[0].presence.status, seen[0].presence.instance.clone()), (PresenceStatus::Online, in_world.instance.clone()));
+    set_presence(&client, &malek_token, &malek, PresenceUpdate { status: PresenceStatus::Invisible, ..in_world.clone() }).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(get_friends_presence(&client, &lyuma_token, &lyuma).await?[0].presence.status, PresenceStatus::Offline);
+    assert_eq!(get_presence(&client, &malek_token, &malek).await?.status, PresenceStatus::Invisible);
+    // Without heartbeats the presence runs out after the test config's 3 second ttl.
+    set_presence(&client, &malek_token, &malek, in_world.clone()).await?;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    heartbeat(&client, &malek_token, &malek).await?;
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(get_friends_presence(&client, &lyuma_token, &lyuma).await?[0].presence.status, PresenceStatus::Online);
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(get_friends_presence(&client, &lyuma_token, &lyuma).await?[0].presence.status, PresenceStatus::Offline);
+    assert_eq!(get_presence(&client, &malek_token, &malek).await?.status, PresenceStatus::Offline);
+
+    let lobby = in_world.instance.clone().unwrap();
+    let registration = InstanceRegistration { instance: lobby.clone(), join_address: String::from("udp://localhost:7777"), capacity: 8, visibility: InstanceVisibility::Friends };
+    assert_eq!(register_instance(&client, &malek_token, &malek, registration.clone()).await?.host, malek);
+    let elsewhere = register_instance(&client, &lyuma_token, &lyuma, registration.clone()).await.unwrap_err();
+    assert!(matches!(elsewhere.downcast_ref::<ApiError>(), Some(ApiError::BadRequest(_))));
+    update_player_count(&client, &malek_token, &malek, lobby.clone(), 3).await?;
+    let overfull = update_player_count(&client, &malek_token, &malek, lobby.clone(), 9).await.unwrap_err();
+    assert!(matches!(overfull.downcast_ref::<ApiError>(), Some(ApiError::BadRequest(_))));
+    set_presence(&client, &malek_token, &malek, in_world.clone()).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let seen = get_friends_instances(&client, &lyuma_token, &lyuma).await?;
+    assert_eq!(seen.len(), 1);
+    assert_eq!((seen[0].instance.players, seen[0].instance.join_address.as_str(), seen[0].friends.clone()), (3, "udp://localhost:7777", vec![malek.clone()]));
+    register_instance(&client, &malek_token, &malek, InstanceRegistration { visibility: InstanceVisibility::Private, ..registration.clone() }).await?;
+    assert_eq!(get_friends_instances(&client, &lyuma_token, &lyuma).await?.len(), 0);
+    close_instance(&client, &malek_token, &malek, lobby.clone()).await?;
+    assert_eq!(get_instances(&client, &malek_token, &malek).await?.len(), 0);
 
     let invite_uuid = InviteUuid(String::from("1"));
 
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
     let invite = Invite {
         from: lyuma.clone(),
         to: malek.clone(),
         uuid: invite_uuid.clone(),
+        instance: lobby.clone(),
+        message: Some(String::from("come build")),
+        created: now,
+        expires: now + 60,
+        status: InviteStatus::Pending,
     };
 
-    send_invite(&client, invite.clone()).await?;
+    send_invite(&client, &lyuma_token, invite.clone()).await?;
+
+    assert_eq!(get_sent_invites(&client, &lyuma_token, &lyuma).await?.len(), 1);
+    assert_eq!(get_rec_invites(&client, &malek_token, &malek).await?.len(), 1);
+    let delivered = Invite { status: InviteStatus::Delivered, ..invite.clone() };
+    assert_eq!(get_invite(&client, &malek_token, &malek, get_rec_invites(&client, &malek_token, &malek).await?.first().unwrap().clone()).await.unwrap(), delivered);
+    assert_eq!(get_invite(&client, &lyuma_token, &lyuma, invite_uuid.clone()).await?.status, InviteStatus::Delivered);
+
+    // Declining cleans up the recipient's side and tells the sender.
+    decline_invite(&client, &malek_token, &malek, invite_uuid.clone()).await?;
+    assert_eq!(get_rec_invites(&client, &malek_token, &malek).await?.len(), 0);
+    assert_eq!(get_invite(&client, &lyuma_token, &lyuma, invite_uuid.clone()).await?.status, InviteStatus::Declined);
+    remove_invite(&client, &lyuma_token, &lyuma, invite_uuid.clone()).await?;
+    assert_eq!(get_sent_invites(&client, &lyuma_token, &lyuma).await?.len(), 0);
 
-    assert_eq!(get_sent_invites(&client, &lyuma).await?.len(), 1);
-    assert_eq!(get_rec_invites(&client, &malek).await?.len(), 1);
-    assert_eq!(get_invite(&client, &malek, get_rec_invites(&client, &malek).await?.first().unwrap().clone()).await.unwrap(), invite);
 
-    remove_invite(&client, &malek, invite_uuid.clone()).await?;
-    remove_invite(&client, &lyuma, invite_uuid.clone()).await?;
-    assert_eq!(get_rec_invites(&client, &malek).await?.len(), 0);
-    assert_eq!(get_sent_invites(&client, &lyuma).await?.len(), 0);
+    unfriend(&client, &malek_token, &malek, &lyuma).await?;
+    assert_eq!(get_friends(&client, &malek_token, &malek).await?.len(), 0);
+    assert_eq!(get_friends(&client, &lyuma_token, &lyuma).await?.len(), 0);
 
+    // Reconnecting from the first cursor replays everything after it, in order.
+    let mut replay = Box::pin(events(&client, &lyuma_token, &lyuma, Some(received.cursor)).await?);
+    let next = tokio::time::timeout(Duration::from_secs(5), replay.next()).await?.context("stream ended")??;
+    assert_eq!(next.cursor, received.cursor + 1);
+    assert_eq!(next.kind, EventKind::InviteUpdated(delivered.clone()));
+    let next = tokio::time::timeout(Duration::from_secs(5), replay.next()).await?.context("stream ended")??;
+    assert_eq!(next.kind, EventKind::InviteUpdated(Invite { status: InviteStatus::Declined, ..delivered.clone() }));
+    let next = tokio::time::timeout(Duration::from_secs(5), replay.next()).await?.context("stream ended")??;
+    assert_eq!(next.kind, EventKind::InviteRemoved(invite_uuid.clone()));
+    let next = tokio::time::timeout(Duration::from_secs(5), replay.next()).await?.context("stream ended")??;
+    assert_eq!(next.kind, EventKind::Unfriended(malek.clone()));
 
-    unfriend(&client, &malek, &lyuma).await?;
-    assert_eq!(get_friends(&client, &malek).await?.len(), 0);
-    assert_eq!(get_friends(&client, &lyuma).await?.len(), 0);
+    // A private instance can still be joined through an invite from its host.
+    register_instance(&client, &malek_token, &malek, InstanceRegistration { visibility: InstanceVisibility::Private, ..registration }).await?;
+    let to_lobby = Invite { from: malek.clone(), to: lyuma.clone(), uuid: InviteUuid(String::from("2")), ..invite.clone() };
+    // They aren't friends any more, and only friends can invite by default.
+    let not_friends = send_invite(&client, &malek_token, to_lobby.clone()).await.unwrap_err();
+    assert!(matches!(not_friends.downcast_ref::<ApiError>(), Some(ApiError::RemoteRejected(_))));
+    assert_eq!(get_invite(&client, &malek_token, &malek, to_lobby.uuid.clone()).await?.status, InviteStatus::Rejected);
+    set_privacy(&client, &lyuma_token, &lyuma, PrivacySettings { invites: InvitePolicy::Anyone, ..Default::default() }).await?;
+    send_invite(&client, &malek_token, to_lobby.clone()).await?;
+    assert_eq!(resolve_invite(&client, &lyuma_token, &lyuma, to_lobby.uuid.clone()).await?.join_address, "udp://localhost:7777");
+    assert_eq!(accept_invite(&client, &lyuma_token, &lyuma, to_lobby.uuid.clone()).await?.join_address, "udp://localhost:7777");
+    assert_eq!(get_rec_invites(&client, &lyuma_token, &lyuma).await?.len(), 0);
+    assert_eq!(get_invite(&client, &malek_token, &malek, to_lobby.uuid.clone()).await?.status, InviteStatus::Accepted);
+    let expired = send_invite(&client, &malek_token, Invite { uuid: InviteUuid(String::from("3")), created: now - 10, expires: now - 5, ..to_lobby.clone() }).await.unwrap_err();
+    assert!(matches!(expired.downcast_ref::<ApiError>(), Some(ApiError::BadRequest(_))));
+    let short = Invite { uuid: InviteUuid(String::from("4")), expires: now + 1, ..to_lobby.clone() };
+    send_invite(&client, &malek_token, short.clone()).await?;
+    // The test config sweeps every second.
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(get_rec_invites(&client, &lyuma_token, &lyuma).await?.len(), 0);
+    // The sender keeps expired invites for a while, so they can see nobody answered.
+    assert_eq!(get_sent_invites(&client, &malek_token, &malek).await?.len(), 2);
+    assert_eq!(get_invite(&client, &malek_token, &malek, short.uuid.clone()).await?.status, InviteStatus::Expired);
+    let swept = resolve_invite(&client, &lyuma_token, &lyuma, short.uuid).await.unwrap_err();
+    assert!(matches!(swept.downcast_ref::<ApiError>(), Some(ApiError::NotFound(_))));
 
-    send_friend_request(&client, friend_request.clone()).await?;
-    deny_friend_request(&client, &lyuma, fuuid.clone()).await?;
+    send_friend_request(&client, &malek_token, friend_request.clone()).await?;
+    deny_friend_request(&client, &lyuma_token, &lyuma, fuuid.clone()).await?;
 
-    assert_eq!(get_friends(&client, &malek).await?.len(), 0);
-    assert_eq!(get_friends(&client, &lyuma).await?.len(), 0);
-    assert_eq!(sent_friend_requests(&client, &malek).await?.len(), 0);
-    assert_eq!(rec_friend_requests(&client, &lyuma).await?.len(), 0);
+    assert_eq!(get_friends(&client, &malek_token, &malek).await?.len(), 0);
+    assert_eq!(get_friends(&client, &lyuma_token, &lyuma).await?.len(), 0);
+    assert_eq!(sent_friend_requests(&client, &malek_token, &malek).await?.len(), 0);
+    assert_eq!(rec_friend_requests(&client, &lyuma_token, &lyuma).await?.len(), 0);
+
+    let cancelled = FriendRequest { uuid: FriendRequestUuid(String::from("3")), ..friend_request.clone() };
+    send_friend_request(&client, &malek_token, cancelled.clone()).await?;
+    assert_eq!(rec_friend_requests(&client, &lyuma_token, &lyuma).await?.len(), 1);
+    cancel_friend_request(&client, &malek_token, &malek, cancelled.uuid.clone()).await?;
+    assert_eq!(sent_friend_requests(&client, &malek_token, &malek).await?.len(), 0);
+    assert_eq!(rec_friend_requests(&client, &lyuma_token, &lyuma).await?.len(), 0);
+    let gone = accept_friend_request(&client, &lyuma_token, &lyuma, cancelled.uuid).await.unwrap_err();
+    assert!(matches!(gone.downcast_ref::<ApiError>(), Some(ApiError::NotFound(_))));
+
+    // Friends of friends need someone in common, ayla here.
+    let ayla = "ayla@localhost:8000".parse::<Username>()?;
+    register(&client, &ayla, "ayla-password", None).await?;
+    let ayla_token = login(&client, &ayla, "ayla-password").await?;
+    for (friend, friend_token, uuid) in [(&lyuma, &lyuma_token, "ayla-lyuma"), (&malek, &malek_token, "ayla-malek")] {
+        send_friend_request(&client, &ayla_token, FriendRequest { from: ayla.clone(), to: friend.clone(), uuid: FriendRequestUuid(String::from(uuid)) }).await?;
+        accept_friend_request(&client, friend_token, friend, FriendRequestUuid(String::from(uuid))).await?;
+    }
+    let guarded = FriendRequest { uuid: FriendRequestUuid(String::from("5")), ..friend_request.clone() };
+    set_privacy(&client, &lyuma_token, &lyuma, PrivacySettings { friend_requests: FriendRequestPolicy::Nobody, ..get_privacy(&client, &lyuma_token, &lyuma).await? }).await?;
+    let refused = send_friend_request(&client, &malek_token, guarded.clone()).await.unwrap_err();
+    assert!(matches!(refused.downcast_ref::<ApiError>(), Some(ApiError::RemoteRejected(message)) if message.contains("only accepts friend requests from nobody")));
+    assert_eq!(sent_friend_requests(&client, &malek_token, &malek).await?.len(), 0);
+    set_privacy(&client, &lyuma_token, &lyuma, PrivacySettings { friend_requests: FriendRequestPolicy::AllowedDomains, allowed_domains: vec![String::from("Example.com")], ..Default::default() }).await?;
+    assert_eq!(get_privacy(&client, &lyuma_token, &lyuma).await?.allowed_domains, vec![String::from("example.com")]);
+    assert!(send_friend_request(&client, &malek_token, guarded.clone()).await.is_err());
+    set_privacy(&client, &lyuma_token, &lyuma, PrivacySettings { friend_requests: FriendRequestPolicy::FriendsOfFriends, ..Default::default() }).await?;
+    send_friend_request(&client, &malek_token, guarded.clone()).await?;
+    assert_eq!(rec_friend_requests(&client, &lyuma_token, &lyuma).await?, vec![guarded.uuid.clone()]);
+    cancel_friend_request(&client, &malek_token, &malek, guarded.uuid.clone()).await?;
+    unfriend(&client, &malek_token, &malek, &ayla).await?;
+    assert!(send_friend_request(&client, &malek_token, guarded.clone()).await.is_err());
+    set_privacy(&client, &lyuma_token, &lyuma, PrivacySettings::default()).await?;
+
+    // Blocking unfriends, and whatever the blocked user sends after that disappears.
+    let self_block = block(&client, &lyuma_token, &lyuma, BlockTarget::User(lyuma.clone())).await.unwrap_err();
+    assert!(matches!(self_block.downcast_ref::<ApiError>(), Some(ApiError::BadRequest(_))));
+    block(&client, &lyuma_token, &lyuma, BlockTarget::User(ayla.clone())).await?;
+    assert!(!get_friends(&client, &lyuma_token, &lyuma).await?.contains(&ayla));
+    assert!(!get_friends(&client, &ayla_token, &ayla).await?.contains(&lyuma));
+    let ignored = FriendRequest { from: ayla.clone(), to: lyuma.clone(), uuid: FriendRequestUuid(String::from("6")) };
+    send_friend_request(&client, &ayla_token, ignored.clone()).await?;
+    assert_eq!(sent_friend_requests(&client, &ayla_token, &ayla).await?, vec![ignored.uuid.clone()]);
+    assert!(rec_friend_requests(&client, &lyuma_token, &lyuma).await?.is_empty());
+    let hidden = get_user_profile(&client, &ayla_token, &ayla, &lyuma).await.unwrap_err();
+    assert!(matches!(hidden.downcast_ref::<ApiError>(), Some(ApiError::NotFound(_))));
+    let blocked_invite = Invite { from: lyuma.clone(), to: ayla.clone(), uuid: InviteUuid(String::from("blocked")), ..invite.clone() };
+    assert!(send_invite(&client, &lyuma_token, blocked_invite).await.is_err());
+    cancel_friend_request(&client, &ayla_token, &ayla, ignored.uuid).await?;
+    // A domain block covers everyone on that server, and is stored the way usernames spell it.
+    block(&client, &lyuma_token, &lyuma, BlockTarget::Domain(String::from("LOCALHOST:8000"))).await?;
+    assert_eq!(get_blocks(&client, &lyuma_token, &lyuma).await?, BlockList { users: vec![ayla.clone()], domains: vec![String::from("localhost:8000")] });
+    send_friend_request(&client, &malek_token, guarded.clone()).await?;
+    assert!(rec_friend_requests(&client, &lyuma_token, &lyuma).await?.is_empty());
+    cancel_friend_request(&client, &malek_token, &malek, guarded.uuid.clone()).await?;
+    unblock(&client, &lyuma_token, &lyuma, BlockTarget::Domain(String::from("localhost:8000"))).await?;
+    unblock(&client, &lyuma_token, &lyuma, BlockTarget::User(ayla.clone())).await?;
+    assert_eq!(get_blocks(&client, &lyuma_token, &lyuma).await?, BlockList::default());
+    get_user_profile(&client, &ayla_token, &ayla, &lyuma).await?;
+
+    let avatar = |uuid: &str, format| AvatarMeta { format, link: Url(format!("https://example.com/{uuid}.glb")), uuid: AvatarUuid(uuid.to_string()), hash: None, vrm: None };
+    publish_avatar(&client, &malek_token, &malek, avatar("rpm", AvatarFormat::ReadyPlayerMe)).await?;
+    publish_avatar(&client, &malek_token, &malek, avatar("vrm", AvatarFormat::Vrm1_0)).await?;
+    let bad_hash = publish_avatar(&client, &malek_token, &malek, AvatarMeta { hash: Some(String::from("nope")), ..avatar("x", AvatarFormat::Vrm1_0) }).await.unwrap_err();
+    assert!(matches!(bad_hash.downcast_ref::<ApiError>(), Some(ApiError::BadRequest(_))));
+    assert_eq!(get_avatars(&client, &malek_token, &malek).await?.default, Some(AvatarUuid(String::from("rpm"))));
+    // They aren't friends any more and avatars are only shown to friends by default.
+    let hidden = get_user_avatar(&client, &lyuma_token, &lyuma, &malek, vec![]).await.unwrap_err();
+    assert!(matches!(hidden.downcast_ref::<ApiError>(), Some(ApiError::NotFound(_))));
+    set_avatar_visibility(&client, &malek_token, &malek, AvatarVisibility::Public).await?;
+    assert_eq!(get_user_avatar(&client, &lyuma_token, &lyuma, &malek, vec![]).await?.uuid.0, "rpm");
+    assert_eq!(get_user_avatar(&client, &lyuma_token, &lyuma, &malek, vec![AvatarFormat::Vrm1_0, AvatarFormat::ReadyPlayerMe]).await?.uuid.0, "vrm");
+    set_default_avatar(&client, &malek_token, &malek, AvatarUuid(String::from("vrm"))).await?;
+    assert_eq!(get_user_avatar(&client, &lyuma_token, &lyuma, &malek, vec![]).await?.uuid.0, "vrm");
+    remove_avatar(&client, &malek_token, &malek, AvatarUuid(String::from("vrm"))).await?;
+    let unsupported = get_user_avatar(&client, &lyuma_token, &lyuma, &malek, vec![AvatarFormat::Vrm1_0]).await.unwrap_err();
+    assert!(matches!(unsupported.downcast_ref::<ApiError>(), Some(ApiError::NotFound(_))));
+    assert_eq!(get_avatars(&client, &malek_token, &malek).await?.default, Some(AvatarUuid(String::from("rpm"))));
+
+    let not_a_model = serve_file(b"not a model".to_vec()).await?;
+    let rejected = ingest_avatar(&client, &malek_token, &malek, AvatarMeta { link: Url(not_a_model), ..avatar("bad", AvatarFormat::Vrm1_0) }).await.unwrap_err();
+    assert!(matches!(rejected.downcast_ref::<ApiError>(), Some(ApiError::BadRequest(_))));
+    let model = serve_file(vrm_model()).await?;
+    let ingested = ingest_avatar(&client, &malek_token, &malek, AvatarMeta { link: Url(model), ..avatar("bot", AvatarFormat::Vrm1_0) }).await?;
+    assert_eq!(ingested.hash.as_ref().map(String::len), Some(64));
+    let fetched = get_user_avatar(&client, &lyuma_token, &lyuma, &malek, vec![AvatarFormat::Vrm1_0]).await?;
+    assert_eq!(fetched.vrm.map(|vrm| vrm.name), Some(String::from("Lobby Bot")));
+    // Clients can't claim metadata the server didn't read itself.
+    publish_avatar(&client, &malek_token, &malek, AvatarMeta { uuid: AvatarUuid(String::from("claimed")), ..ingested.clone() }).await?;
+    assert!(get_avatars(&client, &malek_token, &malek).await?.avatars.iter().any(|avatar| avatar.uuid.0 == "claimed" && avatar.vrm.is_none()));
+
+    // The test config allows 4096 byte files and 6144 bytes per user.
+    let blob = upload_avatar_blob(&client, &malek_token, &malek, vrm_model()).await?;
+    assert_eq!(Some(&blob.hash), ingested.hash.as_ref());
+    assert_eq!(upload_avatar_blob(&client, &malek_token, &malek, vrm_model()).await?, blob);
+    let too_large = upload_avatar_blob(&client, &malek_token, &malek, vec![0; 5000]).await.unwrap_err();
+    assert!(matches!(too_large.downcast_ref::<ApiError>(), Some(ApiError::BadRequest(_))));
+    let filler = upload_avatar_blob(&client, &malek_token, &malek, vec![1; 4000]).await?;
+    let over_quota = upload_avatar_blob(&client, &malek_token, &malek, vec![2; 4000]).await.unwrap_err();
+    assert!(matches!(over_quota.downcast_ref::<ApiError>(), Some(ApiError::Forbidden(_))));
+    remove_avatar_blob(&client, &malek_token, &malek, filler.hash.clone()).await?;
+    assert_eq!(get_avatar_blobs(&client, &malek_token, &malek).await?.used, blob.size);
+    let gone = client.get(&filler.link.0).send().await?;
+    assert_eq!(gone.status(), reqwest::StatusCode::NOT_FOUND);
+    let hosted = ingest_avatar(&client, &malek_token, &malek, AvatarMeta { link: blob.link.clone(), hash: Some(blob.hash.clone()), ..avatar("hosted", AvatarFormat::Vrm1_0) }).await?;
+    let in_use = remove_avatar_blob(&client, &malek_token, &malek, blob.hash.clone()).await.unwrap_err();
+    assert!(matches!(in_use.downcast_ref::<ApiError>(), Some(ApiError::BadRequest(_))));
+    assert_eq!(download_avatar(&client, &hosted).await?, vrm_model());
+    let tampered = download_avatar(&client, &AvatarMeta { hash: Some("0".repeat(64)), ..hosted.clone() }).await.unwrap_err();
+    assert!(tampered.downcast_ref::<HashMismatch>().is_some());
+    let partial = client.get(&blob.link.0).header(reqwest::header::RANGE, "bytes=0-3").send().await?;
+    assert_eq!(partial.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+    assert_eq!(partial.bytes().await?.as_ref(), b"glTF");
+
+    let settings = ProfileSettings {
+        profile: Profile { display_name: Some(String::from("Malek")), bio: Some(String::from("builds worlds")), pronouns: Some(String::from("he/him")), avatar: Some(AvatarUuid(String::from("hosted"))), ..Default::default() },
+        visibility: ProfileVisibility { bio: FieldVisibility::Friends, pronouns: FieldVisibility::Private, ..Default::default() },
+    };
+    set_profile(&client, &malek_token, &malek, settings.clone()).await?;
+    assert_eq!(get_avatars(&client, &malek_token, &malek).await?.default, Some(AvatarUuid(String::from("hosted"))));
+    assert_eq!(get_profile(&client, &malek_token, &malek).await?.profile.pronouns.as_deref(), Some("he/him"));
+    let seen = get_user_profile(&client, &lyuma_token, &lyuma, &malek).await?;
+    assert_eq!((seen.name_for(&malek), seen.bio, seen.pronouns), (String::from("Malek"), None, None));
+    let too_long = set_profile(&client, &malek_token, &malek, ProfileSettings { profile: Profile { bio: Some("a".repeat(501)), ..Default::default() }, ..settings.clone() }).await.unwrap_err();
+    assert!(matches!(too_long.downcast_ref::<ApiError>(), Some(ApiError::BadRequest(_))));
+    // Friends see more, and their servers are told about changes.
+    let refriend = FriendRequest { uuid: FriendRequestUuid(String::from("4")), ..friend_request.clone() };
+    send_friend_request(&client, &malek_token, refriend.clone()).await?;
+    accept_friend_request(&client, &lyuma_token, &lyuma, refriend.uuid).await?;
+    assert_eq!(get_user_profile(&client, &lyuma_token, &lyuma, &malek).await?.bio.as_deref(), Some("builds worlds"));
+    let mut lyuma_events = Box::pin(events(&client, &lyuma_token, &lyuma, None).await?);
+    set_profile(&client, &malek_token, &malek, ProfileSettings { profile: Profile { display_name: Some(String::from("Malek the Builder")), ..settings.profile.clone() }, ..settings.clone() }).await?;
+    let updated = tokio::time::timeout(Duration::from_secs(5), lyuma_events.next()).await?.context("stream ended")??;
+    assert_eq!(updated.kind, EventKind::ProfileUpdated(malek.clone()));
+    assert_eq!(get_user_profile(&client, &lyuma_token, &lyuma, &malek).await?.display_name.as_deref(), Some("Malek the Builder"));
+
+    // Nothing listens on 9100, so the request waits in the outbox instead of failing.
+    let ghost = "ghost@localhost:9100".parse::<Username>()?;
+    send_friend_request(&client, &malek_token, FriendRequest { from: malek.clone(), to: ghost, uuid: FriendRequestUuid(String::from("2")) }).await?;
+    let outbox = get_outbox(&client, &malek_token, &malek).await?;
+    assert_eq!(outbox.len(), 1);
+    assert_eq!(outbox[0].status, DeliveryStatus::Pending);
+    assert!(outbox[0].last_error.is_some());
+    // Only failed deliveries can be dismissed or retried.
+    let pending = dismiss_delivery(&client, &malek_token, &malek, outbox[0].id.clone()).await.unwrap_err();
+    assert!(matches!(pending.downcast_ref::<ApiError>(), Some(ApiError::NotFound(_))));
+    assert!(retry_delivery(&client, &malek_token, &malek, outbox[0].id.clone()).await.is_err());
+
+    // Only admins can change who the server federates with.
+    let not_admin = deny_domain(&client, &lyuma_token, &lyuma, Defederation { domain: String::from("localhost:8000"), sever_friendships: false }).await.unwrap_err();
+    assert!(matches!(not_admin.downcast_ref::<ApiError>(), Some(ApiError::Forbidden(_))));
+    let mut malek_events = Box::pin(events(&client, &malek_token, &malek, None).await?);
+    deny_domain(&client, &malek_token, &malek, Defederation { domain: String::from("LOCALHOST:9000"), sever_friendships: true }).await?;
+    assert_eq!(get_federation(&client, &malek_token, &malek).await?.denied, vec![String::from("localhost:9000")]);
+    let severed = tokio::time::timeout(Duration::from_secs(5), malek_events.next()).await?.context("stream ended")??;
+    assert_eq!(severed.kind, EventKind::Defederated(String::from("localhost:9000")));
+    assert!(!get_friends(&client, &malek_token, &malek).await?.contains(&lyuma));
+    // Neither server gets through to the other any more.
+    let outbound = get_user_profile(&client, &malek_token, &malek, &lyuma).await.unwrap_err();
+    assert!(matches!(outbound.downcast_ref::<ApiError>(), Some(ApiError::Forbidden(_))));
+    let inbound = send_friend_request(&client, &lyuma_token, FriendRequest { from: lyuma.clone(), to: malek.clone(), uuid: FriendRequestUuid(String::from("7")) }).await.unwrap_err();
+    assert!(matches!(inbound.downcast_ref::<ApiError>(), Some(ApiError::RemoteRejected(_))));
+    reset_domain(&client, &malek_token, &malek, "localhost:9000").await?;
+    assert!(get_federation(&client, &malek_token, &malek).await?.denied.is_empty());
+    get_user_profile(&client, &malek_token, &malek, &lyuma).await?;
+
+    // The test config lets a user have 3 unanswered friend requests from one domain. Later ones
+    // are answered with a 429, so they wait in the outbox until there is room.
+    let mira = "mira@localhost:9000".parse::<Username>()?;
+    register(&client, &mira, "mira-password", None).await?;
+    let mira_token = login(&client, &mira, "mira-password").await?;
+    for uuid in ["m1", "m2", "m3", "m4"] {
+        send_friend_request(&client, &malek_token, FriendRequest { from: malek.clone(), to: mira.clone(), uuid: FriendRequestUuid(String::from(uuid)) }).await?;
+    }
+    assert_eq!(rec_friend_requests(&client, &mira_token, &mira).await?.len(), 3);
+    let outbox = get_outbox(&client, &malek_token, &malek).await?;
+    let throttled = outbox.iter().find(|delivery| delivery.url.contains("mira")).context("throttled request not in the outbox")?;
+    assert_eq!(throttled.status, DeliveryStatus::Pending);
+    assert!(throttled.last_error.as_deref().is_some_and(|error| error.starts_with("too many requests")));
+    // Retried no sooner than the Retry-After of an hour asks.
+    assert!(throttled.next_attempt >= throttled.created + 60 * 60);
 
     Ok(())
 }
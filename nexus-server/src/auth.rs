@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use anyhow::Context;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Path};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::Extension;
+use serde::{Deserialize, Serialize};
+use nexus_common::{ApiError, SessionToken};
+use crate::{unix_time, AppError, Result, State};
+use crate::storage::Table;
+
+/// How long a session token stays valid after login.
+const SESSION_LIFETIME_SECS: u64 = 60 * 60 * 24 * 30;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SessionData {
+    username: String,
+    expires: u64,
+}
+
+impl State {
+    pub fn set_password(&self, user: impl AsRef<str>, password: &str) -> Result<()> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow::anyhow!("Error hashing password: {e}"))?
+            .to_string();
+        self.storage.insert(Table::Credentials, user.as_ref(), hash.as_bytes())?;
+        Ok(())
+    }
+    pub fn check_password(&self, user: impl AsRef<str>, password: &str) -> Result<bool> {
+        let Some(hash) = self.storage.get(Table::Credentials, user.as_ref())? else { return Ok(false) };
+        let hash = String::from_utf8(hash)?;
+        let hash = PasswordHash::new(&hash).map_err(|e| anyhow::anyhow!("Stored password hash is invalid: {e}"))?;
+        Ok(Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    }
+    pub fn create_session(&self, user: impl AsRef<str>) -> Result<SessionToken> {
+        let token = rand::random::<[u8; 32]>().iter().map(|b| format!("{b:02x}")).collect::<String>();
+        let session = SessionData {
+            username: user.as_ref().to_string(),
+            expires: unix_time() + SESSION_LIFETIME_SECS,
+        };
+        self.storage.insert(Table::Sessions, &token, &serde_json::to_vec(&session)?)?;
+        Ok(SessionToken(token))
+    }
+    pub fn remove_session(&self, token: &SessionToken) -> Result<()> {
+        self.storage.remove(Table::Sessions, &token.0)?;
+        Ok(())
+    }
+    fn session_user(&self, token: &SessionToken) -> Result<Option<String>> {
+        let Some(session) = self.storage.get(Table::Sessions, &token.0)? else { return Ok(None) };
+        let session: SessionData = serde_json::from_slice(&session)?;
+        if session.expires < unix_time() {
+            self.storage.remove(Table::Sessions, &token.0)?;
+            return Ok(None);
+        }
+        Ok(Some(session.username))
+    }
+}
+
+/// Extractor for `/:username/private/*` routes. Requires an `Authorization: Bearer <token>` header
+/// whose session belongs to the username in the path, and yields that username.
+pub struct Session(pub String, pub SessionToken);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Session {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, s: &S) -> std::result::Result<Self, Self::Rejection> {
+        let unauthorized = |msg: &str| AppError::from(ApiError::Unauthorized(msg.to_string()));
+        let Extension(state) = Extension::<State>::from_request_parts(parts, s).await?;
+        let Path(params) = Path::<HashMap<String, String>>::from_request_parts(parts, s).await
+            .map_err(|e| ApiError::BadRequest(e.body_text()))?;
+        let username = params.get("username")
+            .context("Route has no username")?;
+        let token = parts.headers.get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .map(|token| SessionToken(token.to_string()))
+            .ok_or_else(|| unauthorized("Missing session token"))?;
+        match state.session_user(&token)? {
+            Some(session_user) if &session_user == username => {
+                state.check_session_rate(&token.0)?;
+                Ok(Session(session_user, token))
+            }
+            _ => Err(unauthorized("Invalid session token")),
+        }
+    }
+}
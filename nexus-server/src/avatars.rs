@@ -0,0 +1,200 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use reqwest::redirect::Policy;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use nexus_common::api::Endpoint;
+use nexus_common::api::server_server::GetAvatar;
+use nexus_common::{ApiError, AvatarFormat, AvatarMeta, AvatarQuery, AvatarRequest, AvatarUuid, AvatarVisibility, Username};
+use crate::{blobs, vrm, Result, State, CONNECT_TIMEOUT};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AvatarConfig {
+    /// The largest avatar file the server downloads when ingesting a link.
+    pub max_ingest_bytes: u64,
+    /// How long downloading one may take, so a slow host can't hold the request open.
+    pub ingest_timeout_secs: u64,
+    /// Lets ingested links point at loopback and private network addresses. Only for test setups,
+    /// since it lets users make the server fetch from its own network.
+    pub allow_private_links: bool,
+}
+
+impl Default for AvatarConfig {
+    fn default() -> Self {
+        Self { max_ingest_bytes: 64 * 1024 * 1024, ingest_timeout_secs: 60, allow_private_links: false }
+    }
+}
+
+/// Whether `ip` can be reached from the internet, as opposed to this machine or its network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let shared = a == 100 && (64..128).contains(&b);
+            !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation() || shared || a == 0)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() || ip.is_unique_local() || ip.is_unicast_link_local()),
+        },
+    }
+}
+
+fn no_avatar(user: &Username) -> ApiError {
+    ApiError::NotFound(format!("{user} has no avatar you can use"))
+}
+
+/// Rejects avatars whose link or hash other servers and clients couldn't use.
+fn check_avatar(avatar: &AvatarMeta) -> Result<()> {
+    (!avatar.uuid.0.is_empty()).then_some(()).ok_or_else(|| ApiError::BadRequest("Avatar uuid is empty".to_string()))?;
+    let link = reqwest::Url::parse(&avatar.link.0).map_err(|e| ApiError::BadRequest(format!("Avatar link is not a url: {e}")))?;
+    matches!(link.scheme(), "http" | "https").then_some(()).ok_or_else(|| ApiError::BadRequest("Avatar links must be http or https".to_string()))?;
+    if let Some(hash) = &avatar.hash {
+        blobs::is_sha256(hash).then_some(()).ok_or_else(|| ApiError::BadRequest("Avatar hash must be a lowercase hex SHA-256".to_string()))?;
+    }
+    Ok(())
+}
+
+impl State {
+    /// Publishes an avatar as the client describes it. Use [`State::ingest_avatar`] to have the
+    /// server check the file instead.
+    pub fn publish_avatar(&self, username: &str, avatar: AvatarMeta) -> Result<()> {
+        check_avatar(&avatar)?;
+        self.store_avatar(username, AvatarMeta { vrm: None, ..avatar })
+    }
+    /// Downloads the avatar at `avatar.link`, hashes it and, for VRMs, validates the model and
+    /// reads its metadata, then publishes the result.
+    pub async fn ingest_avatar(&self, username: &str, avatar: AvatarMeta) -> Result<AvatarMeta> {
+        check_avatar(&avatar)?;
+        let bytes = self.download_avatar(&avatar.link.0).await?;
+        let hash = blobs::sha256_hex(&bytes);
+        if avatar.hash.as_ref().is_some_and(|expected| *expected != hash) {
+            return Err(ApiError::BadRequest(format!("Avatar file has hash {hash}, not the one given")).into());
+        }
+        let vrm = match avatar.format {
+            AvatarFormat::Vrm1_0 => Some(vrm::read_vrm(&bytes)?),
+            AvatarFormat::ReadyPlayerMe => None,
+        };
+        let avatar = AvatarMeta { hash: Some(hash), vrm, ..avatar };
+        self.store_avatar(username, avatar.clone())?;
+        Ok(avatar)
+    }
+    /// Blobs this server hosts are read from storage. Anything else is only fetched from a public
+    /// address, resolved once and pinned so the name can't be pointed elsewhere in between, and
+    /// without following redirects. That takes a client of its own, with the same connect timeout
+    /// as the shared one.
+    async fn download_avatar(&self, link: &str) -> Result<Vec<u8>> {
+        let max = self.avatar_config.max_ingest_bytes;
+        let too_large = || ApiError::BadRequest(format!("Avatar files are limited to {max} bytes"));
+        if let Some(hash) = self.hosted_blob_hash(link) {
+            let bytes = self.avatar_blob(hash)?;
+            (bytes.len() as u64 <= max).then_some(()).ok_or_else(too_large)?;
+            return Ok(bytes);
+        }
+        let url = reqwest::Url::parse(link).map_err(|e| ApiError::BadRequest(format!("Avatar link is not a url: {e}")))?;
+        let host = url.host_str().ok_or_else(|| ApiError::BadRequest("Avatar link has no host".to_string()))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let lookup = tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port));
+        let addr = tokio::time::timeout(CONNECT_TIMEOUT, lookup).await
+            .map_err(|_| ApiError::RemoteUnreachable(format!("Resolving {host} timed out")))?
+            .map_err(|e| ApiError::RemoteUnreachable(format!("Could not resolve {host}: {e}")))?
+            .find(|addr| self.avatar_config.allow_private_links || is_public(addr.ip()))
+            .ok_or_else(|| ApiError::BadRequest(format!("Avatar link {host} has no public address")))?;
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(Duration::from_secs(self.avatar_config.ingest_timeout_secs))
+            .resolve(host, SocketAddr::new(addr.ip(), port))
+            .build()?;
+        let mut response = client.get(url).send().await
+            .map_err(|e| ApiError::RemoteUnreachable(format!("Could not download the avatar: {e}")))?;
+        if !response.status().is_success() {
+            return Err(ApiError::BadRequest(format!("Downloading the avatar failed with {}", response.status())).into());
+        }
+        if response.content_length().is_some_and(|length| length > max) {
+            return Err(too_large().into());
+        }
+        let mut bytes = vec![];
+        while let Some(chunk) = response.chunk().await.map_err(|e| ApiError::RemoteUnreachable(format!("Could not download the avatar: {e}")))? {
+            bytes.extend_from_slice(&chunk);
+            if bytes.len() as u64 > max {
+                return Err(too_large().into());
+            }
+        }
+        Ok(bytes)
+    }
+    fn store_avatar(&self, username: &str, avatar: AvatarMeta) -> Result<()> {
+        self.user_mut(username, |user| {
+            let avatars = &mut user.avatars;
+            match avatars.avatars.iter_mut().find(|existing| existing.uuid == avatar.uuid) {
+                Some(existing) => *existing = avatar.clone(),
+                None => avatars.avatars.push(avatar.clone()),
+            }
+            avatars.default.get_or_insert_with(|| avatar.uuid.clone());
+        })
+    }
+    /// Removes an avatar. If it was the default, the oldest remaining one takes its place.
+    pub fn remove_avatar(&self, username: &str, uuid: &AvatarUuid) -> Result<()> {
+        self.try_user_mut(username, |user| {
+            let avatars = &mut user.avatars;
+            let index = avatars.avatars.iter().position(|avatar| avatar.uuid == *uuid).ok_or_else(|| ApiError::NotFound("AvatarUuid not found".to_string()))?;
+            avatars.avatars.remove(index);
+            if avatars.default.as_ref() == Some(uuid) {
+                avatars.default = avatars.avatars.first().map(|avatar| avatar.uuid.clone());
+            }
+            Ok(())
+        })
+    }
+    pub fn set_default_avatar(&self, username: &str, uuid: &AvatarUuid) -> Result<()> {
+        self.try_user_mut(username, |user| {
+            user.avatars.avatars.iter().any(|avatar| avatar.uuid == *uuid).then_some(()).ok_or_else(|| ApiError::NotFound("AvatarUuid not found".to_string()))?;
+            user.avatars.default = Some(uuid.clone());
+            Ok(())
+        })
+    }
+    pub fn set_avatar_visibility(&self, username: &str, visibility: AvatarVisibility) -> Result<()> {
+        self.user_mut(username, |user| user.avatars.visibility = visibility)
+    }
+    /// The avatar a local user shows `viewer`, in the first of `query.formats` they have one in.
+    pub fn local_avatar(&self, username: &str, query: &AvatarQuery) -> Result<AvatarMeta> {
+        let owner = self.local_user(username);
+        let user = self.user(username)?;
+        let visible = match user.avatars.visibility {
+            AvatarVisibility::Public => true,
+            AvatarVisibility::Friends => query.from == owner || user.friends.contains(&query.from),
+        };
+        visible.then_some(()).ok_or_else(|| no_avatar(&owner))?;
+        Ok(user.avatars.negotiate(&query.formats).cloned().ok_or_else(|| no_avatar(&owner))?)
+    }
+    /// Looks up `request.user`'s avatar for a local user, asking the user's server if they are remote.
+    pub async fn user_avatar(&self, username: &str, request: AvatarRequest) -> Result<AvatarMeta> {
+        let query = AvatarQuery { from: self.local_user(username), formats: request.formats };
+        let user = request.user;
+        if user.website == self.domain {
+            return self.local_avatar(&user.username, &query);
+        }
+        let path = GetAvatar::path(&[&user.username]);
+        let response = self.federated_post(&self.discover(&user.website).await?, &user.website, &path, &query).await?;
+        match response.status() {
+            status if status.is_success() => Ok(response.json().await?),
+            StatusCode::NOT_FOUND => Err(no_avatar(&user).into()),
+            status => Err(ApiError::RemoteRejected(format!("{} answered {status}", user.website)).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fe80::1", "fd00::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::BTreeSet;
+use axum::body::{Body, HttpBody};
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use nexus_common::api::{Endpoint, GetAvatarBlob};
+use nexus_common::{ApiError, AvatarBlob, AvatarBlobs, Url};
+use crate::storage::Table;
+use crate::{Result, State};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlobConfig {
+    /// The largest avatar file a user can upload.
+    pub max_upload_bytes: u64,
+    /// How much a user can store in total. A file several users uploaded counts for each of them.
+    pub quota_bytes: u64,
+}
+
+impl Default for BlobConfig {
+    fn default() -> Self {
+        Self { max_upload_bytes: 64 * 1024 * 1024, quota_bytes: 256 * 1024 * 1024 }
+    }
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+fn no_such_blob(hash: &str) -> ApiError {
+    ApiError::NotFound(format!("No avatar file {hash}"))
+}
+
+// A blob is stored together with the local users who uploaded it, so the last of them to remove
+// it deletes the file in the same swap: the owners as a line of JSON, then the file.
+fn encode_blob(owners: &BTreeSet<String>, data: &[u8]) -> Result<Vec<u8>> {
+    let mut bytes = serde_json::to_vec(owners)?;
+    bytes.push(b'\n');
+    bytes.extend_from_slice(data);
+    Ok(bytes)
+}
+
+fn decode_blob(bytes: &[u8]) -> Result<(BTreeSet<String>, &[u8])> {
+    let split = bytes.iter().position(|&b| b == b'\n').ok_or_else(|| anyhow::anyhow!("Avatar blob has no owners line"))?;
+    Ok((serde_json::from_slice(&bytes[..split])?, &bytes[split + 1..]))
+}
+
+impl State {
+    fn blob_link(&self, hash: &str) -> Url {
+        Url(format!("{}{}", self.public_url, GetAvatarBlob::path(&[hash])))
+    }
+    /// The hash of the blob `link` points to, if it is one this server hosts.
+    pub fn hosted_blob_hash<'a>(&self, link: &'a str) -> Option<&'a str> {
+        link.strip_prefix(&self.blob_link("").0).filter(|hash| is_sha256(hash))
+    }
+    pub fn avatar_blobs(&self, username: &str) -> Result<AvatarBlobs> {
+        let user = self.user(username)?;
+        let mut blobs = user.avatar_blobs.iter()
+            .map(|(hash, size)| AvatarBlob { hash: hash.clone(), size: *size, link: self.blob_link(hash) })
+            .collect::<Vec<_>>();
+        blobs.sort_by(|a, b| a.hash.cmp(&b.hash));
+        Ok(AvatarBlobs { used: blobs.iter().map(|blob| blob.size).sum(), blobs, quota: self.blob_config.quota_bytes })
+    }
+    /// Stores an uploaded avatar file for `username`, charging it to their quota unless they
+    /// already store the same file.
+    pub async fn upload_avatar_blob(&self, username: &str, mut body: Body) -> Result<AvatarBlob> {
+        let max = self.blob_config.max_upload_bytes;
+        let mut data = vec![];
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.map_err(|e| ApiError::BadRequest(format!("Could not read the upload: {e}")))?);
+            (data.len() as u64 <= max).then_some(()).ok_or_else(|| ApiError::BadRequest(format!("Avatar files are limited to {max} bytes")))?;
+        }
+        (!data.is_empty()).then_some(()).ok_or_else(|| ApiError::BadRequest("Avatar file is empty".to_string()))?;
+        let hash = sha256_hex(&data);
+        let size = data.len() as u64;
+        let quota = self.blob_config.quota_bytes;
+        self.try_user_mut(username, |user| {
+            if !user.avatar_blobs.contains_key(&hash) {
+                let used = user.avatar_blobs.values().sum::<u64>();
+                (used + size <= quota).then_some(()).ok_or_else(|| ApiError::Forbidden(format!("Storing this file would exceed the {quota} byte quota, {used} bytes are in use")))?;
+                user.avatar_blobs.insert(hash.clone(), size);
+            }
+            Ok(())
+        })?;
+        loop {
+            let old = self.storage.get(Table::AvatarBlobs, &hash)?;
+            let mut owners = match &old {
+                Some(old) => decode_blob(old)?.0,
+                None => BTreeSet::new(),
+            };
+            if !owners.insert(username.to_string()) {
+                break;
+            }
+            if self.storage.compare_and_swap(Table::AvatarBlobs, &hash, old.as_deref(), Some(&encode_blob(&owners, &data)?))? {
+                break;
+            }
+        }
+        Ok(AvatarBlob { link: self.blob_link(&hash), hash, size })
+    }
+    /// Frees a file from `username`'s quota, deleting it once no local user stores it.
+    pub fn remove_avatar_blob(&self, username: &str, hash: &str) -> Result<()> {
+        self.try_user_mut(username, |user| {
+            let in_use = user.avatars.avatars.iter().any(|avatar| avatar.hash.as_deref() == Some(hash));
+            (!in_use).then_some(()).ok_or_else(|| ApiError::BadRequest("Avatar file is still used by an avatar".to_string()))?;
+            user.avatar_blobs.remove(hash).ok_or_else(|| no_such_blob(hash))?;
+            Ok(())
+        })?;
+        loop {
+            let Some(old) = self.storage.get(Table::AvatarBlobs, hash)? else { return Ok(()) };
+            let (mut owners, data) = decode_blob(&old)?;
+            if !owners.remove(username) {
+                return Ok(());
+            }
+            let new = match owners.is_empty() {
+                true => None,
+                false => Some(encode_blob(&owners, data)?),
+            };
+            if self.storage.compare_and_swap(Table::AvatarBlobs, hash, Some(&old), new.as_deref())? {
+                return Ok(());
+            }
+        }
+    }
+    pub fn avatar_blob(&self, hash: &str) -> Result<Vec<u8>> {
+        let bytes = self.storage.get(Table::AvatarBlobs, hash)?.ok_or_else(|| no_such_blob(hash))?;
+        Ok(decode_blob(&bytes)?.1.to_vec())
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum ByteRange {
+    Full,
+    /// First and last byte, inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Reads a `Range` header against a file of `len` bytes. Anything but a single, well formed
+/// `bytes` range is ignored in favour of the whole file, as RFC 9110 allows.
+fn byte_range(range: Option<&str>, len: u64) -> ByteRange {
+    let Some((start, end)) = range.and_then(|range| range.trim().strip_prefix("bytes=")).filter(|spec| !spec.contains(',')).and_then(|spec| spec.split_once('-')) else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let end = match end {
+        "" => None,
+        end => match end.parse::<u64>() {
+            Ok(end) => Some(end),
+            Err(_) => return ByteRange::Full,
+        },
+    };
+    match (start, end) {
+        // The last `suffix` bytes.
+        ("", Some(suffix)) if suffix == 0 || len == 0 => ByteRange::Unsatisfiable,
+        ("", Some(suffix)) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+        ("", None) => ByteRange::Full,
+        (start, end) => match start.parse::<u64>() {
+            Ok(start) if end.is_some_and(|end| end < start) => ByteRange::Full,
+            Ok(start) if start >= len => ByteRange::Unsatisfiable,
+            Ok(start) => ByteRange::Partial(start, end.map_or(len - 1, |end| end.min(len - 1))),
+            Err(_) => ByteRange::Full,
+        },
+    }
+}
+
+/// Serves an avatar file, or the part of it `headers` ask for.
+pub fn blob_response(hash: &str, data: Vec<u8>, headers: &HeaderMap) -> Response {
+    let len = data.len() as u64;
+    let (status, content_range, body) = match byte_range(headers.get(header::RANGE).and_then(|range| range.to_str().ok()), len) {
+        ByteRange::Full => (StatusCode::OK, None, data),
+        ByteRange::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, Some(format!("bytes {start}-{end}/{len}")), data[start as usize..=end as usize].to_vec()),
+        ByteRange::Unsatisfiable => (StatusCode::RANGE_NOT_SATISFIABLE, Some(format!("bytes */{len}")), vec![]),
+    };
+    let mut response = (status, body).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=31536000, immutable"));
+    if let Ok(etag) = HeaderValue::from_str(&format!("\"{hash}\"")) {
+        headers.insert(header::ETAG, etag);
+    }
+    if let Some(content_range) = content_range.and_then(|content_range| HeaderValue::from_str(&content_range).ok()) {
+        headers.insert(header::CONTENT_RANGE, content_range);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_byte_ranges() {
+        assert_eq!(byte_range(None, 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=0-3"), 10), ByteRange::Partial(0, 3));
+        assert_eq!(byte_range(Some("bytes=4-"), 10), ByteRange::Partial(4, 9));
+        assert_eq!(byte_range(Some("bytes=-3"), 10), ByteRange::Partial(7, 9));
+        assert_eq!(byte_range(Some("bytes=-30"), 10), ByteRange::Partial(0, 9));
+        assert_eq!(byte_range(Some("bytes=5-100"), 10), ByteRange::Partial(5, 9));
+        assert_eq!(byte_range(Some("bytes=10-"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=-0"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=0-1,4-5"), 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=5-2"), 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("items=0-1"), 10), ByteRange::Full);
+    }
+
+    #[test]
+    fn stores_owners_with_the_file() {
+        let owners = BTreeSet::from([String::from("malek"), String::from("lyuma")]);
+        let bytes = encode_blob(&owners, b"glTF\n\x02").unwrap();
+        let (decoded, data) = decode_blob(&bytes).unwrap();
+        assert_eq!((decoded, data), (owners, &b"glTF\n\x02"[..]));
+    }
+}
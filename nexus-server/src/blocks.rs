@@ -0,0 +1,78 @@
+use nexus_common::api::Endpoint;
+use nexus_common::api::server_server::{CancelFriendRequest, Unfriend};
+use nexus_common::{ApiError, BlockTarget, EventKind, UnfriendRequest, Username};
+use crate::privacy::normalize_domain;
+use crate::{events, Result, State};
+
+impl State {
+    /// Whether a local user blocked `user`, directly or through their domain.
+    pub fn blocks(&self, username: &str, user: &Username) -> Result<bool> {
+        Ok(self.user(username)?.blocks.blocks(user))
+    }
+    /// Adds to a local user's block list. Friends it covers are unfriended, sent friend requests
+    /// to them are cancelled and what they sent is dropped without an answer.
+    pub async fn block(&self, username: &str, target: BlockTarget) -> Result<()> {
+        let target = match target {
+            BlockTarget::Domain(domain) => BlockTarget::Domain(normalize_domain(&domain)?),
+            user => user,
+        };
+        let me = self.local_user(username);
+        let (unfriended, cancelled) = self.try_user_mut(username, |user| {
+            match &target {
+                BlockTarget::User(blocked) if !user.blocks.users.contains(blocked) => user.blocks.users.push(blocked.clone()),
+                BlockTarget::Domain(domain) if !user.blocks.domains.contains(domain) => user.blocks.domains.push(domain.clone()),
+                _ => {}
+            }
+            let blocks = user.blocks.clone();
+            (!blocks.blocks(&me)).then_some(()).ok_or_else(|| ApiError::BadRequest("Users can't block themselves".to_string()))?;
+            let unfriended = user.friends.iter().filter(|friend| blocks.blocks(friend)).cloned().collect::<Vec<_>>();
+            user.friends.retain(|friend| !blocks.blocks(friend));
+            let mut cancelled = vec![];
+            user.friend_requests.retain(|uuid, friend_request| {
+                if user.rec_friend_requests.contains(uuid) && blocks.blocks(&friend_request.from) {
+                    user.rec_friend_requests.remove(uuid);
+                    return false;
+                }
+                if user.sent_friend_requests.contains(uuid) && blocks.blocks(&friend_request.to) {
+                    user.sent_friend_requests.remove(uuid);
+                    cancelled.push(friend_request.clone());
+                    return false;
+                }
+                true
+            });
+            user.invites.retain(|uuid, invite| !(user.rec_invites.contains(uuid) && blocks.blocks(&invite.from)));
+            user.rec_invites.retain(|uuid| user.invites.contains_key(uuid));
+            events::record(user, EventKind::BlocksUpdated);
+            Ok((unfriended, cancelled))
+        })?;
+        self.notify(username);
+        // The block already holds locally, telling the other servers is best effort.
+        for friend in unfriended {
+            let unfriend = UnfriendRequest { from: me.clone(), to: friend.clone() };
+            if let Err(error) = self.deliver(username, &friend.website, Unfriend::path(&[&friend.username]), &unfriend).await {
+                println!("blocks: error unfriending {friend}: {:#}", error.0);
+            }
+        }
+        for friend_request in cancelled {
+            if let Err(error) = self.deliver(username, &friend_request.to.website, CancelFriendRequest::path(&[&friend_request.to.username]), &friend_request.uuid).await {
+                println!("blocks: error cancelling the friend request to {}: {:#}", friend_request.to, error.0);
+            }
+        }
+        Ok(())
+    }
+    pub fn unblock(&self, username: &str, target: BlockTarget) -> Result<()> {
+        let target = match target {
+            BlockTarget::Domain(domain) => BlockTarget::Domain(normalize_domain(&domain)?),
+            user => user,
+        };
+        self.user_mut(username, |user| {
+            match &target {
+                BlockTarget::User(blocked) => user.blocks.users.retain(|user| user != blocked),
+                BlockTarget::Domain(domain) => user.blocks.domains.retain(|blocked| blocked != domain),
+            }
+            events::record(user, EventKind::BlocksUpdated);
+        })?;
+        self.notify(username);
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use std::env;
+use std::fs;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use crate::avatars::AvatarConfig;
+use crate::blobs::BlobConfig;
+use crate::defederation::FederationConfig;
+use crate::instances::InstanceConfig;
+use crate::invites::InviteConfig;
+use crate::outbox::OutboxConfig;
+use crate::presence::PresenceConfig;
+use crate::profiles::ProfileConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::storage::StorageConfig;
+
+/// Operator configuration, read from the JSON file named by `NEXUS_CONFIG`.
+/// Every field is optional so an empty or missing file gives a working local server.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    /// The `host[:port]` this server federates as, which must match the website of its users.
+    /// Defaults to `localhost:<port>`.
+    pub domain: Option<String>,
+    /// The base url clients and other servers reach this server at, advertised in the
+    /// `/.well-known/nexus` discovery document. Defaults to `http://<domain>`.
+    pub public_url: Option<String>,
+    /// Lets discovery of other servers fall back to plain http for any domain, not only for
+    /// localhost. Only for test setups, since it lets anyone on the network impersonate servers.
+    pub allow_http_discovery: bool,
+    pub registration: RegistrationPolicy,
+    /// Single use codes accepted by [`RegistrationPolicy::InviteOnly`].
+    pub invite_codes: Vec<String>,
+    /// Local usernames allowed to call the admin endpoints.
+    pub admins: Vec<String>,
+    pub storage: StorageConfig,
+    pub outbox: OutboxConfig,
+    pub presence: PresenceConfig,
+    pub instances: InstanceConfig,
+    pub invites: InviteConfig,
+    pub avatars: AvatarConfig,
+    pub blobs: BlobConfig,
+    pub profiles: ProfileConfig,
+    pub federation: FederationConfig,
+    pub rate_limits: RateLimitConfig,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegistrationPolicy {
+    #[default]
+    Open,
+    InviteOnly,
+    Closed,
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Self> {
+        match env::var("NEXUS_CONFIG") {
+            Ok(path) => Ok(serde_json::from_slice(&fs::read(&path).with_context(|| format!("Error reading config {path}"))?)?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
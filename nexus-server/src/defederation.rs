@@ -0,0 +1,145 @@
+use std::collections::BTreeSet;
+use serde::{Deserialize, Serialize};
+use nexus_common::{ApiError, Defederation, EventKind, FederationMode, FederationPolicy, Username};
+use crate::privacy::normalize_domain;
+use crate::storage::Table;
+use crate::{events, Result, State};
+
+/// Where [`AdminRules`] are kept in [`Table::Server`].
+const ADMIN_RULES_KEY: &str = "federation_rules";
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct FederationConfig {
+    pub mode: FederationMode,
+    /// The servers federated with in [`FederationMode::Allowlist`] mode.
+    pub allowed: Vec<String>,
+    /// Servers never federated with. Admins can deny more, but can't allow these.
+    pub denied: Vec<String>,
+    /// Ends friendships with users on servers that aren't federated with when the server starts,
+    /// for domains that were taken out of the config while it was down.
+    pub sever_friendships: bool,
+}
+
+impl FederationConfig {
+    /// The config with its domains spelled the way usernames spell them.
+    pub fn normalized(&self) -> Result<Self> {
+        let normalize = |domains: &[String]| domains.iter().map(|domain| normalize_domain(domain)).collect::<Result<Vec<_>>>();
+        Ok(Self { allowed: normalize(&self.allowed)?, denied: normalize(&self.denied)?, ..self.clone() })
+    }
+}
+
+/// Domains admins allowed or denied at runtime, on top of the config.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+struct AdminRules {
+    allowed: Vec<String>,
+    denied: Vec<String>,
+}
+
+impl State {
+    fn update_admin_rules(&self, mut func: impl FnMut(&mut AdminRules)) -> Result<()> {
+        loop {
+            let old = self.storage.get(Table::Server, ADMIN_RULES_KEY)?;
+            let mut rules = match &old {
+                Some(bytes) => serde_json::from_slice(bytes)?,
+                None => AdminRules::default(),
+            };
+            func(&mut rules);
+            if self.storage.compare_and_swap(Table::Server, ADMIN_RULES_KEY, old.as_deref(), Some(&serde_json::to_vec(&rules)?))? {
+                return Ok(());
+            }
+        }
+    }
+    /// The config's policy with the admins' rules added.
+    pub fn federation_policy(&self) -> Result<FederationPolicy> {
+        let rules: AdminRules = match self.storage.get(Table::Server, ADMIN_RULES_KEY)? {
+            Some(bytes) => serde_json::from_slice(&bytes)?,
+            None => AdminRules::default(),
+        };
+        let config = &self.federation_config;
+        let merge = |configured: &[String], added: Vec<String>| {
+            let mut domains = configured.to_vec();
+            domains.extend(added.into_iter().filter(|domain| !configured.contains(domain)));
+            domains
+        };
+        Ok(FederationPolicy { mode: config.mode, allowed: merge(&config.allowed, rules.allowed), denied: merge(&config.denied, rules.denied) })
+    }
+    /// Refuses to talk to `domain`, in either direction, unless the federation policy allows it.
+    /// This server's own domain is always allowed.
+    pub fn check_federates(&self, domain: &str) -> Result<()> {
+        (domain == self.domain || self.federation_policy()?.allows(domain)).then_some(()).ok_or_else(|| ApiError::Forbidden(format!("{} does not federate with {domain}", self.domain)))?;
+        Ok(())
+    }
+    pub fn check_admin(&self, username: &str) -> Result<()> {
+        self.admins.iter().any(|admin| admin == username).then_some(()).ok_or_else(|| ApiError::Forbidden("Only server admins can change federation".to_string()))?;
+        Ok(())
+    }
+    pub fn allow_domain(&self, domain: &str) -> Result<()> {
+        let domain = normalize_domain(domain)?;
+        (!self.federation_config.denied.contains(&domain)).then_some(()).ok_or_else(|| ApiError::BadRequest(format!("{domain} is denied by the server config")))?;
+        self.update_admin_rules(|rules| {
+            rules.denied.retain(|denied| *denied != domain);
+            if !rules.allowed.contains(&domain) {
+                rules.allowed.push(domain.clone());
+            }
+        })
+    }
+    pub fn deny_domain(&self, defederation: Defederation) -> Result<()> {
+        let domain = normalize_domain(&defederation.domain)?;
+        (domain != self.domain).then_some(()).ok_or_else(|| ApiError::BadRequest("A server can't deny its own domain".to_string()))?;
+        self.update_admin_rules(|rules| {
+            rules.allowed.retain(|allowed| *allowed != domain);
+            if !rules.denied.contains(&domain) {
+                rules.denied.push(domain.clone());
+            }
+        })?;
+        if defederation.sever_friendships {
+            self.sever_friendships()?;
+        }
+        Ok(())
+    }
+    pub fn reset_domain(&self, domain: &str) -> Result<()> {
+        let domain = normalize_domain(domain)?;
+        self.update_admin_rules(|rules| {
+            rules.allowed.retain(|allowed| *allowed != domain);
+            rules.denied.retain(|denied| *denied != domain);
+        })
+    }
+    /// Ends every local user's friendships, friend requests and received invites with users on
+    /// servers the policy doesn't allow, and tells them which domains they lost. Those servers
+    /// aren't told, since nothing is sent to them any more.
+    pub fn sever_friendships(&self) -> Result<()> {
+        let policy = self.federation_policy()?;
+        let cut_off = |user: &Username| user.website != self.domain && !policy.allows(&user.website);
+        for (username, _) in self.storage.scan(Table::Users)? {
+            let severed = self.try_user_mut(&username, |user| {
+                let mut domains = BTreeSet::new();
+                domains.extend(user.friends.iter().filter(|friend| cut_off(friend)).map(|friend| friend.website.clone()));
+                user.friends.retain(|friend| !cut_off(friend));
+                user.friend_requests.retain(|uuid, friend_request| {
+                    let other = match user.sent_friend_requests.contains(uuid) {
+                        true => &friend_request.to,
+                        false => &friend_request.from,
+                    };
+                    if !cut_off(other) {
+                        return true;
+                    }
+                    domains.insert(other.website.clone());
+                    user.sent_friend_requests.remove(uuid);
+                    user.rec_friend_requests.remove(uuid);
+                    false
+                });
+                user.invites.retain(|uuid, invite| !(user.rec_invites.contains(uuid) && cut_off(&invite.from)));
+                user.rec_invites.retain(|uuid| user.invites.contains_key(uuid));
+                for domain in &domains {
+                    events::record(user, EventKind::Defederated(domain.clone()));
+                }
+                Ok(!domains.is_empty())
+            })?;
+            if severed {
+                self.notify(&username);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use axum::extract::Extension;
+use axum::http::HeaderMap;
+use axum::response::sse::{self, KeepAlive, Sse};
+use futures::Stream;
+use tokio::sync::broadcast::error::RecvError;
+use nexus_common::non_api_structs::UserData;
+use nexus_common::{Event, EventKind};
+use crate::auth::Session;
+use crate::{Result, State};
+
+/// How many events each user keeps for clients that reconnect. A client further behind than this
+/// gets [`EventKind::Resync`].
+const MAX_RETAINED_EVENTS: usize = 256;
+
+/// Appends an event to `user`. Call it inside the same `user_mut` as the change it describes, so
+/// the event is stored if and only if the change is, then call [`State::notify`] afterwards.
+pub fn record(user: &mut UserData, kind: EventKind) {
+    user.last_event += 1;
+    user.events.push_back(Event { cursor: user.last_event, kind });
+    while user.events.len() > MAX_RETAINED_EVENTS {
+        user.events.pop_front();
+    }
+}
+
+impl State {
+    /// Wakes up the event streams of `user` after new events were recorded.
+    pub fn notify(&self, user: impl AsRef<str>) {
+        // Only fails when nobody is listening.
+        let _ = self.events.send(user.as_ref().to_string());
+    }
+    /// The events after `cursor`, or everything from now on if there is no cursor.
+    fn events_after(&self, user: &str, cursor: Option<u64>) -> Result<(Vec<Event>, u64)> {
+        let user_data = self.user(user)?;
+        let Some(cursor) = cursor else {
+            return Ok((vec![], user_data.last_event));
+        };
+        let oldest = user_data.events.front().map_or(user_data.last_event + 1, |event| event.cursor);
+        if cursor > user_data.last_event || cursor + 1 < oldest {
+            let resync = Event { cursor: user_data.last_event, kind: EventKind::Resync };
+            return Ok((vec![resync], user_data.last_event));
+        }
+        Ok((user_data.events.into_iter().filter(|event| event.cursor > cursor).collect(), user_data.last_event))
+    }
+}
+
+struct Listener {
+    state: State,
+    username: String,
+    cursor: Option<u64>,
+    pending: VecDeque<Event>,
+    receiver: tokio::sync::broadcast::Receiver<String>,
+}
+
+pub async fn get_events(Extension(state): Extension<State>, Session(username, _): Session, headers: HeaderMap) -> Result<Sse<impl Stream<Item = std::result::Result<sse::Event, Infallible>>>> {
+    println!("client_server::get_events");
+    let cursor = headers.get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    // Subscribe before the first read, so nothing recorded in between is missed.
+    let receiver = state.events.subscribe();
+    // Fail now if the user doesn't exist, rather than ending the stream straight away.
+    state.user(&username)?;
+    let listener = Listener { state, username, cursor, pending: VecDeque::new(), receiver };
+    let stream = futures::stream::unfold(listener, |mut listener| async move {
+        loop {
+            if let Some(event) = listener.pending.pop_front() {
+                let sse_event = sse::Event::default()
+                    .id(event.cursor.to_string())
+                    .json_data(&event)
+                    .unwrap_or_default();
+                return Some((Ok(sse_event), listener));
+            }
+            let (events, last_event) = listener.state.events_after(&listener.username, listener.cursor).ok()?;
+            listener.cursor = Some(last_event);
+            if !events.is_empty() {
+                listener.pending.extend(events);
+                continue;
+            }
+            loop {
+                match listener.receiver.recv().await {
+                    Ok(user) if user == listener.username => break,
+                    Ok(_) => continue,
+                    // Missed some notifications, so just check again.
+                    Err(RecvError::Lagged(_)) => break,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
@@ -0,0 +1,224 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use axum::async_trait;
+use axum::body::{Bytes, HttpBody};
+use axum::extract::{ConnectInfo, FromRequest};
+use axum::http::{HeaderMap, Request};
+use axum::BoxError;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use nexus_common::{ApiError, ServerKey, Username};
+use nexus_common::api::{self, Endpoint};
+use nexus_common::discovery::Discovery;
+use crate::{unix_time, AppError, Result, State};
+use crate::storage::{Storage, Table};
+
+pub const ORIGIN_HEADER: &str = "x-nexus-origin";
+pub const TIMESTAMP_HEADER: &str = "x-nexus-timestamp";
+pub const NONCE_HEADER: &str = "x-nexus-nonce";
+pub const SIGNATURE_HEADER: &str = "x-nexus-signature";
+
+/// How far a signed request's timestamp may drift from our clock. Nonces are remembered for
+/// this long, so a captured request can never be replayed.
+const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+/// A server's key is fetched again at most this often, whether the last fetch failed or its key
+/// stopped matching, so forged origin headers can't make us fetch from a host over and over.
+const KEY_REFETCH_SECS: u64 = 60;
+
+/// The outcome of the last fetch of a server's key: the newest key we got, if any, and when we
+/// last tried.
+#[derive(Clone, Copy, Debug)]
+pub struct FetchedKey {
+    key: Option<VerifyingKey>,
+    fetched: u64,
+}
+
+/// Nonces are keyed by the request's timestamp first, so they can be pruned by their age alone.
+fn nonce_key(timestamp: u64, origin: &str, nonce: &str) -> String {
+    format!("{timestamp:020}\n{origin}\n{nonce}")
+}
+
+/// Loads this server's signing key from the database, generating one on first start.
+pub fn load_signing_key(storage: &dyn Storage) -> anyhow::Result<SigningKey> {
+    if let Some(bytes) = storage.get(Table::Server, "signing_key")? {
+        let bytes: [u8; 32] = bytes.as_slice().try_into().context("Stored signing key has the wrong length")?;
+        return Ok(SigningKey::from_bytes(&bytes));
+    }
+    let key = SigningKey::generate(&mut OsRng);
+    storage.insert(Table::Server, "signing_key", key.to_bytes().as_slice())?;
+    Ok(key)
+}
+
+/// The string both sides sign: everything a forwarded or replayed copy of the request could change.
+/// `domain` is the receiving server's federation domain and `path` the endpoint path without the
+/// server's base url, so a reverse proxy in front of the server doesn't break signatures.
+fn signing_message(method: &str, domain: &str, path: &str, origin: &str, timestamp: u64, nonce: &str, body: &[u8]) -> String {
+    let digest = Sha256::digest(body).iter().map(|b| format!("{b:02x}")).collect::<String>();
+    format!("{method}\n{domain}\n{path}\n{origin}\n{timestamp}\n{nonce}\n{digest}")
+}
+
+impl State {
+    pub fn server_key(&self) -> ServerKey {
+        ServerKey {
+            domain: self.domain.clone(),
+            public_key: BASE64.encode(self.signing_key.verifying_key().as_bytes()),
+        }
+    }
+    /// Sends a signed federation POST to `path` on `domain`'s server, found through `discovery`.
+    pub async fn federated_post(&self, discovery: &Discovery, domain: &str, path: &str, body: &impl Serialize) -> Result<reqwest::Response> {
+        let body = serde_json::to_vec(body)?;
+        let timestamp = unix_time();
+        let nonce = rand::random::<[u8; 16]>().iter().map(|b| format!("{b:02x}")).collect::<String>();
+        let message = signing_message("POST", domain, path, &self.domain, timestamp, &nonce, &body);
+        let signature = self.signing_key.sign(message.as_bytes());
+        Ok(self.reqwest_client
+            .post(discovery.url(path))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(ORIGIN_HEADER, &self.domain)
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(NONCE_HEADER, &nonce)
+            .header(SIGNATURE_HEADER, BASE64.encode(signature.to_bytes()))
+            .header(api::PROTOCOL_VERSION_HEADER, api::PROTOCOL_VERSION)
+            .body(body)
+            .send()
+            .await?)
+    }
+    /// Fetches `origin`'s key, remembering the attempt. A failure keeps the key we already had.
+    async fn fetch_server_key(&self, origin: &str) -> anyhow::Result<VerifyingKey> {
+        let result = self.download_server_key(origin).await;
+        let mut server_keys = self.server_keys.lock().unwrap();
+        let key = result.as_ref().ok().copied().or_else(|| server_keys.get(origin).and_then(|fetched| fetched.key));
+        server_keys.insert(origin.to_string(), FetchedKey { key, fetched: unix_time() });
+        result
+    }
+    async fn download_server_key(&self, origin: &str) -> anyhow::Result<VerifyingKey> {
+        let server_key: ServerKey = self.reqwest_client
+            .get(self.discover(origin).await.map_err(|e| e.0)?.url(api::GetServerKey::PATH))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if server_key.domain != origin {
+            return Err(anyhow!("Server key for {origin} was published as {}", server_key.domain));
+        }
+        let bytes: [u8; 32] = BASE64.decode(server_key.public_key)?
+            .try_into()
+            .map_err(|_| anyhow!("Server key for {origin} has the wrong length"))?;
+        Ok(VerifyingKey::from_bytes(&bytes)?)
+    }
+    /// Records a nonce as used, returning false if the same request was already seen.
+    fn use_nonce(&self, origin: &str, nonce: &str, timestamp: u64) -> anyhow::Result<bool> {
+        self.storage.compare_and_swap(Table::Nonces, &nonce_key(timestamp, origin, nonce), None, Some(&[]))
+    }
+    /// Forgets nonces whose timestamps are too old to be accepted anyway, along with any stored
+    /// in an older format.
+    fn prune_nonces(&self) -> anyhow::Result<()> {
+        let oldest = unix_time().saturating_sub(MAX_CLOCK_SKEW_SECS);
+        for (key, _) in self.storage.scan(Table::Nonces)? {
+            let timestamp = key.split('\n').next().and_then(|timestamp| timestamp.parse::<u64>().ok());
+            if timestamp.is_none_or(|timestamp| timestamp < oldest) {
+                self.storage.remove(Table::Nonces, &key)?;
+            }
+        }
+        Ok(())
+    }
+    async fn verify(&self, headers: &HeaderMap, method: &str, path: &str, body: &[u8]) -> anyhow::Result<String> {
+        let header = |name: &str| headers.get(name)
+            .and_then(|value| value.to_str().ok())
+            .with_context(|| format!("Missing {name} header"));
+        let origin = header(ORIGIN_HEADER)?;
+        let timestamp: u64 = header(TIMESTAMP_HEADER)?.parse()?;
+        let nonce = header(NONCE_HEADER)?;
+        let signature = Signature::from_slice(&BASE64.decode(header(SIGNATURE_HEADER)?)?)?;
+        if unix_time().abs_diff(timestamp) > MAX_CLOCK_SKEW_SECS {
+            return Err(anyhow!("Request timestamp is outside the allowed window"));
+        }
+        let message = signing_message(method, &self.domain, path, origin, timestamp, nonce, body);
+        let verifies = |key: &VerifyingKey| key.verify(message.as_bytes(), &signature).is_ok();
+        let now = unix_time();
+        let cached = self.server_keys.lock().unwrap().get(origin).copied();
+        let verified = match cached {
+            Some(FetchedKey { key: Some(key), .. }) if verifies(&key) => true,
+            Some(FetchedKey { key: None, fetched }) if now < fetched + KEY_REFETCH_SECS => return Err(ApiError::RemoteUnreachable(format!("Could not get the key for {origin}")).into()),
+            Some(FetchedKey { key: Some(_), fetched }) if now < fetched + KEY_REFETCH_SECS => false,
+            // The origin may have rotated its key since we fetched it.
+            _ => verifies(&self.fetch_server_key(origin).await.map_err(|e| ApiError::RemoteUnreachable(format!("Could not get the key for {origin}: {e:#}")))?),
+        };
+        if !verified {
+            return Err(anyhow!("Invalid signature from {origin}"));
+        }
+        if !self.use_nonce(origin, nonce, timestamp)? {
+            return Err(anyhow!("Replayed request from {origin}"));
+        }
+        Ok(origin.to_string())
+    }
+}
+
+/// Background task pruning the nonces table.
+pub async fn run(state: State) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        if let Err(error) = state.prune_nonces() {
+            println!("federation: error pruning nonces: {error:#}");
+        }
+    }
+}
+
+/// Extractor for server to server routes. Verifies the request was signed by the server named in
+/// the origin header, then deserializes the JSON body.
+pub struct Federated<T> {
+    pub origin: String,
+    pub body: T,
+}
+
+impl<T> Federated<T> {
+    /// Checks that `user` lives on the server that signed this request.
+    pub fn check_origin(&self, user: &Username) -> Result<()> {
+        if user.website != self.origin {
+            return Err(ApiError::Forbidden(format!("{} cannot act for {}.{}", self.origin, user.username, user.website)).into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S, B, T> FromRequest<S, B> for Federated<T>
+    where
+        T: DeserializeOwned,
+        B: HttpBody + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+        S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request<B>, s: &S) -> std::result::Result<Self, Self::Rejection> {
+        let state = req.extensions().get::<State>().cloned().context("Missing state")?;
+        if let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+            state.check_peer_rate(addr.ip())?;
+        }
+        let method = req.method().to_string();
+        let path = req.uri().path().to_string();
+        let headers = req.headers().clone();
+        // Checked before the signature, which could mean fetching the key from the denied server.
+        if let Some(origin) = headers.get(ORIGIN_HEADER).and_then(|origin| origin.to_str().ok()) {
+            state.check_federates(origin)?;
+        }
+        let body = Bytes::from_request(req, s).await
+            .map_err(|e| ApiError::BadRequest(e.body_text()))?;
+        // Not being able to fetch the key isn't the sender's fault, so it isn't a 401 either.
+        let origin = state.verify(&headers, &method, &path, &body).await
+            .map_err(|e| e.downcast::<ApiError>().unwrap_or_else(|e| ApiError::Unauthorized(e.to_string())))?;
+        state.check_server_rate(&origin)?;
+        let body = serde_json::from_slice(&body)
+            .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+        Ok(Self { origin, body })
+    }
+}
@@ -0,0 +1,191 @@
+use std::time::Duration;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use nexus_common::api::Endpoint;
+use nexus_common::api::server_server::GetInstance;
+use nexus_common::{ApiError, FriendInstance, Instance, InstanceQuery, InstanceRef, InstanceRegistration, InstanceVisibility, PlayerCountUpdate, Username};
+use crate::storage::Table;
+use crate::{unix_time, Result, State};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InstanceConfig {
+    /// How long an instance stays registered without its game server updating it.
+    pub ttl_secs: u64,
+}
+
+impl Default for InstanceConfig {
+    fn default() -> Self {
+        Self { ttl_secs: 10 * 60 }
+    }
+}
+
+fn key(instance: &InstanceRef) -> String {
+    format!("{}\n{}", instance.world_id, instance.instance_id)
+}
+
+fn no_such_instance(instance: &InstanceRef) -> ApiError {
+    ApiError::NotFound(format!("No instance {} of world {}", instance.instance_id, instance.world_id))
+}
+
+impl State {
+    fn instance(&self, instance: &InstanceRef) -> Result<Option<(Vec<u8>, Instance)>> {
+        match self.storage.get(Table::Instances, &key(instance))? {
+            Some(bytes) => {
+                let instance = serde_json::from_slice(&bytes)?;
+                Ok(Some((bytes, instance)))
+            }
+            None => Ok(None),
+        }
+    }
+    /// Atomically applies `func` to an instance `username` hosts, removing it if `func` returns
+    /// `None`. Like [`State::try_user_mut`], `func` may run more than once.
+    fn instance_mut(&self, username: &str, instance: &InstanceRef, mut func: impl FnMut(Instance) -> Option<Instance>) -> Result<()> {
+        let host = self.local_user(username);
+        loop {
+            let (old, current) = self.instance(instance)?.ok_or_else(|| no_such_instance(instance))?;
+            (current.host == host).then_some(()).ok_or_else(|| ApiError::Forbidden("Instance is hosted by another user".to_string()))?;
+            let new = func(current).map(|new| serde_json::to_vec(&new)).transpose()?;
+            if self.storage.compare_and_swap(Table::Instances, &key(instance), Some(&old), new.as_deref())? {
+                return Ok(());
+            }
+        }
+    }
+    /// Registers an instance for `username`, or updates it if they already host it. The
+    /// instance's domain may be left empty, it is always this server.
+    pub fn register_instance(&self, username: &str, registration: InstanceRegistration) -> Result<Instance> {
+        let mut instance_ref = registration.instance;
+        if instance_ref.domain.is_empty() {
+            instance_ref.domain = self.domain.clone();
+        }
+        (instance_ref.domain == self.domain).then_some(()).ok_or_else(|| ApiError::BadRequest(format!("Instances registered here belong to {}", self.domain)))?;
+        let valid_id = |id: &str| !id.is_empty() && !id.contains('\n');
+        (valid_id(&instance_ref.world_id) && valid_id(&instance_ref.instance_id)).then_some(()).ok_or_else(|| ApiError::BadRequest("World and instance ids must be non-empty single lines".to_string()))?;
+        let host = self.local_user(username);
+        let now = unix_time();
+        loop {
+            let existing = self.instance(&instance_ref)?;
+            let (old, created, players) = match &existing {
+                Some((_, current)) if current.host != host => return Err(ApiError::AlreadyExists("Instance is hosted by another user".to_string()).into()),
+                Some((old, current)) => (Some(old.as_slice()), current.created, current.players),
+                None => (None, now, 0),
+            };
+            let instance = Instance {
+                instance: instance_ref.clone(),
+                host: host.clone(),
+                join_address: registration.join_address.clone(),
+                capacity: registration.capacity,
+                players: players.min(registration.capacity),
+                visibility: registration.visibility,
+                created,
+                updated: now,
+            };
+            if self.storage.compare_and_swap(Table::Instances, &key(&instance_ref), old, Some(&serde_json::to_vec(&instance)?))? {
+                return Ok(instance);
+            }
+        }
+    }
+    pub fn update_player_count(&self, username: &str, update: PlayerCountUpdate) -> Result<()> {
+        let capacity = self.instance(&update.instance)?.ok_or_else(|| no_such_instance(&update.instance))?.1.capacity;
+        (update.players <= capacity).then_some(()).ok_or_else(|| ApiError::BadRequest(format!("Instance only has room for {capacity} players")))?;
+        self.instance_mut(username, &update.instance, |instance| {
+            Some(Instance { players: update.players.min(instance.capacity), updated: unix_time(), ..instance })
+        })
+    }
+    pub fn close_instance(&self, username: &str, instance: &InstanceRef) -> Result<()> {
+        self.instance_mut(username, instance, |_| None)
+    }
+    pub fn hosted_instances(&self, username: &str) -> Result<Vec<Instance>> {
+        let host = self.local_user(username);
+        Ok(self.instances()?.into_iter().filter(|instance| instance.host == host).collect())
+    }
+    fn instances(&self) -> Result<Vec<Instance>> {
+        Ok(self.storage.scan(Table::Instances)?
+            .into_iter()
+            .filter_map(|(_, bytes)| serde_json::from_slice(&bytes).ok())
+            .collect())
+    }
+    /// A local instance, if `user` is allowed to see it: by its visibility, or because the host
+    /// invited them. Hidden instances are reported as missing so their existence doesn't leak.
+    pub fn visible_instance(&self, instance: &InstanceRef, user: &Username) -> Result<Instance> {
+        let (_, instance) = self.instance(instance)?.ok_or_else(|| no_such_instance(instance))?;
+        let visible = match instance.visibility {
+            InstanceVisibility::Public => true,
+            InstanceVisibility::Friends => instance.host == *user
+                || self.user(&instance.host.username)?.friends.contains(user)
+                || self.invited_to(&instance, user)?,
+            InstanceVisibility::Private => instance.host == *user || self.invited_to(&instance, user)?,
+        };
+        visible.then_some(()).ok_or_else(|| no_such_instance(&instance.instance))?;
+        Ok(instance)
+    }
+    /// An instance on any server, if `user` is allowed to see it.
+    pub async fn lookup_instance(&self, instance: &InstanceRef, user: &Username) -> Result<Instance> {
+        if instance.domain == self.domain {
+            return self.visible_instance(instance, user);
+        }
+        let query = InstanceQuery { from: user.clone(), instance: instance.clone() };
+        Ok(self.remote_instance(&query).await?.ok_or_else(|| no_such_instance(instance))?)
+    }
+    async fn remote_instance(&self, query: &InstanceQuery) -> Result<Option<Instance>> {
+        let domain = &query.instance.domain;
+        let response = self.federated_post(&self.discover(domain).await?, domain, GetInstance::PATH, query).await?;
+        match response.status() {
+            status if status.is_success() => Ok(Some(response.json().await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(ApiError::RemoteRejected(format!("{domain} answered {status}")).into()),
+        }
+    }
+    /// The instances `username`'s friends are in, going by their presence, that `username` may
+    /// see. Instances on other servers are looked up there.
+    pub async fn friends_instances(&self, username: &str) -> Result<Vec<FriendInstance>> {
+        let user = self.local_user(username);
+        let mut by_instance: Vec<(InstanceRef, Vec<Username>)> = vec![];
+        for friend_presence in self.friends_presence(username)? {
+            let Some(instance) = friend_presence.presence.instance.filter(|instance| !instance.domain.is_empty()) else {
+                continue;
+            };
+            match by_instance.iter_mut().find(|(known, _)| *known == instance) {
+                Some((_, friends)) => friends.push(friend_presence.user),
+                None => by_instance.push((instance, vec![friend_presence.user])),
+            }
+        }
+        let lookups = by_instance.into_iter().map(|(instance, friends)| {
+            let user = &user;
+            async move {
+                let found = self.lookup_instance(&instance, user).await;
+                if let Err(error) = &found {
+                    if !matches!(error.0.downcast_ref::<ApiError>(), Some(ApiError::NotFound(_))) {
+                        println!("instances: error looking up {}: {:#}", instance.domain, error.0);
+                    }
+                }
+                found.ok().map(|instance| FriendInstance { instance, friends })
+            }
+        });
+        Ok(futures::future::join_all(lookups).await.into_iter().flatten().collect())
+    }
+}
+
+/// Background task dropping instances whose game server stopped updating them.
+pub async fn run(state: State) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        let entries = match state.storage.scan(Table::Instances) {
+            Ok(entries) => entries,
+            Err(error) => {
+                println!("instances: error reading instances: {error:#}");
+                continue;
+            }
+        };
+        let now = unix_time();
+        for (key, bytes) in entries {
+            let Ok(instance) = serde_json::from_slice::<Instance>(&bytes) else {
+                continue;
+            };
+            if now > instance.updated + state.instance_config.ttl_secs {
+                // Only if it wasn't updated in the meantime.
+                let _ = state.storage.compare_and_swap(Table::Instances, &key, Some(&bytes), None);
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use nexus_common::api::Endpoint;
+use nexus_common::api::server_server::AnswerInvite;
+use nexus_common::non_api_structs::UserData;
+use nexus_common::{ApiError, EventKind, Instance, Invite, InviteAnswer, InviteStatus, InviteUuid, Username, MAX_INVITE_MESSAGE_LEN};
+use crate::{events, unix_time, Result, State};
+use crate::storage::Table;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InviteConfig {
+    /// How often expired invites are cleaned up for every user.
+    pub sweep_interval_secs: u64,
+    /// How long the sender keeps an invite after it expired, so they can still see how it ended.
+    pub keep_sent_secs: u64,
+}
+
+impl Default for InviteConfig {
+    fn default() -> Self {
+        Self { sweep_interval_secs: 60, keep_sent_secs: 60 * 60 * 24 }
+    }
+}
+
+/// What the sweep does with one invite.
+enum Sweep {
+    Keep,
+    Expire,
+    Remove,
+}
+
+/// Rejects invites that are already expired or malformed, both from our own users and from
+/// other servers.
+pub fn check_invite(invite: &Invite) -> Result<()> {
+    (!invite.is_expired(unix_time())).then_some(()).ok_or_else(|| ApiError::BadRequest("Invite has expired".to_string()))?;
+    (invite.created <= invite.expires).then_some(()).ok_or_else(|| ApiError::BadRequest("Invite expires before it was created".to_string()))?;
+    let names_instance = !invite.instance.world_id.is_empty() && !invite.instance.instance_id.is_empty() && !invite.instance.domain.is_empty();
+    names_instance.then_some(()).ok_or_else(|| ApiError::BadRequest("Invite does not say which instance it is for".to_string()))?;
+    let message_len = invite.message.as_ref().map_or(0, String::len);
+    (message_len <= MAX_INVITE_MESSAGE_LEN).then_some(()).ok_or_else(|| ApiError::BadRequest(format!("Invite messages are limited to {MAX_INVITE_MESSAGE_LEN} bytes")))?;
+    Ok(())
+}
+
+impl State {
+    /// Where a received invite leads. The instance's server decides whether `username` may see it.
+    pub async fn resolve_invite(&self, username: &str, uuid: &InviteUuid) -> Result<Instance> {
+        let invite = self.user(username)?.invites.remove(uuid).ok_or_else(|| ApiError::NotFound("InviteUuid not found".to_string()))?;
+        (!invite.is_expired(unix_time())).then_some(()).ok_or_else(|| ApiError::BadRequest("Invite has expired".to_string()))?;
+        self.lookup_instance(&invite.instance, &self.local_user(username)).await
+    }
+    /// Whether the local host of `instance` has an unexpired invite out to `user` for it.
+    pub fn invited_to(&self, instance: &Instance, user: &Username) -> Result<bool> {
+        let now = unix_time();
+        let host = self.user(&instance.host.username)?;
+        Ok(host.sent_invites.iter()
+            .filter_map(|uuid| host.invites.get(uuid))
+            .any(|invite| invite.to == *user && invite.instance == instance.instance && invite.status != InviteStatus::Declined && !invite.is_expired(now)))
+    }
+    /// Called by the outbox once the recipient's server has a sent invite.
+    pub fn mark_invite_delivered(&self, username: &str, uuid: &InviteUuid) -> Result<()> {
+        self.user_mut(username, |user| {
+            // The answer may have come back first.
+            let Some(invite) = user.invites.get_mut(uuid).filter(|invite| invite.status == InviteStatus::Pending) else {
+                return;
+            };
+            invite.status = InviteStatus::Delivered;
+            let invite = invite.clone();
+            events::record(user, EventKind::InviteUpdated(invite));
+        })?;
+        self.notify(username);
+        Ok(())
+    }
+    pub fn invite_rejected(&self, username: &str, uuid: &InviteUuid) -> Result<()> {
+        self.user_mut(username, |user| {
+            let Some(invite) = user.invites.get_mut(uuid).filter(|invite| invite.status == InviteStatus::Pending) else {
+                return;
+            };
+            invite.status = InviteStatus::Rejected;
+            let invite = invite.clone();
+            events::record(user, EventKind::InviteUpdated(invite));
+        })?;
+        self.notify(username);
+        Ok(())
+    }
+    /// Removes a received invite and tells its sender whether it was accepted.
+    pub async fn answer_invite(&self, username: &str, uuid: &InviteUuid, accepted: bool) -> Result<()> {
+        let invite = self.try_user_mut(username, |user| {
+            user.rec_invites.remove(uuid).then_some(()).ok_or_else(|| ApiError::NotFound("InviteUuid not found".to_string()))?;
+            let invite = user.invites.remove(uuid).ok_or_else(|| ApiError::NotFound("InviteUuid not found".to_string()))?;
+            (!invite.is_expired(unix_time())).then_some(()).ok_or_else(|| ApiError::BadRequest("Invite has expired".to_string()))?;
+            // Other sessions of the same user hear about it through the events stream.
+            events::record(user, EventKind::InviteRemoved(uuid.clone()));
+            Ok(invite)
+        })?;
+        self.notify(username);
+        let answer = InviteAnswer { uuid: uuid.clone(), accepted };
+        self.deliver(username, &invite.from.website, AnswerInvite::path(&[&invite.from.username]), &answer).await
+    }
+    fn sweep_action(&self, user: &UserData, invite: &Invite, now: u64) -> Sweep {
+        if !user.sent_invites.contains(&invite.uuid) {
+            return match invite.is_expired(now) {
+                true => Sweep::Remove,
+                false => Sweep::Keep,
+            };
+        }
+        if now > invite.expires.saturating_add(self.invite_config.keep_sent_secs) {
+            Sweep::Remove
+        } else if invite.is_expired(now) && matches!(invite.status, InviteStatus::Pending | InviteStatus::Delivered) {
+            Sweep::Expire
+        } else {
+            Sweep::Keep
+        }
+    }
+    /// Removes expired received invites, and marks expired sent invites so the sender can see
+    /// they went unanswered before removing them too.
+    fn sweep_invites(&self, username: &str) -> Result<()> {
+        let now = unix_time();
+        let user = self.user(username)?;
+        if user.invites.values().all(|invite| matches!(self.sweep_action(&user, invite, now), Sweep::Keep)) {
+            return Ok(());
+        }
+        self.user_mut(username, |user| {
+            let actions = user.invites.values()
+                .map(|invite| (invite.uuid.clone(), self.sweep_action(user, invite, now)))
+                .collect::<Vec<_>>();
+            for (uuid, action) in actions {
+                match action {
+                    Sweep::Keep => {}
+                    Sweep::Expire => {
+                        let Some(invite) = user.invites.get_mut(&uuid) else { continue };
+                        invite.status = InviteStatus::Expired;
+                        let invite = invite.clone();
+                        events::record(user, EventKind::InviteUpdated(invite));
+                    }
+                    Sweep::Remove => {
+                        user.invites.remove(&uuid);
+                        user.sent_invites.remove(&uuid);
+                        user.rec_invites.remove(&uuid);
+                        events::record(user, EventKind::InviteRemoved(uuid));
+                    }
+                }
+            }
+        })?;
+        self.notify(username);
+        Ok(())
+    }
+}
+
+/// Background task cleaning up expired invites on both the sender's and the recipient's side.
+pub async fn run(state: State) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(state.invite_config.sweep_interval_secs)).await;
+        let users = match state.storage.scan(Table::Users) {
+            Ok(users) => users,
+            Err(error) => {
+                println!("invites: error reading users: {error:#}");
+                continue;
+            }
+        };
+        for (username, _) in users {
+            if let Err(error) = state.sweep_invites(&username) {
+                println!("invites: error sweeping {username}: {:#}", error.0);
+            }
+        }
+    }
+}
@@ -1,30 +1,69 @@
 use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fs::{remove_dir};
 use std::net::SocketAddr;
-use axum::Extension;
-use axum::extract::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::{async_trait, middleware, Extension};
+use axum::body::Body;
+use axum::extract::{ConnectInfo, FromRequest, Path};
+use axum::handler::Handler;
+use axum::http::{HeaderMap, HeaderValue};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use sled::{Db, IVec};
-use nexus_common::{FriendRequest, FriendRequestUuid, Invite, InviteUuid, Username};
+use serde::de::DeserializeOwned;
+use nexus_common::{is_valid_username, ApiError, RegisterError, Username};
+use nexus_common::api::{self, Endpoint, Method};
+use nexus_common::discovery::{Discovery, Resolver};
 use nexus_common::non_api_structs::UserData;
-use anyhow::{Context};
+use storage::{Storage, Table};
+
+mod auth;
+mod avatars;
+mod blobs;
+mod blocks;
+mod config;
+mod defederation;
+mod events;
+mod federation;
+mod instances;
+mod invites;
+mod migrations;
+mod outbox;
+mod presence;
+mod privacy;
+mod profiles;
+mod ratelimit;
+mod storage;
+mod vrm;
 
 pub type Result<T> = std::result::Result<T, AppError>;
 
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[derive(Debug)]
 pub struct AppError(anyhow::Error);
 
-// Tell axum how to convert `AppError` into a response.
+// Tell axum how to convert `AppError` into a response. Errors that are an `ApiError` somewhere in
+// their chain are sent as that, anything else is a bug or a storage failure and becomes a 500.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        let error = match self.0.downcast_ref::<ApiError>() {
+            Some(error) => error.clone(),
+            None => {
+                println!("internal error: {:#}", self.0);
+                ApiError::Internal(format!("Something went wrong: {}", self.0))
+            }
+        };
+        let status = StatusCode::from_u16(error.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = (status, axum::Json(error)).into_response();
+        if let Some(ratelimit::RetryAfter(secs)) = self.0.downcast_ref::<ratelimit::RetryAfter>() {
+            response.headers_mut().insert(axum::http::header::RETRY_AFTER, HeaderValue::from(*secs));
+        }
+        response
     }
 }
 
@@ -39,76 +78,257 @@ impl<E> From<E> for AppError
     }
 }
 
+/// `axum::Json`, except a body that fails to parse is rejected with an [`ApiError::BadRequest`]
+/// body like every other error.
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S, Body> for Json<T> {
+    type Rejection = AppError;
+
+    async fn from_request(req: axum::http::Request<Body>, s: &S) -> std::result::Result<Self, Self::Rejection> {
+        match axum::Json::<T>::from_request(req, s).await {
+            Ok(axum::Json(value)) => Ok(Json(value)),
+            Err(rejection) => Err(ApiError::BadRequest(rejection.body_text()).into()),
+        }
+    }
+}
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+fn no_such_user(user: &str) -> ApiError {
+    ApiError::NotFound(format!("No user named {user}"))
+}
+
+/// Advertised in the discovery document.
+const CAPABILITIES: &[&str] = &["friends", "invites", "outbox", "events", "presence", "instances", "avatars", "avatar-blobs", "profiles", "privacy"];
+
+/// Limits on every request this server makes to another, so one that never answers can't hold up
+/// a handler or the outbox. Requests must finish well within the outbox's inline attempt lease.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+const INVITE_UNUSED: &[u8] = b"unused";
+const INVITE_USED: &[u8] = b"used";
+
 #[derive(Clone)]
 pub struct State {
-    db: Db,
+    storage: Arc<dyn Storage>,
+    registration: config::RegistrationPolicy,
+    outbox_config: outbox::OutboxConfig,
+    presence_config: presence::PresenceConfig,
+    instance_config: instances::InstanceConfig,
+    invite_config: invites::InviteConfig,
+    avatar_config: avatars::AvatarConfig,
+    blob_config: blobs::BlobConfig,
+    profile_config: profiles::ProfileConfig,
+    federation_config: defederation::FederationConfig,
+    admins: Vec<String>,
+    rate_limit_config: ratelimit::RateLimitConfig,
+    domain: String,
+    public_url: String,
+    signing_key: ed25519_dalek::SigningKey,
+    server_keys: Arc<Mutex<HashMap<String, federation::FetchedKey>>>,
+    rate_limiter: Arc<Mutex<ratelimit::RateLimiter>>,
+    friend_checks: Arc<Mutex<privacy::FriendChecks>>,
     reqwest_client: reqwest::Client,
+    resolver: Resolver,
+    /// Usernames whose events changed, see [`State::notify`].
+    events: tokio::sync::broadcast::Sender<String>,
 }
 impl State {
-    pub fn new(port: u16) -> Self {
-        let sled_path = String::from("sled") + &port.to_string();
-        let _ = remove_dir(&sled_path);
-        Self {
-            db: sled::open(sled_path).unwrap(),
-            reqwest_client: Default::default(),
+    pub fn new(port: u16, config: &config::Config) -> Self {
+        let storage: Arc<dyn Storage> = storage::open(&config.storage, port).unwrap().into();
+        let domain = config.domain.clone().unwrap_or_else(|| format!("localhost:{port}"));
+        let state = Self {
+            registration: config.registration,
+            outbox_config: config.outbox.clone(),
+            presence_config: config.presence.clone(),
+            instance_config: config.instances.clone(),
+            invite_config: config.invites.clone(),
+            avatar_config: config.avatars.clone(),
+            blob_config: config.blobs.clone(),
+            profile_config: config.profiles.clone(),
+            federation_config: config.federation.normalized().unwrap_or_else(|e| panic!("Invalid federation config: {:#}", e.0)),
+            admins: config.admins.clone(),
+            rate_limit_config: config.rate_limits.clone(),
+            public_url: config.public_url.clone().unwrap_or_else(|| format!("http://{domain}")),
+            domain,
+            signing_key: federation::load_signing_key(storage.as_ref()).unwrap(),
+            server_keys: Default::default(),
+            rate_limiter: Default::default(),
+            friend_checks: Default::default(),
+            storage,
+            reqwest_client: reqwest::Client::builder().connect_timeout(CONNECT_TIMEOUT).timeout(REQUEST_TIMEOUT).build().unwrap(),
+            resolver: Resolver::new(config.allow_http_discovery),
+            events: tokio::sync::broadcast::channel(1024).0,
+        };
+        for code in &config.invite_codes {
+            state.add_registration_invite(code).unwrap_or_else(|_| panic!("Error adding invite code {code}"));
         }
+        state
     }
     pub fn user(&self, user: impl AsRef<str>) -> Result<UserData> {
-        Ok(serde_json::from_slice(&self.db.get(user.as_ref())?.with_context(|| "Error getting user")?)?)
+        let user = user.as_ref();
+        Ok(migrations::decode_user(&self.storage.get(Table::Users, user)?.ok_or_else(|| no_such_user(user))?)?.0)
     }
-    pub fn try_user_mut(&self, user: impl AsRef<str>, func: impl Fn(&mut UserData) -> Result<()> ) -> Result<()> {
+    /// Atomically applies `func` to a user's data. If another request changed the user in the
+    /// meantime `func` is run again on the fresh data, so it must not have side effects. Nothing is
+    /// written if `func` returns an error.
+    pub fn try_user_mut<T>(&self, user: impl AsRef<str>, mut func: impl FnMut(&mut UserData) -> Result<T>) -> Result<T> {
         let user = user.as_ref();
-        let mut user_data = serde_json::from_slice(&self.db.get(user)?.with_context(|| "Error getting user")?)?;
-        func(&mut user_data)?;
-        self.db.insert(user, serde_json::to_vec(&user_data)?)?;
-        Ok(())
+        loop {
+            let old = self.storage.get(Table::Users, user)?.ok_or_else(|| no_such_user(user))?;
+            let (mut user_data, _) = migrations::decode_user(&old)?;
+            let result = func(&mut user_data)?;
+            if self.storage.compare_and_swap(Table::Users, user, Some(&old), Some(&migrations::encode_user(&user_data)?))? {
+                return Ok(result);
+            }
+        }
     }
     pub fn user_mut(&self, user: impl AsRef<str>, mut func: impl FnMut(&mut UserData)) -> Result<()> {
-        let user = user.as_ref();
-        let mut user_data = serde_json::from_slice(&self.db.get(user)?.with_context(|| "Error getting user")?)?;
-        func(&mut user_data);
-        self.db.insert(user, serde_json::to_vec(&user_data)?)?;
+        self.try_user_mut(user, |user_data| {
+            func(user_data);
+            Ok(())
+        })
+    }
+    pub fn user_exists(&self, user: impl AsRef<str>) -> Result<bool> {
+        Ok(self.storage.get(Table::Users, user.as_ref())?.is_some())
+    }
+    /// Creates an empty user, returning false if the name was already taken.
+    pub fn create_user(&self, user: impl AsRef<str>) -> Result<bool> {
+        Ok(self.storage.compare_and_swap(Table::Users, user.as_ref(), None, Some(&migrations::encode_user(&UserData::default())?))?)
+    }
+    /// Makes a registration invite code usable, unless it already exists (used or not).
+    pub fn add_registration_invite(&self, code: impl AsRef<str>) -> Result<()> {
+        self.storage.compare_and_swap(Table::RegistrationInvites, code.as_ref(), None, Some(INVITE_UNUSED))?;
+        Ok(())
+    }
+    /// Marks a registration invite code as used, returning false if it was unknown or already used.
+    pub fn take_registration_invite(&self, code: impl AsRef<str>) -> Result<bool> {
+        Ok(self.storage.compare_and_swap(Table::RegistrationInvites, code.as_ref(), Some(INVITE_UNUSED), Some(INVITE_USED))?)
+    }
+    pub fn return_registration_invite(&self, code: impl AsRef<str>) -> Result<()> {
+        self.storage.compare_and_swap(Table::RegistrationInvites, code.as_ref(), Some(INVITE_USED), Some(INVITE_UNUSED))?;
         Ok(())
     }
+    /// The full identity of a user hosted on this server.
+    pub fn local_user(&self, user: impl AsRef<str>) -> Username {
+        Username { username: user.as_ref().to_string(), website: self.domain.clone() }
+    }
     pub fn reqwest_client(&self) -> reqwest::Client {
         self.reqwest_client.clone()
     }
+    /// Where the server for `domain` lives, from its cached discovery document.
+    pub async fn discover(&self, domain: &str) -> Result<Discovery> {
+        self.check_federates(domain)?;
+        Ok(self.resolver.resolve(&self.reqwest_client, domain).await?)
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let mut port = 8000;
-    if let Some(p) = env::args().into_iter().collect::<Vec<_>>().get(1) {
+    let (flags, args): (Vec<_>, Vec<_>) = env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    if let Some(p) = args.first() {
         port = p.parse().unwrap();
     }
-    let state = State::new(port);
+    let config = config::Config::load()?;
+    let state = State::new(port, &config);
+    if flags.iter().any(|flag| flag == "--migrate") {
+        return migrations::migrate_all(state.storage.as_ref(), flags.iter().any(|flag| flag == "--dry-run"));
+    }
+    if config.federation.sever_friendships {
+        state.sever_friendships().map_err(|e| e.0)?;
+    }
     let app = axum::Router::new()
         .route("/", get(root))
-        .route("/add-user/:username", get(add_user))
-        .route("/:username/private/get/friends", get(client_server::get_friends))
-        .route("/:username/private/get/sent-invites", get(client_server::get_sent_invites))
-        .route("/:username/private/get/rec-invites", get(client_server::get_rec_invites))
-        .route("/:username/private/get/sent-friend-requests", get(client_server::get_sent_friend_requests))
-        .route("/:username/private/get/rec-friend-requests", get(client_server::get_rec_friend_requests))
-        .route("/:username/private/get/invite/:uuid", get(client_server::get_invite))
-        .route("/:username/private/get/friend-request/:uuid", get(client_server::get_friend_request))
-        .route("/:username/private/post/send-invite", post(client_server::post_send_invite))
-        .route("/:username/private/post/remove-invite", post(client_server::post_remove_invite))
-        .route("/:username/private/post/send-friend-request", post(client_server::post_send_friend_request))
-        .route("/:username/private/post/accept-friend-request", post(client_server::post_accept_friend_request))
-        .route("/:username/private/post/deny-friend-request", post(client_server::post_deny_friend_request))
-        .route("/:username/private/post/unfriend", post(client_server::post_unfriend))
-        .route("/:username/friend/post/send-invite", post(server_server::post_send_invite))
-        .route("/:username/public/post/send-friend-request", post(server_server::post_send_friend_request))
-        .route("/:username/public/post/accept-friend-request", post(server_server::post_accept_friend_request))
-        .route("/:username/public/post/deny-friend-request", post(server_server::post_deny_friend_request))
-        .route("/:username/friend/post/unfriend", post(server_server::post_unfriend))
-        .layer(Extension(state))
+        .endpoint::<api::GetDiscovery, _, _>(get_discovery)
+        .endpoint::<api::GetServerKey, _, _>(get_server_key)
+        .endpoint::<api::Register, _, _>(register)
+        .endpoint::<api::Login, _, _>(login)
+        .endpoint::<api::GetAvatarBlob, _, _>(get_avatar_blob)
+        .endpoint::<api::client_server::Logout, _, _>(logout)
+        .endpoint::<api::client_server::GetEvents, _, _>(events::get_events)
+        .endpoint::<api::client_server::GetOutbox, _, _>(client_server::get_outbox)
+        .endpoint::<api::client_server::DismissDelivery, _, _>(client_server::post_dismiss_delivery)
+        .endpoint::<api::client_server::RetryDelivery, _, _>(client_server::post_retry_delivery)
+        .endpoint::<api::client_server::GetFriends, _, _>(client_server::get_friends)
+        .endpoint::<api::client_server::GetSentInvites, _, _>(client_server::get_sent_invites)
+        .endpoint::<api::client_server::GetRecInvites, _, _>(client_server::get_rec_invites)
+        .endpoint::<api::client_server::GetSentFriendRequests, _, _>(client_server::get_sent_friend_requests)
+        .endpoint::<api::client_server::GetRecFriendRequests, _, _>(client_server::get_rec_friend_requests)
+        .endpoint::<api::client_server::GetInvite, _, _>(client_server::get_invite)
+        .endpoint::<api::client_server::ResolveInvite, _, _>(client_server::get_resolve_invite)
+        .endpoint::<api::client_server::GetFriendRequest, _, _>(client_server::get_friend_request)
+        .endpoint::<api::client_server::GetPresence, _, _>(client_server::get_presence)
+        .endpoint::<api::client_server::GetFriendsPresence, _, _>(client_server::get_friends_presence)
+        .endpoint::<api::client_server::SetPresence, _, _>(client_server::post_set_presence)
+        .endpoint::<api::client_server::Heartbeat, _, _>(client_server::post_heartbeat)
+        .endpoint::<api::client_server::GetInstances, _, _>(client_server::get_instances)
+        .endpoint::<api::client_server::GetFriendsInstances, _, _>(client_server::get_friends_instances)
+        .endpoint::<api::client_server::RegisterInstance, _, _>(client_server::post_register_instance)
+        .endpoint::<api::client_server::UpdatePlayerCount, _, _>(client_server::post_update_player_count)
+        .endpoint::<api::client_server::CloseInstance, _, _>(client_server::post_close_instance)
+        .endpoint::<api::client_server::GetAvatars, _, _>(client_server::get_avatars)
+        .endpoint::<api::client_server::PublishAvatar, _, _>(client_server::post_publish_avatar)
+        .endpoint::<api::client_server::IngestAvatar, _, _>(client_server::post_ingest_avatar)
+        .endpoint::<api::client_server::RemoveAvatar, _, _>(client_server::post_remove_avatar)
+        .endpoint::<api::client_server::SetDefaultAvatar, _, _>(client_server::post_set_default_avatar)
+        .endpoint::<api::client_server::SetAvatarVisibility, _, _>(client_server::post_set_avatar_visibility)
+        .endpoint::<api::client_server::GetAvatarBlobs, _, _>(client_server::get_avatar_blobs)
+        .endpoint::<api::client_server::UploadAvatarBlob, _, _>(client_server::post_upload_avatar_blob)
+        .endpoint::<api::client_server::RemoveAvatarBlob, _, _>(client_server::post_remove_avatar_blob)
+        .endpoint::<api::client_server::GetUserAvatar, _, _>(client_server::post_get_user_avatar)
+        .endpoint::<api::client_server::GetPrivacy, _, _>(client_server::get_privacy)
+        .endpoint::<api::client_server::SetPrivacy, _, _>(client_server::post_set_privacy)
+        .endpoint::<api::client_server::GetBlocks, _, _>(client_server::get_blocks)
+        .endpoint::<api::client_server::Block, _, _>(client_server::post_block)
+        .endpoint::<api::client_server::Unblock, _, _>(client_server::post_unblock)
+        .endpoint::<api::client_server::GetProfile, _, _>(client_server::get_profile)
+        .endpoint::<api::client_server::SetProfile, _, _>(client_server::post_set_profile)
+        .endpoint::<api::client_server::GetUserProfile, _, _>(client_server::post_get_user_profile)
+        .endpoint::<api::client_server::SendInvite, _, _>(client_server::post_send_invite)
+        .endpoint::<api::client_server::RemoveInvite, _, _>(client_server::post_remove_invite)
+        .endpoint::<api::client_server::AcceptInvite, _, _>(client_server::post_accept_invite)
+        .endpoint::<api::client_server::DeclineInvite, _, _>(client_server::post_decline_invite)
+        .endpoint::<api::client_server::SendFriendRequest, _, _>(client_server::post_send_friend_request)
+        .endpoint::<api::client_server::AcceptFriendRequest, _, _>(client_server::post_accept_friend_request)
+        .endpoint::<api::client_server::DenyFriendRequest, _, _>(client_server::post_deny_friend_request)
+        .endpoint::<api::client_server::CancelFriendRequest, _, _>(client_server::post_cancel_friend_request)
+        .endpoint::<api::client_server::Unfriend, _, _>(client_server::post_unfriend)
+        .endpoint::<api::admin::GetFederation, _, _>(admin::get_federation)
+        .endpoint::<api::admin::AllowDomain, _, _>(admin::post_allow_domain)
+        .endpoint::<api::admin::DenyDomain, _, _>(admin::post_deny_domain)
+        .endpoint::<api::admin::ResetDomain, _, _>(admin::post_reset_domain)
+        .endpoint::<api::server_server::SendInvite, _, _>(server_server::post_send_invite)
+        .endpoint::<api::server_server::SendFriendRequest, _, _>(server_server::post_send_friend_request)
+        .endpoint::<api::server_server::AcceptFriendRequest, _, _>(server_server::post_accept_friend_request)
+        .endpoint::<api::server_server::DenyFriendRequest, _, _>(server_server::post_deny_friend_request)
+        .endpoint::<api::server_server::CancelFriendRequest, _, _>(server_server::post_cancel_friend_request)
+        .endpoint::<api::server_server::Unfriend, _, _>(server_server::post_unfriend)
+        .endpoint::<api::server_server::AnswerInvite, _, _>(server_server::post_answer_invite)
+        .endpoint::<api::server_server::CheckFriend, _, _>(server_server::post_check_friend)
+        .endpoint::<api::server_server::UpdatePresence, _, _>(server_server::post_update_presence)
+        .endpoint::<api::server_server::GetInstance, _, _>(server_server::post_get_instance)
+        .endpoint::<api::server_server::GetAvatar, _, _>(server_server::post_get_avatar)
+        .endpoint::<api::server_server::GetProfile, _, _>(server_server::post_get_profile)
+        .endpoint::<api::server_server::UpdateProfile, _, _>(server_server::post_update_profile)
+        .layer(middleware::map_response(advertise_protocol_version))
+        .layer(Extension(state.clone()))
         ;
+    tokio::spawn(outbox::run(state.clone()));
+    tokio::spawn(federation::run(state.clone()));
+    tokio::spawn(presence::run(state.clone()));
+    tokio::spawn(instances::run(state.clone()));
+    tokio::spawn(invites::run(state));
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
     println!("listening on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
     Ok(())
 }
@@ -116,175 +336,554 @@ async fn main() -> anyhow::Result<()> {
 async fn root(Extension(_state): Extension<State>) -> &'static str {
     "Hello World!"
 }
-async fn add_user(Extension(state): Extension<State>, Path(username): Path<String>) -> Result<impl IntoResponse> {
-    state.db.insert(username, serde_json::to_vec(&UserData::default())?)?;
+async fn advertise_protocol_version(mut response: Response) -> Response {
+    response.headers_mut().insert(api::PROTOCOL_VERSION_HEADER, HeaderValue::from(api::PROTOCOL_VERSION));
+    response
+}
+async fn get_discovery(Extension(state): Extension<State>) -> Json<api::Response<api::GetDiscovery>> {
+    Json(Discovery {
+        server: state.public_url.clone(),
+        protocol_version: api::PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    })
+}
+async fn get_server_key(Extension(state): Extension<State>) -> Json<api::Response<api::GetServerKey>> {
+    Json(state.server_key())
+}
+async fn register(Extension(state): Extension<State>, ConnectInfo(addr): ConnectInfo<SocketAddr>, Json(registration): Json<api::Request<api::Register>>) -> Result<Response> {
+    state.check_registration_rate(addr.ip())?;
+    let reject = |status: StatusCode, error: RegisterError| Ok((status, Json(error)).into_response());
+    if !is_valid_username(&registration.username) {
+        return reject(StatusCode::BAD_REQUEST, RegisterError::InvalidUsername);
+    }
+    if state.user_exists(&registration.username)? {
+        return reject(StatusCode::CONFLICT, RegisterError::UsernameTaken);
+    }
+    let invite_code = match (state.registration, &registration.invite_code) {
+        (config::RegistrationPolicy::Closed, _) => return reject(StatusCode::FORBIDDEN, RegisterError::RegistrationClosed),
+        (config::RegistrationPolicy::InviteOnly, None) => return reject(StatusCode::FORBIDDEN, RegisterError::InviteCodeRequired),
+        (config::RegistrationPolicy::InviteOnly, Some(code)) => {
+            if !state.take_registration_invite(code)? {
+                return reject(StatusCode::FORBIDDEN, RegisterError::InvalidInviteCode);
+            }
+            Some(code)
+        }
+        (config::RegistrationPolicy::Open, _) => None,
+    };
+    if !state.create_user(&registration.username)? {
+        // Someone registered the name between our check and the insert, so give the code back.
+        if let Some(code) = invite_code {
+            state.return_registration_invite(code)?;
+        }
+        return reject(StatusCode::CONFLICT, RegisterError::UsernameTaken);
+    }
+    state.set_password(&registration.username, &registration.password)?;
+    Ok(().into_response())
+}
+async fn login(Extension(state): Extension<State>, Path(username): Path<String>, Json(credentials): Json<api::Request<api::Login>>) -> Result<Json<api::Response<api::Login>>> {
+    state.check_login_rate(&username)?;
+    if !state.check_password(&username, &credentials.password)? {
+        return Err(ApiError::Unauthorized("Invalid username or password".to_string()).into());
+    }
+    Ok(Json(state.create_session(&username)?))
+}
+async fn get_avatar_blob(Extension(state): Extension<State>, Path(hash): Path<String>, headers: HeaderMap) -> Result<Response> {
+    Ok(blobs::blob_response(&hash, state.avatar_blob(&hash)?, &headers))
+}
+async fn logout(Extension(state): Extension<State>, auth::Session(_, token): auth::Session) -> Result<()> {
+    state.remove_session(&token)?;
     Ok(())
 }
+
+/// Declares routes from their [`Endpoint`] definition, so the path and method can't drift from
+/// what `nexus_client` calls.
+trait EndpointRouter {
+    fn endpoint<E: Endpoint, H: Handler<T, (), Body>, T: 'static>(self, handler: H) -> Self;
+}
+impl EndpointRouter for axum::Router {
+    fn endpoint<E: Endpoint, H: Handler<T, (), Body>, T: 'static>(self, handler: H) -> Self {
+        match E::METHOD {
+            Method::Get => self.route(E::PATH, get(handler)),
+            Method::Post => self.route(E::PATH, post(handler)),
+        }
+    }
+}
+
 mod client_server {
-    use axum::{Extension, Json};
+    use axum::Extension;
+    use axum::extract::RawBody;
     use axum::extract::Path;
-    use axum::response::IntoResponse;
-    use serde_json::Value;
-    use anyhow::{Context};
-    use crate::Result;
-    use nexus_common::{FriendRequest, FriendRequestUuid, Invite, InviteUuid, UnfriendRequest};
-    use crate::State;
+    use crate::{Json, Result};
+    use nexus_common::api::{self, Endpoint, Request, Response};
+    use nexus_common::api::client_server::*;
+    use nexus_common::{ApiError, EventKind, FriendRequestUuid, Invite, InviteStatus, InviteUuid};
+    use crate::{events, invites, State};
+    use crate::outbox::{OnDelivered, OnRejected};
+    use crate::auth::Session;
 
-    pub async fn get_friends(Extension(state): Extension<State>, Path(username): Path<String>) -> Result<impl IntoResponse> {
-        Ok(serde_json::to_string(&state
+    pub async fn get_outbox(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetOutbox>>> {
+        Ok(Json(state.outbox(username)?))
+    }
+    pub async fn post_dismiss_delivery(Extension(state): Extension<State>, Session(username, _): Session, Json(id): Json<Request<DismissDelivery>>) -> Result<()> {
+        println!("client_client::post_dismiss_delivery");
+        state.dismiss_delivery(&username, &id)
+    }
+    pub async fn post_retry_delivery(Extension(state): Extension<State>, Session(username, _): Session, Json(id): Json<Request<RetryDelivery>>) -> Result<()> {
+        println!("client_client::post_retry_delivery");
+        state.retry_delivery(&username, &id)
+    }
+    pub async fn get_friends(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetFriends>>> {
+        Ok(Json(state
             .user(username)?
             .friends
-        )?)
+        ))
     }
-    pub async fn get_sent_invites(Extension(state): Extension<State>, Path(username): Path<String>) -> Result<impl IntoResponse> {
-        Ok(serde_json::to_string(&state.user(username)?.sent_invites)?)
+    pub async fn get_sent_invites(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetSentInvites>>> {
+        Ok(Json(state.user(username)?.sent_invites.into_iter().collect()))
     }
-    pub async fn get_rec_invites(Extension(state): Extension<State>, Path(username): Path<String>) -> Result<impl IntoResponse> {
-        Ok(serde_json::to_string(&state.user(username)?.rec_invites)?)
+    pub async fn get_rec_invites(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetRecInvites>>> {
+        Ok(Json(state.user(username)?.rec_invites.into_iter().collect()))
     }
-    pub async fn get_sent_friend_requests(Extension(state): Extension<State>, Path(username): Path<String>) -> Result<impl IntoResponse> {
-        Ok(serde_json::to_string(&state
+    pub async fn get_sent_friend_requests(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetSentFriendRequests>>> {
+        Ok(Json(state
             .user(username)?
             .sent_friend_requests
-        )?)
+            .into_iter().collect()
+        ))
     }
-    pub async fn get_rec_friend_requests(Extension(state): Extension<State>, Path(username): Path<String>) -> Result<impl IntoResponse> {
-        Ok(serde_json::to_string(&state
+    pub async fn get_rec_friend_requests(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetRecFriendRequests>>> {
+        Ok(Json(state
             .user(username)?
             .rec_friend_requests
-        )?)
+            .into_iter().collect()
+        ))
+    }
+    pub async fn get_invite(Extension(state): Extension<State>, Session(username, _): Session, Path((_, uuid)): Path<(String, String)>) -> Result<Json<Response<GetInvite>>> {
+        Ok(Json(state.user(username)?.invites.remove(&InviteUuid(uuid)).ok_or_else(|| ApiError::NotFound("InviteUuid not found".to_string()))?))
     }
-    pub async fn get_invite(Extension(state): Extension<State>, Path((username, uuid)): Path<(String, String)>) -> Result<impl IntoResponse> {
-        Ok(serde_json::to_string(&state.user(username)?.invites.get(&InviteUuid(uuid)).with_context(|| "InviteUuid not found")?)?)
+    pub async fn get_resolve_invite(Extension(state): Extension<State>, Session(username, _): Session, Path((_, uuid)): Path<(String, String)>) -> Result<Json<Response<ResolveInvite>>> {
+        Ok(Json(state.resolve_invite(&username, &InviteUuid(uuid)).await?))
     }
-    pub async fn get_friend_request(Extension(state): Extension<State>, Path((username, uuid)): Path<(String, String)>) -> Result<impl IntoResponse> {
-        Ok(serde_json::to_string(&state
+    pub async fn get_friend_request(Extension(state): Extension<State>, Session(username, _): Session, Path((_, uuid)): Path<(String, String)>) -> Result<Json<Response<GetFriendRequest>>> {
+        Ok(Json(state
             .user(username)?
             .friend_requests
-                .get(&FriendRequestUuid(uuid)).with_context(|| "FriendRequestUuid not found")?
-        )?)
-    }
-    pub async fn post_send_invite(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
-        let invite: Invite = serde_json::from_value(payload)?;
-        state.user_mut(&username, |user| { user.invites.insert(invite.uuid.clone(), invite.clone());})?;
-        state.user_mut(&username, |user| { user.sent_invites.insert(invite.uuid.clone());})?;
-        state.reqwest_client
-            .post(invite.to.to_url().0 + "/friend/post/send-invite")
-            .json(&invite)
-            .send()
-            .await?;
+                .remove(&FriendRequestUuid(uuid)).ok_or_else(|| ApiError::NotFound("FriendRequestUuid not found".to_string()))?
+        ))
+    }
+    pub async fn get_presence(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetPresence>>> {
+        Ok(Json(state.presence(&username)?))
+    }
+    pub async fn get_friends_presence(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetFriendsPresence>>> {
+        Ok(Json(state.friends_presence(&username)?))
+    }
+    pub async fn post_set_presence(Extension(state): Extension<State>, Session(username, _): Session, Json(update): Json<Request<SetPresence>>) -> Result<()> {
+        println!("client_client::post_set_presence");
+        state.set_presence(&username, update).await
+    }
+    pub async fn post_heartbeat(Extension(state): Extension<State>, Session(username, _): Session) -> Result<()> {
+        state.heartbeat(&username).await
+    }
+    pub async fn get_instances(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetInstances>>> {
+        Ok(Json(state.hosted_instances(&username)?))
+    }
+    pub async fn get_friends_instances(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetFriendsInstances>>> {
+        Ok(Json(state.friends_instances(&username).await?))
+    }
+    pub async fn post_register_instance(Extension(state): Extension<State>, Session(username, _): Session, Json(registration): Json<Request<RegisterInstance>>) -> Result<Json<Response<RegisterInstance>>> {
+        println!("client_client::post_register_instance");
+        Ok(Json(state.register_instance(&username, registration)?))
+    }
+    pub async fn post_update_player_count(Extension(state): Extension<State>, Session(username, _): Session, Json(update): Json<Request<UpdatePlayerCount>>) -> Result<()> {
+        state.update_player_count(&username, update)
+    }
+    pub async fn post_close_instance(Extension(state): Extension<State>, Session(username, _): Session, Json(instance): Json<Request<CloseInstance>>) -> Result<()> {
+        println!("client_client::post_close_instance");
+        state.close_instance(&username, &instance)
+    }
+    pub async fn get_avatars(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetAvatars>>> {
+        Ok(Json(state.user(username)?.avatars))
+    }
+    pub async fn post_publish_avatar(Extension(state): Extension<State>, Session(username, _): Session, Json(avatar): Json<Request<PublishAvatar>>) -> Result<()> {
+        println!("client_client::post_publish_avatar");
+        state.publish_avatar(&username, avatar)
+    }
+    pub async fn post_ingest_avatar(Extension(state): Extension<State>, Session(username, _): Session, Json(avatar): Json<Request<IngestAvatar>>) -> Result<Json<Response<IngestAvatar>>> {
+        println!("client_client::post_ingest_avatar");
+        Ok(Json(state.ingest_avatar(&username, avatar).await?))
+    }
+    pub async fn post_remove_avatar(Extension(state): Extension<State>, Session(username, _): Session, Json(uuid): Json<Request<RemoveAvatar>>) -> Result<()> {
+        println!("client_client::post_remove_avatar");
+        state.remove_avatar(&username, &uuid)
+    }
+    pub async fn post_set_default_avatar(Extension(state): Extension<State>, Session(username, _): Session, Json(uuid): Json<Request<SetDefaultAvatar>>) -> Result<()> {
+        state.set_default_avatar(&username, &uuid)
+    }
+    pub async fn post_set_avatar_visibility(Extension(state): Extension<State>, Session(username, _): Session, Json(visibility): Json<Request<SetAvatarVisibility>>) -> Result<()> {
+        state.set_avatar_visibility(&username, visibility)
+    }
+    pub async fn get_avatar_blobs(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetAvatarBlobs>>> {
+        Ok(Json(state.avatar_blobs(&username)?))
+    }
+    pub async fn post_upload_avatar_blob(Extension(state): Extension<State>, Session(username, _): Session, RawBody(body): RawBody) -> Result<Json<Response<UploadAvatarBlob>>> {
+        println!("client_client::post_upload_avatar_blob");
+        Ok(Json(state.upload_avatar_blob(&username, body).await?))
+    }
+    pub async fn post_remove_avatar_blob(Extension(state): Extension<State>, Session(username, _): Session, Json(hash): Json<Request<RemoveAvatarBlob>>) -> Result<()> {
+        println!("client_client::post_remove_avatar_blob");
+        state.remove_avatar_blob(&username, &hash)
+    }
+    pub async fn post_get_user_avatar(Extension(state): Extension<State>, Session(username, _): Session, Json(request): Json<Request<GetUserAvatar>>) -> Result<Json<Response<GetUserAvatar>>> {
+        Ok(Json(state.user_avatar(&username, request).await?))
+    }
+    pub async fn get_privacy(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetPrivacy>>> {
+        Ok(Json(state.user(username)?.privacy))
+    }
+    pub async fn post_set_privacy(Extension(state): Extension<State>, Session(username, _): Session, Json(privacy): Json<Request<SetPrivacy>>) -> Result<()> {
+        println!("client_client::post_set_privacy");
+        state.set_privacy(&username, privacy)
+    }
+    pub async fn get_blocks(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetBlocks>>> {
+        Ok(Json(state.user(username)?.blocks))
+    }
+    pub async fn post_block(Extension(state): Extension<State>, Session(username, _): Session, Json(target): Json<Request<Block>>) -> Result<()> {
+        println!("client_client::post_block");
+        state.block(&username, target).await
+    }
+    pub async fn post_unblock(Extension(state): Extension<State>, Session(username, _): Session, Json(target): Json<Request<Unblock>>) -> Result<()> {
+        println!("client_client::post_unblock");
+        state.unblock(&username, target)
+    }
+    pub async fn get_profile(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetProfile>>> {
+        Ok(Json(state.profile_settings(&username)?))
+    }
+    pub async fn post_set_profile(Extension(state): Extension<State>, Session(username, _): Session, Json(settings): Json<Request<SetProfile>>) -> Result<()> {
+        println!("client_client::post_set_profile");
+        state.set_profile(&username, settings).await
+    }
+    pub async fn post_get_user_profile(Extension(state): Extension<State>, Session(username, _): Session, Json(user): Json<Request<GetUserProfile>>) -> Result<Json<Response<GetUserProfile>>> {
+        Ok(Json(state.user_profile(&username, user).await?))
+    }
+    pub async fn post_send_invite(Extension(state): Extension<State>, Session(username, _): Session, Json(invite): Json<Request<SendInvite>>) -> Result<()> {
+        (invite.from == state.local_user(&username)).then_some(()).ok_or_else(|| ApiError::Forbidden("Invite is not from this user".to_string()))?;
+        invites::check_invite(&invite)?;
+        (!state.blocks(&username, &invite.to)?).then_some(()).ok_or_else(|| ApiError::BadRequest(format!("{} is blocked", invite.to)))?;
+        let invite = Invite { status: InviteStatus::Pending, ..invite };
+        state.user_mut(&username, |user| {
+            user.invites.insert(invite.uuid.clone(), invite.clone());
+            user.sent_invites.insert(invite.uuid.clone());
+        })?;
+        let on_delivered = Some(OnDelivered::InviteDelivered(invite.uuid.clone()));
+        let on_rejected = Some(OnRejected::InviteRejected(invite.uuid.clone()));
+        state.deliver_with(&username, &invite.to.website, api::server_server::SendInvite::path(&[&invite.to.username]), &invite, on_delivered, on_rejected).await?;
         Ok(())
     }
-    pub async fn post_remove_invite(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
+    pub async fn post_accept_invite(Extension(state): Extension<State>, Session(username, _): Session, Json(invite_uuid): Json<Request<AcceptInvite>>) -> Result<Json<Response<AcceptInvite>>> {
+        println!("client_client::post_accept_invite");
+        // Only accept invites that still lead somewhere.
+        let instance = state.resolve_invite(&username, &invite_uuid).await?;
+        state.answer_invite(&username, &invite_uuid, true).await?;
+        Ok(Json(instance))
+    }
+    pub async fn post_decline_invite(Extension(state): Extension<State>, Session(username, _): Session, Json(invite_uuid): Json<Request<DeclineInvite>>) -> Result<()> {
+        println!("client_client::post_decline_invite");
+        state.answer_invite(&username, &invite_uuid, false).await
+    }
+    pub async fn post_remove_invite(Extension(state): Extension<State>, Session(username, _): Session, Json(invite_uuid): Json<Request<RemoveInvite>>) -> Result<()> {
         println!("client_client::post_remove_invite");
-        let invite_uuid: InviteUuid = serde_json::from_value(payload)?;
-        state.user_mut(&username, |user| { user.invites.remove(&invite_uuid); })?;
-        state.user_mut(&username, |user| { user.rec_invites.remove(&invite_uuid); })?;
-        state.user_mut(&username, |user| { user.sent_invites.remove(&invite_uuid); })?;
+        state.user_mut(&username, |user| {
+            // Other sessions of the same user hear about it through the events stream.
+            if user.invites.remove(&invite_uuid).is_some() {
+                events::record(user, EventKind::InviteRemoved(invite_uuid.clone()));
+            }
+            user.rec_invites.remove(&invite_uuid);
+            user.sent_invites.remove(&invite_uuid);
+        })?;
+        state.notify(&username);
         Ok(())
     }
 
-    pub async fn post_send_friend_request(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
-        let friend_request: FriendRequest = serde_json::from_value(payload)?;
-        state.try_user_mut(&username, |user| Ok({ user.friend_requests.insert(friend_request.uuid.clone(), friend_request.clone()); }))?;
-        state.try_user_mut(&username, |user| Ok({ user.sent_friend_requests.insert(friend_request.uuid.clone()); }))?;
-        state.reqwest_client
-            .post(friend_request.to.to_url().0 + "/public/post/send-friend-request")
-            .json(&friend_request)
-            .send()
-            .await?;
+    pub async fn post_send_friend_request(Extension(state): Extension<State>, Session(username, _): Session, Json(friend_request): Json<Request<SendFriendRequest>>) -> Result<()> {
+        (friend_request.from == state.local_user(&username)).then_some(()).ok_or_else(|| ApiError::Forbidden("Friend request is not from this user".to_string()))?;
+        (!state.blocks(&username, &friend_request.to)?).then_some(()).ok_or_else(|| ApiError::BadRequest(format!("{} is blocked", friend_request.to)))?;
+        state.user_mut(&username, |user| {
+            user.friend_requests.insert(friend_request.uuid.clone(), friend_request.clone());
+            user.sent_friend_requests.insert(friend_request.uuid.clone());
+        })?;
+        let on_rejected = Some(OnRejected::FriendRequestRejected(friend_request.uuid.clone()));
+        state.deliver_with(&username, &friend_request.to.website, api::server_server::SendFriendRequest::path(&[&friend_request.to.username]), &friend_request, None, on_rejected).await?;
         Ok(())
     }
-    pub async fn post_accept_friend_request(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
+    pub async fn post_accept_friend_request(Extension(state): Extension<State>, Session(username, _): Session, Json(friend_request_uuid): Json<Request<AcceptFriendRequest>>) -> Result<()> {
         println!("client_client::post_accept_friend_request");
-        let friend_request_uuid: FriendRequestUuid = serde_json::from_value(payload)?;
-        let user_from = state.user(&username)?.friend_requests.remove(&friend_request_uuid)
-            .context("FriendRequestUuid not found")?.from;
-        state.user_mut(&username, |user| { user.friend_requests.remove(&friend_request_uuid).unwrap(); })?;
-        state.user_mut(&username, |user| { user.rec_friend_requests.remove(&friend_request_uuid); })?;
-        state.reqwest_client
-            .post(user_from.to_url().0 + "/public/post/accept-friend-request")
-            .json(&friend_request_uuid)
-            .send()
-            .await?;
-        state.user_mut(username, |user| user.friends.push(user_from.clone()) )?;
+        let user_from = state.try_user_mut(&username, |user| {
+            let user_from = user.friend_requests.remove(&friend_request_uuid).ok_or_else(|| ApiError::NotFound("FriendRequestUuid not found".to_string()))?.from;
+            user.rec_friend_requests.remove(&friend_request_uuid);
+            if !user.friends.contains(&user_from) {
+                user.friends.push(user_from.clone());
+            }
+            Ok(user_from)
+        })?;
+        state.deliver(&username, &user_from.website, api::server_server::AcceptFriendRequest::path(&[&user_from.username]), &friend_request_uuid).await?;
         Ok(())
     }
-    pub async fn post_deny_friend_request(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
+    pub async fn post_deny_friend_request(Extension(state): Extension<State>, Session(username, _): Session, Json(friend_request_uuid): Json<Request<DenyFriendRequest>>) -> Result<()> {
         println!("client_client::post_deny_friend_request");
-        let friend_request_uuid: FriendRequestUuid = serde_json::from_value(payload)?;
-        let user_from = state.user(&username)?.friend_requests.remove(&friend_request_uuid)
-            .context("FriendRequestUuid not found")?.from;
-        state.user_mut(&username, |user| { user.friend_requests.remove(&friend_request_uuid).unwrap(); })?;
-        state.user_mut(&username, |user| { user.rec_friend_requests.remove(&friend_request_uuid); })?;
-        state.reqwest_client
-            .post(user_from.to_url().0 + "/public/post/deny-friend-request")
-            .json(&friend_request_uuid)
-            .send()
-            .await?;
+        let user_from = state.try_user_mut(&username, |user| {
+            let user_from = user.friend_requests.remove(&friend_request_uuid).ok_or_else(|| ApiError::NotFound("FriendRequestUuid not found".to_string()))?.from;
+            user.rec_friend_requests.remove(&friend_request_uuid);
+            Ok(user_from)
+        })?;
+        state.deliver(&username, &user_from.website, api::server_server::DenyFriendRequest::path(&[&user_from.username]), &friend_request_uuid).await?;
+        Ok(())
+    }
+    pub async fn post_cancel_friend_request(Extension(state): Extension<State>, Session(username, _): Session, Json(friend_request_uuid): Json<Request<CancelFriendRequest>>) -> Result<()> {
+        println!("client_client::post_cancel_friend_request");
+        let user_to = state.try_user_mut(&username, |user| {
+            user.sent_friend_requests.remove(&friend_request_uuid).then_some(()).ok_or_else(|| ApiError::NotFound("FriendRequestUuid not found".to_string()))?;
+            Ok(user.friend_requests.remove(&friend_request_uuid).ok_or_else(|| ApiError::NotFound("FriendRequestUuid not found".to_string()))?.to)
+        })?;
+        state.deliver(&username, &user_to.website, api::server_server::CancelFriendRequest::path(&[&user_to.username]), &friend_request_uuid).await?;
         Ok(())
     }
-    pub async fn post_unfriend(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
+    pub async fn post_unfriend(Extension(state): Extension<State>, Session(username, _): Session, Json(unfriend_request): Json<Request<Unfriend>>) -> Result<()> {
         println!("client_client::post_unfriend");
-        let unfriend_request: UnfriendRequest = serde_json::from_value(payload)?;
+        (unfriend_request.from == state.local_user(&username)).then_some(()).ok_or_else(|| ApiError::Forbidden("Unfriend request is not from this user".to_string()))?;
         state.user_mut(&username, |user| { user.friends.retain(|f| f.clone() != unfriend_request.to); })?;
-        state.reqwest_client
-            .post(unfriend_request.to.to_url().0 + "/friend/post/unfriend")
-            .json(&unfriend_request)
-            .send()
-            .await?;
+        state.deliver(&username, &unfriend_request.to.website, api::server_server::Unfriend::path(&[&unfriend_request.to.username]), &unfriend_request).await?;
         Ok(())
     }
 }
 
+mod admin {
+    use axum::Extension;
+    use nexus_common::api::{Request, Response};
+    use nexus_common::api::admin::*;
+    use crate::auth::Session;
+    use crate::{Json, Result, State};
+
+    pub async fn get_federation(Extension(state): Extension<State>, Session(username, _): Session) -> Result<Json<Response<GetFederation>>> {
+        state.check_admin(&username)?;
+        Ok(Json(state.federation_policy()?))
+    }
+    pub async fn post_allow_domain(Extension(state): Extension<State>, Session(username, _): Session, Json(domain): Json<Request<AllowDomain>>) -> Result<()> {
+        println!("admin::post_allow_domain");
+        state.check_admin(&username)?;
+        state.allow_domain(&domain)
+    }
+    pub async fn post_deny_domain(Extension(state): Extension<State>, Session(username, _): Session, Json(defederation): Json<Request<DenyDomain>>) -> Result<()> {
+        println!("admin::post_deny_domain");
+        state.check_admin(&username)?;
+        state.deny_domain(defederation)
+    }
+    pub async fn post_reset_domain(Extension(state): Extension<State>, Session(username, _): Session, Json(domain): Json<Request<ResetDomain>>) -> Result<()> {
+        println!("admin::post_reset_domain");
+        state.check_admin(&username)?;
+        state.reset_domain(&domain)
+    }
+}
+
 mod server_server {
-    use axum::{Extension, Json};
+    use axum::Extension;
     use axum::extract::Path;
-    use axum::response::IntoResponse;
-    use serde_json::Value;
-    use nexus_common::{FriendRequest, FriendRequestUuid, Invite, UnfriendRequest};
-    use anyhow::{Context};
-    use crate::State;
+    use nexus_common::api::{Request, Response};
+    use nexus_common::api::server_server::*;
+    use nexus_common::{ApiError, EventKind, Invite, InviteStatus};
+    use crate::{events, invites};
+    use crate::federation::Federated;
+    use crate::{no_such_user, Json, State};
     use crate::Result;
 
-    pub async fn post_send_invite(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
+    pub async fn post_send_invite(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<SendInvite>>) -> Result<()> {
         println!("server_server::post_send_invite");
-        let invite: Invite = serde_json::from_value(payload)?;
-        state.user_mut(&username, |user| { user.invites.insert(invite.uuid.clone(), invite.clone()); })?;
-        state.user_mut(&username, |user| { user.rec_invites.insert(invite.uuid.clone()); })?;
+        request.check_origin(&request.body.from)?;
+        invites::check_invite(&request.body)?;
+        (state.user_exists(&username)?).then_some(()).ok_or_else(|| no_such_user(&username))?;
+        state.check_target_rate(&username)?;
+        // Dropped rather than refused, so blocked users can't tell.
+        if state.blocks(&username, &request.body.from)? {
+            return Ok(());
+        }
+        state.check_invite_allowed(&username, &request.body.from)?;
+        let invite = Invite { status: InviteStatus::Delivered, ..request.body };
+        state.user_mut(&username, |user| {
+            user.invites.insert(invite.uuid.clone(), invite.clone());
+            user.rec_invites.insert(invite.uuid.clone());
+            events::record(user, EventKind::InviteReceived(invite.clone()));
+        })?;
+        state.notify(&username);
         Ok(())
     }
 
-    pub async fn post_send_friend_request(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
+    pub async fn post_send_friend_request(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<SendFriendRequest>>) -> Result<()> {
         println!("server_server::post_send_friend_request");
-        let friend_request: FriendRequest = serde_json::from_value(payload)?;
-        state.user_mut(&username, |user| { user.friend_requests.insert(friend_request.uuid.clone(), friend_request.clone()); })?;
-        state.user_mut(&username, |user| { user.rec_friend_requests.insert(friend_request.uuid.clone()); })?;
+        request.check_origin(&request.body.from)?;
+        (state.user_exists(&username)?).then_some(()).ok_or_else(|| no_such_user(&username))?;
+        state.check_target_rate(&username)?;
+        if state.blocks(&username, &request.body.from)? {
+            return Ok(());
+        }
+        state.check_friend_request_allowed(&username, &request.body.from).await?;
+        let friend_request = request.body;
+        state.try_user_mut(&username, |user| {
+            // A retried delivery of a request we already have doesn't count twice.
+            if !user.rec_friend_requests.contains(&friend_request.uuid) {
+                state.check_pending_friend_requests(user, &friend_request.from)?;
+            }
+            user.friend_requests.insert(friend_request.uuid.clone(), friend_request.clone());
+            user.rec_friend_requests.insert(friend_request.uuid.clone());
+            events::record(user, EventKind::FriendRequestReceived(friend_request.clone()));
+            Ok(())
+        })?;
+        state.notify(&username);
         Ok(())
     }
 
-    pub async fn post_accept_friend_request(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
+    pub async fn post_accept_friend_request(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<AcceptFriendRequest>>) -> Result<()> {
         println!("server_server::post_accept_friend_request");
-        let friend_request_uuid: FriendRequestUuid = serde_json::from_value(payload)?;
-        state.user_mut(&username, |user| { user.sent_friend_requests.remove(&friend_request_uuid); })?;
-        let friend_request = state.user(&username)?.friend_requests.remove(&friend_request_uuid).with_context(|| "FriendRequestUuid did not exist")?;
-        state.user_mut(&username, |user| { user.friend_requests.remove(&friend_request_uuid).unwrap(); })?;
-        state.user_mut(username, |user| user.friends.push(friend_request.to.clone()))?;
+        let friend_request_uuid = request.body.clone();
+        state.try_user_mut(&username, |user| {
+            let friend_request = user.friend_requests.remove(&friend_request_uuid).ok_or_else(|| ApiError::NotFound("FriendRequestUuid did not exist".to_string()))?;
+            request.check_origin(&friend_request.to)?;
+            user.sent_friend_requests.remove(&friend_request_uuid);
+            if !user.friends.contains(&friend_request.to) {
+                user.friends.push(friend_request.to.clone());
+            }
+            events::record(user, EventKind::FriendRequestAccepted(friend_request));
+            Ok(())
+        })?;
+        state.notify(&username);
         Ok(())
     }
 
-    pub async fn post_deny_friend_request(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
+    pub async fn post_deny_friend_request(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<DenyFriendRequest>>) -> Result<()> {
         println!("server_server::post_deny_friend_request");
-        let friend_request_uuid: FriendRequestUuid = serde_json::from_value(payload)?;
-        state.user_mut(&username, |user| user.sent_friend_requests.retain(|f| f.0 != friend_request_uuid.0))?;
-        state.try_user_mut(&username, |user| Ok({user.friend_requests.remove(&friend_request_uuid).with_context(|| "FriendRequestUuid not found")?;}))?;
+        let friend_request_uuid = request.body.clone();
+        state.try_user_mut(&username, |user| {
+            let friend_request = user.friend_requests.remove(&friend_request_uuid).ok_or_else(|| ApiError::NotFound("FriendRequestUuid not found".to_string()))?;
+            request.check_origin(&friend_request.to)?;
+            user.sent_friend_requests.remove(&friend_request_uuid);
+            events::record(user, EventKind::FriendRequestDenied(friend_request));
+            Ok(())
+        })?;
+        state.notify(&username);
         Ok(())
     }
 
-    pub async fn post_unfriend(Extension(state): Extension<State>, Path(username): Path<String>, Json(payload): Json<Value>) -> Result<impl IntoResponse> {
+    pub async fn post_cancel_friend_request(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<CancelFriendRequest>>) -> Result<()> {
+        println!("server_server::post_cancel_friend_request");
+        let friend_request_uuid = request.body.clone();
+        let cancelled = state.try_user_mut(&username, |user| {
+            // Already answered, so there is nothing left to take back.
+            if !user.rec_friend_requests.contains(&friend_request_uuid) {
+                return Ok(false);
+            }
+            let friend_request = user.friend_requests.remove(&friend_request_uuid).ok_or_else(|| ApiError::NotFound("FriendRequestUuid not found".to_string()))?;
+            request.check_origin(&friend_request.from)?;
+            user.rec_friend_requests.remove(&friend_request_uuid);
+            events::record(user, EventKind::FriendRequestCancelled(friend_request));
+            Ok(true)
+        })?;
+        if cancelled {
+            state.notify(&username);
+        }
+        Ok(())
+    }
+
+    pub async fn post_unfriend(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<Unfriend>>) -> Result<()> {
         println!("server_server::post_unfriend");
-        let unfriend_request: UnfriendRequest = serde_json::from_value(payload)?;
-        state.user_mut(&username, |user| user.friends.retain(|f| f.clone() != unfriend_request.from))?;
+        request.check_origin(&request.body.from)?;
+        let unfriend_request = request.body;
+        state.user_mut(&username, |user| {
+            user.friends.retain(|f| f.clone() != unfriend_request.from);
+            events::record(user, EventKind::Unfriended(unfriend_request.from.clone()));
+        })?;
+        state.notify(&username);
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub async fn post_answer_invite(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<AnswerInvite>>) -> Result<()> {
+        println!("server_server::post_answer_invite");
+        let answer = request.body.clone();
+        state.try_user_mut(&username, |user| {
+            user.sent_invites.contains(&answer.uuid).then_some(()).ok_or_else(|| ApiError::NotFound("InviteUuid not found".to_string()))?;
+            let invite = user.invites.get_mut(&answer.uuid).ok_or_else(|| ApiError::NotFound("InviteUuid not found".to_string()))?;
+            request.check_origin(&invite.to)?;
+            invite.status = match answer.accepted {
+                true => InviteStatus::Accepted,
+                false => InviteStatus::Declined,
+            };
+            let invite = invite.clone();
+            events::record(user, EventKind::InviteUpdated(invite));
+            Ok(())
+        })?;
+        state.notify(&username);
+        Ok(())
+    }
+    pub async fn post_check_friend(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<CheckFriend>>) -> Result<Json<Response<CheckFriend>>> {
+        request.check_origin(&request.body.from)?;
+        Ok(Json(state.check_friend(&username, &request.body)?))
+    }
+    pub async fn post_update_presence(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<UpdatePresence>>) -> Result<()> {
+        request.check_origin(&request.body.from)?;
+        if state.blocks(&username, &request.body.from)? {
+            return Ok(());
+        }
+        (state.user(&username)?.friends.contains(&request.body.from)).then_some(()).ok_or_else(|| ApiError::Forbidden("Presence is only accepted from friends".to_string()))?;
+        state.receive_presence(request.body)
+    }
+    pub async fn post_get_avatar(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<GetAvatar>>) -> Result<Json<Response<GetAvatar>>> {
+        request.check_origin(&request.body.from)?;
+        Ok(Json(state.local_avatar(&username, &request.body)?))
+    }
+    pub async fn post_get_profile(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<GetProfile>>) -> Result<Json<Response<GetProfile>>> {
+        request.check_origin(&request.body.from)?;
+        Ok(Json(state.local_profile(&username, &request.body.from)?))
+    }
+    pub async fn post_update_profile(Extension(state): Extension<State>, Path(username): Path<String>, request: Federated<Request<UpdateProfile>>) -> Result<()> {
+        println!("server_server::post_update_profile");
+        request.check_origin(&request.body.from)?;
+        (state.user(&username)?.friends.contains(&request.body.from)).then_some(()).ok_or_else(|| ApiError::Forbidden("Profiles are only accepted from friends".to_string()))?;
+        state.receive_profile(&username, request.body)
+    }
+    pub async fn post_get_instance(Extension(state): Extension<State>, request: Federated<Request<GetInstance>>) -> Result<Json<Response<GetInstance>>> {
+        request.check_origin(&request.body.from)?;
+        Ok(Json(state.visible_instance(&request.body.instance, &request.body.from)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use nexus_common::FriendRequestUuid;
+    use crate::config::Config;
+    use crate::storage::{Backend, StorageConfig};
+    use crate::State;
+
+    fn hammer(backend: Backend, path: Option<String>) {
+        let config = Config { storage: StorageConfig { backend, path }, ..Default::default() };
+        let state = State::new(0, &config);
+        assert!(state.create_user("malek").unwrap());
+        let threads = (0..8).map(|t| {
+            let state = state.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    state.user_mut("malek", |user| { user.sent_friend_requests.insert(FriendRequestUuid(format!("{t}-{i}"))); }).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(state.user("malek").unwrap().sent_friend_requests.len(), 8 * 50);
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        hammer(Backend::Memory, None);
+        hammer(Backend::Sqlite, Some(String::from(":memory:")));
+        let sled_path = std::env::temp_dir().join(format!("nexus-hammer-{}", std::process::id()));
+        hammer(Backend::Sled, Some(sled_path.to_string_lossy().to_string()));
+        let _ = std::fs::remove_dir_all(sled_path);
+    }
+}
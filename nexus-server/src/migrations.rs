@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use nexus_common::non_api_structs::UserData;
+use crate::storage::{Storage, Table};
+
+/// Version written for every stored `UserData`. Bump it and add a [`Migration`] to
+/// [`USER_MIGRATIONS`] whenever a stored field changes meaning or shape. Purely additive fields
+/// can use `#[serde(default)]` instead.
+pub const USER_DATA_VERSION: u32 = 2;
+
+/// Upgrades the JSON of a record stored at version `from` to version `from + 1`.
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    pub migrate: fn(Value) -> anyhow::Result<Value>,
+}
+
+pub const USER_MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "wrap unversioned records in an envelope",
+        migrate: Ok,
+    },
+    Migration {
+        from: 1,
+        description: "store usernames as user@domain strings",
+        // `Username` still reads the old struct form, so a round trip rewrites every one of them.
+        migrate: |data| Ok(serde_json::to_value(serde_json::from_value::<UserData>(data)?)?),
+    },
+];
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    data: Value,
+}
+
+/// Reads a stored user record of any known version. Records from before versioning are bare
+/// `UserData` JSON and count as version 0. Returns the record and the version it was stored at.
+pub fn decode_user(bytes: &[u8]) -> anyhow::Result<(UserData, u32)> {
+    let value: Value = serde_json::from_slice(bytes)?;
+    let is_envelope = value.get("version").is_some() && value.get("data").is_some();
+    let Envelope { version, mut data } = if is_envelope {
+        serde_json::from_value(value)?
+    } else {
+        Envelope { version: 0, data: value }
+    };
+    if version > USER_DATA_VERSION {
+        return Err(anyhow!("User record is version {version} but this server only understands up to {USER_DATA_VERSION}"));
+    }
+    for migration in USER_MIGRATIONS.iter().filter(|m| m.from >= version) {
+        data = (migration.migrate)(data).with_context(|| format!("Error migrating user record from version {}", migration.from))?;
+    }
+    Ok((serde_json::from_value(data)?, version))
+}
+
+pub fn encode_user(user: &UserData) -> anyhow::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&Envelope { version: USER_DATA_VERSION, data: serde_json::to_value(user)? })?)
+}
+
+/// Upgrades every stored user record to [`USER_DATA_VERSION`], printing what changes.
+/// With `dry_run` nothing is written. Records that can't be read are listed and left alone, and
+/// make it fail once the rest are done.
+pub fn migrate_all(storage: &dyn Storage, dry_run: bool) -> anyhow::Result<()> {
+    let mut upgraded = 0;
+    let mut unreadable = 0;
+    let users = storage.scan(Table::Users)?;
+    for (user, bytes) in &users {
+        let (user_data, version) = match decode_user(bytes) {
+            Ok(decoded) => decoded,
+            Err(error) => {
+                println!("{user}: can't be read: {error:#}");
+                unreadable += 1;
+                continue;
+            }
+        };
+        if version == USER_DATA_VERSION {
+            continue;
+        }
+        for migration in USER_MIGRATIONS.iter().filter(|m| m.from >= version) {
+            println!("{user}: v{} -> v{}: {}", migration.from, migration.from + 1, migration.description);
+        }
+        if !dry_run && !storage.compare_and_swap(Table::Users, user, Some(bytes), Some(&encode_user(&user_data)?))? {
+            println!("{user}: changed while migrating, it will be upgraded when next read");
+            continue;
+        }
+        upgraded += 1;
+    }
+    let verb = if dry_run { "would upgrade" } else { "upgraded" };
+    println!("{verb} {upgraded} of {} user records to v{USER_DATA_VERSION}", users.len());
+    match unreadable {
+        0 => Ok(()),
+        _ => Err(anyhow!("{unreadable} user records can't be read and need fixing by hand")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_legacy_records() {
+        let legacy = br#"{"friends":[{"username":"lyuma","website":"localhost:9000"}],"sent_friend_requests":[],"rec_friend_requests":[],"friend_requests":{},"invites":{},"sent_invites":[],"rec_invites":[]}"#;
+        let (user, version) = decode_user(legacy).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(user.friends[0].username, "lyuma");
+        let encoded = encode_user(&user).unwrap();
+        assert!(String::from_utf8_lossy(&encoded).contains(r#""lyuma@localhost:9000""#));
+        let (_, version) = decode_user(&encoded).unwrap();
+        assert_eq!(version, USER_DATA_VERSION);
+    }
+
+    #[test]
+    fn rejects_newer_records() {
+        let newer = serde_json::to_vec(&Envelope { version: USER_DATA_VERSION + 1, data: serde_json::to_value(UserData::default()).unwrap() }).unwrap();
+        assert!(decode_user(&newer).is_err());
+    }
+
+    #[test]
+    fn skips_unreadable_records() {
+        let storage = crate::storage::MemoryStorage::default();
+        storage.insert(Table::Users, "malek", &encode_user(&UserData::default()).unwrap()).unwrap();
+        storage.insert(Table::Users, "broken", br#"{"friends":[{"username":"mal ek","website":"localhost:9000"}]}"#).unwrap();
+        assert!(migrate_all(&storage, true).is_err());
+        assert!(storage.get(Table::Users, "broken").unwrap().is_some());
+    }
+}
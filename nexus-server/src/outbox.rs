@@ -0,0 +1,408 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use nexus_common::{ApiError, Delivery, DeliveryStatus, FriendRequestUuid, InviteUuid};
+use crate::storage::Table;
+use crate::{unix_time, Result, State};
+
+/// How long an inline delivery attempt may take before the worker is allowed to retry the entry.
+const INLINE_ATTEMPT_LEASE_SECS: u64 = 30;
+const MAX_BACKOFF_SECS: u64 = 60 * 60;
+const PRUNE_FAILED_INTERVAL_SECS: u64 = 60;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    /// Deliveries still failing this long after they were queued are marked failed and no longer retried.
+    pub dead_letter_after_secs: u64,
+    /// The delay before the first retry, doubled on every further attempt.
+    pub base_backoff_secs: u64,
+    /// How long failed deliveries are listed before they are dropped, unless the user dismisses or
+    /// retries them first. 0 keeps them until then.
+    pub keep_failed_secs: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self { dead_letter_after_secs: 60 * 60 * 24, base_backoff_secs: 2, keep_failed_secs: 60 * 60 * 24 * 7 }
+    }
+}
+
+/// A queued federation POST, stored in [`Table::Outbox`] under its delivery id, or in
+/// [`Table::FailedDeliveries`] once given up on.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct OutboxEntry {
+    owner: String,
+    delivery: Delivery,
+    /// The domain the message is for and the endpoint path on its server. Entries queued before
+    /// discovery only have `delivery.url`, see [`OutboxEntry::target`].
+    #[serde(default)]
+    domain: String,
+    #[serde(default)]
+    path: String,
+    body: Value,
+    #[serde(default)]
+    on_delivered: Option<OnDelivered>,
+    #[serde(default)]
+    on_rejected: Option<OnRejected>,
+    /// When the delivery was given up on.
+    #[serde(default)]
+    failed: u64,
+    /// Whether that was because the remote server refused it.
+    #[serde(default)]
+    rejected: bool,
+}
+
+/// Follow-up work for when a delivery succeeds, stored with the entry so it also happens when the
+/// background task is the one that gets it through.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnDelivered {
+    /// Marks the owner's sent invite as delivered.
+    InviteDelivered(InviteUuid),
+}
+
+/// Like [`OnDelivered`], for when the remote server refuses the message.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnRejected {
+    /// Drops the owner's sent friend request.
+    FriendRequestRejected(FriendRequestUuid),
+    /// Marks the owner's sent invite as rejected.
+    InviteRejected(InviteUuid),
+}
+
+impl OutboxEntry {
+    /// A pending entry, first attempted `delay` seconds from now.
+    fn new(owner: &str, domain: String, path: String, body: Value, delay: u64) -> Self {
+        let now = unix_time();
+        Self {
+            owner: owner.to_string(),
+            delivery: Delivery {
+                id: rand::random::<[u8; 16]>().iter().map(|b| format!("{b:02x}")).collect(),
+                url: format!("{domain}{path}"),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                created: now,
+                next_attempt: now + delay,
+                last_error: None,
+            },
+            domain,
+            path,
+            body,
+            on_delivered: None,
+            on_rejected: None,
+            failed: 0,
+            rejected: false,
+        }
+    }
+    fn target(&self) -> Result<(String, String)> {
+        if !self.domain.is_empty() {
+            return Ok((self.domain.clone(), self.path.clone()));
+        }
+        let url = reqwest::Url::parse(&self.delivery.url)?;
+        let host = url.host_str().unwrap_or_default();
+        let domain = match url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        Ok((domain, url.path().to_string()))
+    }
+}
+
+enum Attempt {
+    Delivered,
+    /// With how many seconds the remote server asked us to wait, if it did.
+    Retry(String, Option<u64>),
+    Rejected(String),
+}
+
+impl State {
+    /// Sends a federation POST to `path` on `domain`'s server on behalf of `owner`. The message is
+    /// written to the outbox before the first attempt, so if the remote server is unreachable it is
+    /// retried in the background and this still succeeds. Only an explicit rejection from the
+    /// remote server is an error.
+    pub async fn deliver(&self, owner: impl AsRef<str>, domain: impl Into<String>, path: impl Into<String>, body: &impl Serialize) -> Result<()> {
+        self.deliver_with(owner, domain, path, body, None, None).await
+    }
+    /// [`State::deliver`], running `on_delivered` once the message gets through or `on_rejected`
+    /// if the remote server refuses it, whether that happens now or on a later retry.
+    pub async fn deliver_with(&self, owner: impl AsRef<str>, domain: impl Into<String>, path: impl Into<String>, body: &impl Serialize, on_delivered: Option<OnDelivered>, on_rejected: Option<OnRejected>) -> Result<()> {
+        let mut entry = OutboxEntry::new(owner.as_ref(), domain.into(), path.into(), serde_json::to_value(body)?, INLINE_ATTEMPT_LEASE_SECS);
+        entry.on_delivered = on_delivered;
+        entry.on_rejected = on_rejected;
+        self.save_outbox_entry(&entry)?;
+        match self.attempt(&mut entry).await {
+            Attempt::Delivered => Ok(()),
+            Attempt::Retry(..) => self.save_outbox_entry(&entry),
+            Attempt::Rejected(error) => {
+                self.storage.remove(Table::Outbox, &entry.delivery.id)?;
+                Err(ApiError::RemoteRejected(format!("{} rejected the request: {error}", entry.delivery.url)).into())
+            }
+        }
+    }
+    /// Queues a federation POST like [`State::deliver`], but leaves every attempt to the background
+    /// task, for messages whose sender doesn't wait for the answer.
+    pub fn enqueue(&self, owner: impl AsRef<str>, domain: impl Into<String>, path: impl Into<String>, body: &impl Serialize) -> Result<()> {
+        self.save_outbox_entry(&OutboxEntry::new(owner.as_ref(), domain.into(), path.into(), serde_json::to_value(body)?, 0))
+    }
+    /// Every queued or failed delivery owned by `owner`.
+    pub fn outbox(&self, owner: impl AsRef<str>) -> Result<Vec<Delivery>> {
+        let mut entries = self.outbox_entries(Table::Outbox)?;
+        entries.extend(self.outbox_entries(Table::FailedDeliveries)?);
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.owner == owner.as_ref())
+            .map(|entry| entry.delivery)
+            .collect())
+    }
+    fn outbox_entries(&self, table: Table) -> Result<Vec<OutboxEntry>> {
+        self.storage.scan(table)?
+            .into_iter()
+            .map(|(_, bytes)| Ok(serde_json::from_slice(&bytes)?))
+            .collect()
+    }
+    /// Stores a pending entry in the outbox, or moves a failed one out of it.
+    fn save_outbox_entry(&self, entry: &OutboxEntry) -> Result<()> {
+        let bytes = serde_json::to_vec(entry)?;
+        match entry.delivery.status {
+            DeliveryStatus::Pending => {
+                self.storage.insert(Table::Outbox, &entry.delivery.id, &bytes)?;
+            }
+            DeliveryStatus::Failed => {
+                self.storage.insert(Table::FailedDeliveries, &entry.delivery.id, &bytes)?;
+                self.storage.remove(Table::Outbox, &entry.delivery.id)?;
+            }
+        }
+        Ok(())
+    }
+    /// Takes one of `owner`'s failed deliveries out of [`Table::FailedDeliveries`], unless it is
+    /// gone or `check` refuses it.
+    fn take_failed_delivery(&self, owner: &str, id: &str, check: impl FnOnce(&OutboxEntry) -> Result<()>) -> Result<OutboxEntry> {
+        let not_found = || ApiError::NotFound(format!("No failed delivery {id}"));
+        let bytes = self.storage.get(Table::FailedDeliveries, id)?.ok_or_else(not_found)?;
+        let entry: OutboxEntry = serde_json::from_slice(&bytes)?;
+        (entry.owner == owner).then_some(()).ok_or_else(not_found)?;
+        check(&entry)?;
+        self.storage.compare_and_swap(Table::FailedDeliveries, id, Some(&bytes), None)?.then_some(()).ok_or_else(not_found)?;
+        Ok(entry)
+    }
+    pub fn dismiss_delivery(&self, owner: &str, id: &str) -> Result<()> {
+        self.take_failed_delivery(owner, id, |_| Ok(()))?;
+        Ok(())
+    }
+    /// Queues a failed delivery again, as if it had just been sent.
+    pub fn retry_delivery(&self, owner: &str, id: &str) -> Result<()> {
+        let mut entry = self.take_failed_delivery(owner, id, |entry| {
+            (!entry.rejected).then_some(()).ok_or_else(|| ApiError::BadRequest("The remote server rejected this delivery, it can't be retried".to_string()))?;
+            Ok(())
+        })?;
+        let now = unix_time();
+        entry.delivery = Delivery { status: DeliveryStatus::Pending, attempts: 0, created: now, next_attempt: now, ..entry.delivery };
+        entry.failed = 0;
+        self.save_outbox_entry(&entry)
+    }
+    /// Drops failed deliveries kept longer than [`OutboxConfig::keep_failed_secs`].
+    fn prune_failed_deliveries(&self) -> Result<()> {
+        let keep = self.outbox_config.keep_failed_secs;
+        if keep == 0 {
+            return Ok(());
+        }
+        let now = unix_time();
+        for entry in self.outbox_entries(Table::FailedDeliveries)? {
+            if now > entry.failed + keep {
+                self.storage.remove(Table::FailedDeliveries, &entry.delivery.id)?;
+            }
+        }
+        Ok(())
+    }
+    /// Tries to send `entry` once, removing it from the outbox on success and otherwise recording
+    /// the failure and scheduling the next attempt.
+    async fn attempt(&self, entry: &mut OutboxEntry) -> Attempt {
+        let result = match self.post_entry(entry).await {
+            Ok(response) if response.status().is_success() => Attempt::Delivered,
+            Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                let retry_after = response.headers().get(reqwest::header::RETRY_AFTER)
+                    .and_then(|retry_after| retry_after.to_str().ok())
+                    .and_then(|retry_after| retry_after.parse::<u64>().ok());
+                let text = response.text().await.unwrap_or_default();
+                Attempt::Retry(serde_json::from_str::<ApiError>(&text).map_or(text, |error| error.to_string()), retry_after)
+            }
+            Ok(response) if response.status().is_client_error() => {
+                let status = response.status();
+                let text = response.text().await.unwrap_or_default();
+                // Only the remote server refusing the message itself is final. Anything else, such
+                // as a 401 while it can't fetch our key or a proxy's 408, may pass on a retry.
+                match serde_json::from_str::<ApiError>(&text) {
+                    Ok(error @ (ApiError::BadRequest(_) | ApiError::Forbidden(_) | ApiError::NotFound(_) | ApiError::AlreadyExists(_))) => Attempt::Rejected(error.to_string()),
+                    Ok(error) => Attempt::Retry(error.to_string(), None),
+                    Err(_) => Attempt::Retry(format!("{status} {text}"), None),
+                }
+            }
+            Ok(response) => Attempt::Retry(response.status().to_string(), None),
+            Err(error) => match error.0.downcast_ref::<ApiError>() {
+                // This server refuses to federate with the domain, so retrying won't help.
+                Some(ApiError::Forbidden(reason)) => Attempt::Rejected(reason.clone()),
+                _ => Attempt::Retry(format!("{:#}", error.0), None),
+            },
+        };
+        entry.delivery.attempts += 1;
+        match &result {
+            Attempt::Delivered => {
+                if let Err(error) = self.storage.remove(Table::Outbox, &entry.delivery.id) {
+                    println!("outbox: error removing delivered {}: {error}", entry.delivery.id);
+                }
+                let followed_up = match &entry.on_delivered {
+                    Some(OnDelivered::InviteDelivered(uuid)) => self.mark_invite_delivered(&entry.owner, uuid),
+                    None => Ok(()),
+                };
+                if let Err(error) = followed_up {
+                    println!("outbox: error following up on {}: {:#}", entry.delivery.id, error.0);
+                }
+            }
+            Attempt::Retry(error, retry_after) => {
+                let now = unix_time();
+                entry.delivery.last_error = Some(error.clone());
+                if now.saturating_sub(entry.delivery.created) > self.outbox_config.dead_letter_after_secs {
+                    entry.delivery.status = DeliveryStatus::Failed;
+                    entry.failed = now;
+                } else {
+                    let backoff = self.backoff(entry.delivery.attempts);
+                    entry.delivery.next_attempt = now + retry_after.map_or(backoff, |retry_after| retry_after.max(backoff));
+                }
+            }
+            Attempt::Rejected(error) => {
+                entry.delivery.last_error = Some(error.clone());
+                entry.delivery.status = DeliveryStatus::Failed;
+                entry.failed = unix_time();
+                entry.rejected = true;
+                let followed_up = match &entry.on_rejected {
+                    Some(OnRejected::FriendRequestRejected(uuid)) => self.friend_request_rejected(&entry.owner, uuid),
+                    Some(OnRejected::InviteRejected(uuid)) => self.invite_rejected(&entry.owner, uuid),
+                    None => Ok(()),
+                };
+                if let Err(error) = followed_up {
+                    println!("outbox: error following up on {}: {:#}", entry.delivery.id, error.0);
+                }
+            }
+        }
+        result
+    }
+    async fn post_entry(&self, entry: &mut OutboxEntry) -> Result<reqwest::Response> {
+        let (domain, path) = entry.target()?;
+        let discovery = self.discover(&domain).await?;
+        entry.delivery.url = discovery.url(&path);
+        self.federated_post(&discovery, &domain, &path, &entry.body).await
+    }
+    /// Exponential backoff with up to 50% random jitter, so retries to a recovering server spread out.
+    fn backoff(&self, attempts: u32) -> u64 {
+        let backoff = self.outbox_config.base_backoff_secs
+            .saturating_mul(1 << attempts.saturating_sub(1).min(32))
+            .min(MAX_BACKOFF_SECS);
+        backoff + rand::thread_rng().gen_range(0..=backoff / 2)
+    }
+}
+
+/// Background task retrying queued deliveries once they are due, and dropping old failed ones.
+/// Each domain's deliveries are sent in order by a task of their own, so a server that is slow to
+/// answer only holds up its own.
+pub async fn run(state: State) {
+    let busy = Arc::new(Mutex::new(HashSet::new()));
+    let mut last_pruned = 0;
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        if unix_time() >= last_pruned + PRUNE_FAILED_INTERVAL_SECS {
+            last_pruned = unix_time();
+            if let Err(error) = state.prune_failed_deliveries() {
+                println!("outbox: error pruning failed deliveries: {:#}", error.0);
+            }
+        }
+        let entries = match state.outbox_entries(Table::Outbox) {
+            Ok(entries) => entries,
+            Err(error) => {
+                println!("outbox: error reading outbox: {:#}", error.0);
+                continue;
+            }
+        };
+        let now = unix_time();
+        let mut due = HashMap::<String, Vec<OutboxEntry>>::new();
+        for mut entry in entries {
+            // Failed entries are only still here if they were stored before they got a table of
+            // their own.
+            if entry.delivery.status != DeliveryStatus::Pending {
+                entry.failed = now;
+                if let Err(error) = state.save_outbox_entry(&entry) {
+                    println!("outbox: error moving failed {}: {:#}", entry.delivery.id, error.0);
+                }
+                continue;
+            }
+            if entry.delivery.next_attempt > now {
+                continue;
+            }
+            match entry.target() {
+                Ok((domain, _)) => due.entry(domain).or_default().push(entry),
+                Err(error) => println!("outbox: error reading the target of {}: {:#}", entry.delivery.id, error.0),
+            }
+        }
+        for (domain, entries) in due {
+            if !busy.lock().unwrap().insert(domain.clone()) {
+                continue;
+            }
+            let (state, busy) = (state.clone(), busy.clone());
+            tokio::spawn(async move {
+                for mut entry in entries {
+                    if let Attempt::Delivered = state.attempt(&mut entry).await {
+                        continue;
+                    }
+                    if let Err(error) = state.save_outbox_entry(&entry) {
+                        println!("outbox: error saving {}: {:#}", entry.delivery.id, error.0);
+                    }
+                }
+                busy.lock().unwrap().remove(&domain);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::storage::{Backend, StorageConfig};
+    use super::*;
+
+    fn failed_entry(id: &str, rejected: bool, failed: u64) -> OutboxEntry {
+        OutboxEntry {
+            owner: String::from("malek"),
+            delivery: Delivery { id: id.to_string(), status: DeliveryStatus::Failed, ..Default::default() },
+            domain: String::from("localhost:9100"),
+            path: String::from("/"),
+            body: Value::Null,
+            on_delivered: None,
+            on_rejected: None,
+            failed,
+            rejected,
+        }
+    }
+
+    #[test]
+    fn dismisses_retries_and_drops_failed_deliveries() {
+        let state = State::new(0, &Config { storage: StorageConfig { backend: Backend::Memory, path: None }, ..Default::default() });
+        let now = unix_time();
+        for entry in [failed_entry("timed-out", false, now), failed_entry("rejected", true, now), failed_entry("old", false, 1)] {
+            state.save_outbox_entry(&entry).unwrap();
+        }
+        assert!(state.outbox_entries(Table::Outbox).unwrap().is_empty());
+        assert!(state.dismiss_delivery("lyuma", "timed-out").is_err());
+        assert!(state.retry_delivery("malek", "rejected").is_err());
+        state.dismiss_delivery("malek", "rejected").unwrap();
+        state.retry_delivery("malek", "timed-out").unwrap();
+        let pending = state.outbox_entries(Table::Outbox).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].delivery.status, DeliveryStatus::Pending);
+        state.prune_failed_deliveries().unwrap();
+        assert!(state.outbox_entries(Table::FailedDeliveries).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use nexus_common::api::Endpoint;
+use nexus_common::api::server_server::UpdatePresence;
+use nexus_common::{ApiError, FriendPresence, Presence, PresenceNotice, PresenceStatus, PresenceUpdate, Username};
+use crate::storage::Table;
+use crate::{unix_time, Result, State};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    /// How long a presence lasts without a heartbeat before the user counts as offline.
+    pub ttl_secs: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self { ttl_secs: 90 }
+    }
+}
+
+/// A presence in [`Table::Presence`]. For local users `fanned_out` is when their friends' servers
+/// were last sent it, so heartbeats only go out again once those copies are close to expiring.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+struct StoredPresence {
+    presence: Presence,
+    #[serde(default)]
+    fanned_out: u64,
+}
+
+impl State {
+    fn stored_presence(&self, user: &Username) -> Result<Option<StoredPresence>> {
+        match self.storage.get(Table::Presence, &user.to_string())? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+    fn save_presence(&self, user: &Username, stored: &StoredPresence) -> Result<()> {
+        self.storage.insert(Table::Presence, &user.to_string(), &serde_json::to_vec(stored)?)?;
+        Ok(())
+    }
+    /// A local user's own presence, including invisible, or offline once it expired.
+    pub fn presence(&self, username: &str) -> Result<Presence> {
+        let presence = self.stored_presence(&self.local_user(username))?.unwrap_or_default().presence;
+        Ok(match unix_time() > presence.expires {
+            true => Presence { updated: presence.updated, expires: presence.expires, ..Default::default() },
+            false => presence,
+        })
+    }
+    pub async fn set_presence(&self, username: &str, update: PresenceUpdate) -> Result<()> {
+        let now = unix_time();
+        let presence = Presence {
+            status: update.status,
+            activity: update.activity,
+            instance: update.instance,
+            updated: now,
+            expires: now + self.presence_config.ttl_secs,
+        };
+        self.publish_presence(username, presence).await
+    }
+    /// Pushes back the expiry of a local user's presence.
+    pub async fn heartbeat(&self, username: &str) -> Result<()> {
+        let now = unix_time();
+        let user = self.local_user(username);
+        let stored = self.stored_presence(&user)?
+            .filter(|stored| stored.presence.status != PresenceStatus::Offline && now <= stored.presence.expires);
+        let Some(mut stored) = stored else {
+            return Err(ApiError::NotFound("No presence to keep alive, set one first".to_string()).into());
+        };
+        stored.presence.expires = now + self.presence_config.ttl_secs;
+        if now.saturating_sub(stored.fanned_out) < self.presence_config.ttl_secs / 2 {
+            return self.save_presence(&user, &stored);
+        }
+        self.publish_presence(username, stored.presence).await
+    }
+    async fn publish_presence(&self, username: &str, presence: Presence) -> Result<()> {
+        let user = self.local_user(username);
+        let now = unix_time();
+        self.save_presence(&user, &StoredPresence { presence: presence.clone(), fanned_out: now })?;
+        let notice = PresenceNotice { from: user, presence: presence.visible_at(now) };
+        // One friend per server is enough, the receiving server shares it between all of them.
+        let mut domains = HashSet::new();
+        for friend in self.user(username)?.friends {
+            if friend.website == self.domain || !domains.insert(friend.website.clone()) {
+                continue;
+            }
+            let (state, notice) = (self.clone(), notice.clone());
+            // Best effort rather than through the outbox: a retried presence would only be stale,
+            // and the next heartbeat sends a fresh one anyway.
+            tokio::spawn(async move {
+                let path = UpdatePresence::path(&[&friend.username]);
+                let result = match state.discover(&friend.website).await {
+                    Ok(discovery) => state.federated_post(&discovery, &friend.website, &path, &notice).await.map(|_| ()),
+                    Err(error) => Err(error),
+                };
+                if let Err(error) = result {
+                    println!("presence: error notifying {}: {:#}", friend.website, error.0);
+                }
+            });
+        }
+        Ok(())
+    }
+    /// Stores a presence a friend's server sent, unless we already have a newer one.
+    pub fn receive_presence(&self, notice: PresenceNotice) -> Result<()> {
+        if let Some(stored) = self.stored_presence(&notice.from)? {
+            if stored.presence.updated > notice.presence.updated {
+                return Ok(());
+            }
+        }
+        self.save_presence(&notice.from, &StoredPresence { presence: notice.presence, fanned_out: 0 })
+    }
+    pub fn friends_presence(&self, username: &str) -> Result<Vec<FriendPresence>> {
+        let now = unix_time();
+        self.user(username)?
+            .friends
+            .into_iter()
+            .map(|friend| {
+                let presence = self.stored_presence(&friend)?.unwrap_or_default().presence.visible_at(now);
+                Ok(FriendPresence { user: friend, presence })
+            })
+            .collect()
+    }
+}
+
+/// Background task telling friends when a local user's presence expires, and dropping copies of
+/// remote presences long after they expired.
+pub async fn run(state: State) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let entries = match state.storage.scan(Table::Presence) {
+            Ok(entries) => entries,
+            Err(error) => {
+                println!("presence: error reading presences: {error:#}");
+                continue;
+            }
+        };
+        let now = unix_time();
+        for (key, bytes) in entries {
+            let (Ok(user), Ok(stored)) = (key.parse::<Username>(), serde_json::from_slice::<StoredPresence>(&bytes)) else {
+                continue;
+            };
+            if now <= stored.presence.expires {
+                continue;
+            }
+            if user.website != state.domain {
+                if now > stored.presence.expires + state.presence_config.ttl_secs {
+                    let _ = state.storage.remove(Table::Presence, &key);
+                }
+            } else if stored.presence.status != PresenceStatus::Offline {
+                let offline = Presence { updated: now, expires: now, ..Default::default() };
+                if let Err(error) = state.publish_presence(&user.username, offline).await {
+                    println!("presence: error expiring {user}: {:#}", error.0);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use nexus_common::api::Endpoint;
+use nexus_common::api::server_server::CheckFriend;
+use nexus_common::{ApiError, EventKind, FriendCheck, FriendRequestPolicy, FriendRequestUuid, InvitePolicy, PrivacySettings, Username};
+use crate::{events, unix_time, Result, State};
+
+/// The most remote friends asked about one friend request, so a user with many friends can't be
+/// made to send a request to each of their servers.
+const MAX_REMOTE_FRIEND_CHECKS: usize = 10;
+/// How long a remote server saying two users aren't friends is believed without asking again.
+/// Only those answers are kept, so an unfriending always counts straight away.
+const FRIEND_CHECK_TTL_SECS: u64 = 5 * 60;
+/// Answers are only swept once there are this many.
+const SWEEP_AFTER_CHECKS: usize = 10_000;
+
+/// When a remote friend's server said they aren't friends with a user.
+pub type FriendChecks = HashMap<(Username, Username), u64>;
+
+/// A domain as it appears in [`Username::website`], so lists of them can be compared with users.
+pub fn normalize_domain(domain: &str) -> Result<String> {
+    Ok(format!("nobody@{domain}").parse::<Username>().map_err(|e| ApiError::BadRequest(format!("Domain {domain}: {e}")))?.website)
+}
+
+impl State {
+    /// Replaces a local user's privacy settings, normalizing the allowed domains.
+    pub fn set_privacy(&self, username: &str, mut privacy: PrivacySettings) -> Result<()> {
+        privacy.allowed_domains = privacy.allowed_domains.iter().map(|domain| normalize_domain(domain)).collect::<Result<_>>()?;
+        self.user_mut(username, |user| user.privacy = privacy.clone())
+    }
+    /// Refuses a friend request for a local user that their privacy settings don't allow.
+    pub async fn check_friend_request_allowed(&self, username: &str, from: &Username) -> Result<()> {
+        let user = self.user(username)?;
+        let privacy = &user.privacy;
+        let allowed = match privacy.friend_requests {
+            FriendRequestPolicy::Anyone => true,
+            FriendRequestPolicy::FriendsOfFriends => user.friends.contains(from) || self.has_mutual_friend(username, &user.friends, from).await,
+            FriendRequestPolicy::AllowedDomains => privacy.allowed_domains.contains(&from.website),
+            FriendRequestPolicy::Nobody => false,
+        };
+        let only = match privacy.friend_requests {
+            FriendRequestPolicy::Anyone => "from anyone",
+            FriendRequestPolicy::FriendsOfFriends => "from friends of friends",
+            FriendRequestPolicy::AllowedDomains => "from some servers",
+            FriendRequestPolicy::Nobody => "from nobody",
+        };
+        allowed.then_some(()).ok_or_else(|| ApiError::Forbidden(format!("{} only accepts friend requests {only}", self.local_user(username))))?;
+        Ok(())
+    }
+    /// Refuses an invite for a local user that their privacy settings don't allow.
+    pub fn check_invite_allowed(&self, username: &str, from: &Username) -> Result<()> {
+        let user = self.user(username)?;
+        let allowed = match user.privacy.invites {
+            InvitePolicy::Friends => user.friends.contains(from),
+            InvitePolicy::Anyone => true,
+        };
+        allowed.then_some(()).ok_or_else(|| ApiError::Forbidden(format!("{} only accepts invites from friends", self.local_user(username))))?;
+        Ok(())
+    }
+    /// Whether any of `friends` is also friends with `user`. Local friends are looked up, and of
+    /// the remote ones not recently found not to be, up to [`MAX_REMOTE_FRIEND_CHECKS`] are asked
+    /// through their servers, those on `user`'s server first. Friends whose server can't be asked
+    /// count as not shared.
+    async fn has_mutual_friend(&self, username: &str, friends: &[Username], user: &Username) -> bool {
+        let (local, remote): (Vec<_>, Vec<_>) = friends.iter().partition(|friend| friend.website == self.domain);
+        if local.iter().any(|friend| self.user(&friend.username).is_ok_and(|friend| friend.friends.contains(user))) {
+            return true;
+        }
+        let now = unix_time();
+        let mut unchecked = {
+            let cache = self.friend_checks.lock().unwrap();
+            let not_shared = |friend: &Username| cache.get(&(friend.clone(), user.clone())).is_some_and(|checked| now < checked + FRIEND_CHECK_TTL_SECS);
+            remote.into_iter().filter(|friend| !not_shared(friend)).collect::<Vec<_>>()
+        };
+        unchecked.sort_by_key(|friend| friend.website != user.website);
+        unchecked.truncate(MAX_REMOTE_FRIEND_CHECKS);
+        let check = FriendCheck { from: self.local_user(username), user: user.clone() };
+        let checks = unchecked.into_iter().map(|friend| {
+            let check = check.clone();
+            async move {
+                let path = CheckFriend::path(&[&friend.username]);
+                let response = match self.discover(&friend.website).await {
+                    Ok(discovery) => self.federated_post(&discovery, &friend.website, &path, &check).await,
+                    Err(error) => Err(error),
+                };
+                let shared = match response {
+                    Ok(response) if response.status().is_success() => response.json::<bool>().await.ok(),
+                    _ => None,
+                };
+                (friend, shared)
+            }
+        });
+        let answers = futures::future::join_all(checks).await;
+        let mut cache = self.friend_checks.lock().unwrap();
+        if cache.len() >= SWEEP_AFTER_CHECKS {
+            cache.retain(|_, checked| now < *checked + FRIEND_CHECK_TTL_SECS);
+        }
+        for (friend, shared) in &answers {
+            if *shared == Some(false) {
+                cache.insert(((*friend).clone(), user.clone()), now);
+            }
+        }
+        answers.iter().any(|(_, shared)| *shared == Some(true))
+    }
+    /// Answers a [`FriendCheck`] about a local user, only for their friends.
+    pub fn check_friend(&self, username: &str, check: &FriendCheck) -> Result<bool> {
+        let friends = self.user(username)?.friends;
+        friends.contains(&check.from).then_some(()).ok_or_else(|| ApiError::Forbidden("Only friends can ask who a user's friends are".to_string()))?;
+        Ok(friends.contains(&check.user))
+    }
+    /// Drops a sent friend request the recipient's server refused.
+    pub fn friend_request_rejected(&self, username: &str, uuid: &FriendRequestUuid) -> Result<()> {
+        self.user_mut(username, |user| {
+            if !user.sent_friend_requests.remove(uuid) {
+                return;
+            }
+            if let Some(friend_request) = user.friend_requests.remove(uuid) {
+                events::record(user, EventKind::FriendRequestRejected(friend_request));
+            }
+        })?;
+        self.notify(username);
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use nexus_common::api::Endpoint;
+use nexus_common::api::server_server::{GetProfile, UpdateProfile};
+use nexus_common::{ApiError, EventKind, Profile, ProfileNotice, ProfileQuery, ProfileSettings, Username, MAX_BIO_LEN, MAX_DISPLAY_NAME_LEN, MAX_PRONOUNS_LEN};
+use crate::storage::Table;
+use crate::{blobs, events, no_such_user, unix_time, Result, State};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileConfig {
+    /// How long a friend's cached profile is used before it is fetched again, in case an update
+    /// from their server got lost.
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        Self { cache_ttl_secs: 60 * 60 }
+    }
+}
+
+/// A remote user's profile in [`Table::Profiles`], as their friends may see it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CachedProfile {
+    profile: Profile,
+    fetched: u64,
+}
+
+fn check_profile(profile: &Profile) -> Result<()> {
+    let check_text = |text: &Option<String>, field: &str, max: usize| {
+        let text = text.as_deref().unwrap_or_default();
+        (text.len() <= max).then_some(()).ok_or_else(|| ApiError::BadRequest(format!("Profile {field} is limited to {max} bytes")))?;
+        (!text.chars().any(|c| c.is_control() && c != '\n')).then_some(()).ok_or_else(|| ApiError::BadRequest(format!("Profile {field} contains control characters")))
+    };
+    check_text(&profile.display_name, "display name", MAX_DISPLAY_NAME_LEN)?;
+    check_text(&profile.bio, "bio", MAX_BIO_LEN)?;
+    check_text(&profile.pronouns, "pronouns", MAX_PRONOUNS_LEN)?;
+    let single_line = |text: &Option<String>| !text.as_deref().unwrap_or_default().contains('\n');
+    (single_line(&profile.display_name) && single_line(&profile.pronouns)).then_some(()).ok_or_else(|| ApiError::BadRequest("Display names and pronouns must be a single line".to_string()))?;
+    if let Some(image) = &profile.image {
+        let link = reqwest::Url::parse(&image.link.0).map_err(|e| ApiError::BadRequest(format!("Profile image link is not a url: {e}")))?;
+        matches!(link.scheme(), "http" | "https").then_some(()).ok_or_else(|| ApiError::BadRequest("Profile image links must be http or https".to_string()))?;
+        image.hash.as_deref().is_none_or(blobs::is_sha256).then_some(()).ok_or_else(|| ApiError::BadRequest("Profile image hash must be a lowercase hex SHA-256".to_string()))?;
+    }
+    Ok(())
+}
+
+impl State {
+    /// A local user's whole profile, with `profile.avatar` filled in from their avatars.
+    pub fn profile_settings(&self, username: &str) -> Result<ProfileSettings> {
+        let user = self.user(username)?;
+        let mut settings = user.profile;
+        settings.profile.avatar = user.avatars.default;
+        Ok(settings)
+    }
+    /// Replaces a local user's profile and tells their friends about it.
+    pub async fn set_profile(&self, username: &str, settings: ProfileSettings) -> Result<()> {
+        check_profile(&settings.profile)?;
+        let now = unix_time();
+        let friends = self.try_user_mut(username, |user| {
+            if let Some(avatar) = &settings.profile.avatar {
+                user.avatars.avatars.iter().any(|existing| existing.uuid == *avatar).then_some(()).ok_or_else(|| ApiError::NotFound("AvatarUuid not found".to_string()))?;
+                user.avatars.default = Some(avatar.clone());
+            }
+            user.profile = ProfileSettings {
+                profile: Profile { avatar: None, updated: now, ..settings.profile.clone() },
+                visibility: settings.visibility.clone(),
+            };
+            Ok(user.friends.clone())
+        })?;
+        let from = self.local_user(username);
+        let profile = self.profile_settings(username)?.shown_to(true);
+        // The profile is saved by now, so one friend that can't be told mustn't stop the rest.
+        let mut remote = BTreeMap::<String, Vec<String>>::new();
+        for friend in friends {
+            if friend.website != self.domain {
+                remote.entry(friend.website).or_default().push(friend.username);
+                continue;
+            }
+            match self.user_mut(&friend.username, |user| events::record(user, EventKind::ProfileUpdated(from.clone()))) {
+                Ok(()) => self.notify(&friend.username),
+                Err(error) => println!("profiles: error notifying {friend}: {:#}", error.0),
+            }
+        }
+        // One notice per server, left to the outbox so a slow server doesn't hold up the save.
+        for (domain, mut usernames) in remote {
+            let addressee = usernames.remove(0);
+            let notice = ProfileNotice { from: from.clone(), profile: profile.clone(), also: usernames };
+            if let Err(error) = self.enqueue(username, &domain, UpdateProfile::path(&[&addressee]), &notice) {
+                println!("profiles: error queueing the update for {domain}: {:#}", error.0);
+            }
+        }
+        Ok(())
+    }
+    /// A local user's profile with only the fields `viewer` may see. Users the owner blocked are
+    /// told there is no such user.
+    pub fn local_profile(&self, username: &str, viewer: &Username) -> Result<Profile> {
+        let user = self.user(username)?;
+        (!user.blocks.blocks(viewer)).then_some(()).ok_or_else(|| no_such_user(&self.local_user(username).to_string()))?;
+        let friend = user.friends.contains(viewer);
+        let settings = self.profile_settings(username)?;
+        match *viewer == self.local_user(username) {
+            true => Ok(settings.profile),
+            false => Ok(settings.shown_to(friend)),
+        }
+    }
+    fn cached_profile(&self, user: &Username) -> Result<Option<CachedProfile>> {
+        match self.storage.get(Table::Profiles, &user.to_string())? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+    fn cache_profile(&self, user: &Username, profile: Profile) -> Result<()> {
+        self.storage.insert(Table::Profiles, &user.to_string(), &serde_json::to_vec(&CachedProfile { profile, fetched: unix_time() })?)?;
+        Ok(())
+    }
+    /// Looks up `user`'s profile for a local user. Friends' profiles come from the cache while it
+    /// is fresh, anyone else's are always fetched.
+    pub async fn user_profile(&self, username: &str, user: Username) -> Result<Profile> {
+        let viewer = self.local_user(username);
+        if user.website == self.domain {
+            return self.local_profile(&user.username, &viewer);
+        }
+        // The cache holds what friends may see, so nobody else can be answered from it.
+        let friend = self.user(username)?.friends.contains(&user);
+        if friend {
+            if let Some(cached) = self.cached_profile(&user)? {
+                if unix_time() <= cached.fetched + self.profile_config.cache_ttl_secs {
+                    return Ok(cached.profile);
+                }
+            }
+        }
+        let path = GetProfile::path(&[&user.username]);
+        let response = self.federated_post(&self.discover(&user.website).await?, &user.website, &path, &ProfileQuery { from: viewer }).await?;
+        let profile: Profile = match response.status() {
+            status if status.is_success() => response.json().await?,
+            StatusCode::NOT_FOUND => return Err(no_such_user(&user.to_string()).into()),
+            status => return Err(ApiError::RemoteRejected(format!("{} answered {status}", user.website)).into()),
+        };
+        if friend {
+            self.cache_profile(&user, profile.clone())?;
+        }
+        Ok(profile)
+    }
+    /// Caches a profile a friend's server pushed, unless we already have a newer one, and tells
+    /// the local user it was sent to.
+    pub fn receive_profile(&self, username: &str, notice: ProfileNotice) -> Result<()> {
+        if self.cached_profile(&notice.from)?.is_none_or(|cached| cached.profile.updated <= notice.profile.updated) {
+            self.cache_profile(&notice.from, notice.profile)?;
+        }
+        self.user_mut(username, |user| events::record(user, EventKind::ProfileUpdated(notice.from.clone())))?;
+        self.notify(username);
+        // Others named in the notice are only told if they really are the sender's friends.
+        for other in &notice.also {
+            let told = self.try_user_mut(other, |user| {
+                let friends = user.friends.contains(&notice.from) && !user.blocks.blocks(&notice.from);
+                if friends {
+                    events::record(user, EventKind::ProfileUpdated(notice.from.clone()));
+                }
+                Ok(friends)
+            });
+            match told {
+                Ok(true) => self.notify(other),
+                Ok(false) => {}
+                Err(error) => println!("profiles: error notifying {other}: {:#}", error.0),
+            }
+        }
+        Ok(())
+    }
+}
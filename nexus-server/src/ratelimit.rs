@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use serde::{Deserialize, Serialize};
+use nexus_common::non_api_structs::UserData;
+use nexus_common::{ApiError, Username};
+use crate::{unix_time, AppError, Result, State};
+
+/// Limits count requests in fixed windows of this length.
+const WINDOW_SECS: u64 = 60;
+/// How long a server refused for a full friend request queue is asked to wait. Queues only empty
+/// when the user answers, so there is no point in it coming back sooner.
+const QUEUE_FULL_RETRY_SECS: u64 = 60 * 60;
+/// Windows are only swept once there are this many, so a quiet server never bothers.
+const SWEEP_AFTER_WINDOWS: usize = 10_000;
+
+/// Limits are per minute, and 0 turns one off.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Federation requests from one remote server, to any endpoint.
+    pub per_server_per_minute: u32,
+    /// Federation requests from one IP address, counted before their signature is checked.
+    /// Behind a reverse proxy every server shares its address.
+    pub per_ip_per_minute: u32,
+    /// Friend requests and invites one local user receives, from anywhere.
+    pub per_target_per_minute: u32,
+    /// Requests to private endpoints made with one session token.
+    pub per_session_per_minute: u32,
+    /// Login attempts for one user, to slow down password guessing.
+    pub logins_per_user_per_minute: u32,
+    /// Registrations from one IP address. Behind a reverse proxy everyone shares its address.
+    pub registrations_per_ip_per_minute: u32,
+    /// Unanswered friend requests a user can have before further ones are refused.
+    pub max_pending_friend_requests: usize,
+    /// The same, counting only those from one domain.
+    pub max_pending_per_domain: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_server_per_minute: 600,
+            per_ip_per_minute: 1200,
+            per_target_per_minute: 30,
+            per_session_per_minute: 300,
+            logins_per_user_per_minute: 10,
+            registrations_per_ip_per_minute: 10,
+            max_pending_friend_requests: 100,
+            max_pending_per_domain: 20,
+        }
+    }
+}
+
+/// Attached to a [`ApiError::TooManyRequests`] so the response gets a `Retry-After` header.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryAfter(pub u64);
+
+impl Display for RetryAfter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "retry after {} seconds", self.0)
+    }
+}
+
+fn too_many_requests(message: String, retry_after: u64) -> AppError {
+    AppError(anyhow::Error::new(ApiError::TooManyRequests(format!("{message}, try again in {retry_after} seconds"))).context(RetryAfter(retry_after)))
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    start: u64,
+    count: u32,
+}
+
+/// Request counts of the current window, kept in memory only: a restart forgiving everyone is
+/// harmless.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    windows: HashMap<String, Window>,
+}
+
+impl RateLimiter {
+    /// Counts a request against `key`, returning how many seconds are left in the window if it
+    /// was already over `limit`.
+    fn hit(&mut self, key: &str, limit: u32, now: u64) -> Option<u64> {
+        if limit == 0 {
+            return None;
+        }
+        if self.windows.len() >= SWEEP_AFTER_WINDOWS {
+            self.windows.retain(|_, window| now < window.start + WINDOW_SECS);
+        }
+        let window = self.windows.entry(key.to_string()).or_insert(Window { start: now, count: 0 });
+        if now >= window.start + WINDOW_SECS {
+            *window = Window { start: now, count: 0 };
+        }
+        if window.count >= limit {
+            return Some(window.start + WINDOW_SECS - now);
+        }
+        window.count += 1;
+        None
+    }
+}
+
+impl State {
+    fn check_rate(&self, key: &str, limit: u32, what: &str) -> Result<()> {
+        match self.rate_limiter.lock().unwrap().hit(key, limit, unix_time()) {
+            Some(retry_after) => Err(too_many_requests(format!("Too many {what}"), retry_after)),
+            None => Ok(()),
+        }
+    }
+    /// Limits federation requests from the address they came from, before the signature is checked,
+    /// so a flood of them can't make us verify or fetch keys. The origin they claim can't be used
+    /// for that, anyone can claim any origin.
+    pub fn check_peer_rate(&self, ip: IpAddr) -> Result<()> {
+        self.check_rate(&format!("peer\n{ip}"), self.rate_limit_config.per_ip_per_minute, &format!("requests from {ip}"))
+    }
+    /// Limits federation requests from the server that signed them.
+    pub fn check_server_rate(&self, origin: &str) -> Result<()> {
+        self.check_rate(&format!("server\n{origin}"), self.rate_limit_config.per_server_per_minute, &format!("requests from {origin}"))
+    }
+    /// Limits friend requests and invites to a local user.
+    pub fn check_target_rate(&self, username: &str) -> Result<()> {
+        self.check_rate(&format!("target\n{username}"), self.rate_limit_config.per_target_per_minute, &format!("requests to {}", self.local_user(username)))
+    }
+    pub fn check_login_rate(&self, username: &str) -> Result<()> {
+        self.check_rate(&format!("login\n{username}"), self.rate_limit_config.logins_per_user_per_minute, "login attempts")
+    }
+    pub fn check_registration_rate(&self, ip: IpAddr) -> Result<()> {
+        self.check_rate(&format!("register\n{ip}"), self.rate_limit_config.registrations_per_ip_per_minute, "registrations")
+    }
+    /// Limits requests made with one session token.
+    pub fn check_session_rate(&self, token: &str) -> Result<()> {
+        self.check_rate(&format!("session\n{token}"), self.rate_limit_config.per_session_per_minute, "requests from this session")
+    }
+    /// Refuses another friend request from `from` once the user has too many waiting, in total or
+    /// from `from`'s domain.
+    pub fn check_pending_friend_requests(&self, user: &UserData, from: &Username) -> Result<()> {
+        let pending = user.rec_friend_requests.iter().filter_map(|uuid| user.friend_requests.get(uuid)).map(|friend_request| &friend_request.from);
+        let (total, same_domain) = pending.fold((0, 0), |(total, same_domain), sender| (total + 1, same_domain + (sender.website == from.website) as usize));
+        let config = &self.rate_limit_config;
+        if config.max_pending_friend_requests != 0 && total >= config.max_pending_friend_requests {
+            return Err(too_many_requests(String::from("Too many unanswered friend requests"), QUEUE_FULL_RETRY_SECS));
+        }
+        if config.max_pending_per_domain != 0 && same_domain >= config.max_pending_per_domain {
+            return Err(too_many_requests(format!("Too many unanswered friend requests from {}", from.website), QUEUE_FULL_RETRY_SECS));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_requests_per_window() {
+        let mut limiter = RateLimiter::default();
+        assert_eq!(limiter.hit("a", 2, 100), None);
+        assert_eq!(limiter.hit("a", 2, 110), None);
+        assert_eq!(limiter.hit("a", 2, 120), Some(40));
+        assert_eq!(limiter.hit("b", 2, 120), None);
+        assert_eq!(limiter.hit("a", 2, 160), None);
+        assert_eq!(limiter.hit("a", 0, 160), None);
+    }
+}
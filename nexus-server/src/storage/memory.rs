@@ -0,0 +1,35 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use super::{Storage, Table};
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: Mutex<HashMap<Table, BTreeMap<String, Vec<u8>>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, table: Table, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.tables.lock().unwrap().get(&table).and_then(|t| t.get(key)).cloned())
+    }
+    fn insert(&self, table: Table, key: &str, value: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.tables.lock().unwrap().entry(table).or_default().insert(key.to_string(), value.to_vec()))
+    }
+    fn remove(&self, table: Table, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.tables.lock().unwrap().entry(table).or_default().remove(key))
+    }
+    fn compare_and_swap(&self, table: Table, key: &str, old: Option<&[u8]>, new: Option<&[u8]>) -> anyhow::Result<bool> {
+        let mut tables = self.tables.lock().unwrap();
+        let table = tables.entry(table).or_default();
+        if table.get(key).map(Vec::as_slice) != old {
+            return Ok(false);
+        }
+        match new {
+            Some(new) => table.insert(key.to_string(), new.to_vec()),
+            None => table.remove(key),
+        };
+        Ok(true)
+    }
+    fn scan(&self, table: Table) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        Ok(self.tables.lock().unwrap().get(&table).map(|t| t.clone().into_iter().collect()).unwrap_or_default())
+    }
+}
@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+
+mod memory;
+mod sled_storage;
+mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use sled_storage::SledStorage;
+pub use sqlite::SqliteStorage;
+
+/// The separate key spaces a server keeps records in. Values are opaque bytes to the backend;
+/// `State` decides how each table's records are encoded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Table {
+    /// `UserData` per local user, including their friend requests and invites.
+    Users,
+    Credentials,
+    Sessions,
+    /// Federation nonces already seen, so signed requests can't be replayed.
+    Nonces,
+    RegistrationInvites,
+    /// Federation messages waiting to be delivered to other servers.
+    Outbox,
+    /// Federation messages given up on, kept apart so the outbox worker doesn't rescan them.
+    FailedDeliveries,
+    /// Server wide records such as the signing key.
+    Server,
+    /// The presence of local users and the copies friends' servers sent us, keyed by `user@domain`.
+    Presence,
+    /// Game instances registered with this server, keyed by world id and instance id.
+    Instances,
+    /// Avatar files uploaded by local users, keyed by their SHA-256.
+    AvatarBlobs,
+    /// Profiles of remote users as their friends see them, keyed by `user@domain`.
+    Profiles,
+}
+
+impl Table {
+    pub const ALL: [Table; 12] = [Table::Users, Table::Credentials, Table::Sessions, Table::Nonces, Table::RegistrationInvites, Table::Outbox, Table::FailedDeliveries, Table::Server, Table::Presence, Table::Instances, Table::AvatarBlobs, Table::Profiles];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Table::Users => "users",
+            Table::Credentials => "credentials",
+            Table::Sessions => "sessions",
+            Table::Nonces => "nonces",
+            Table::RegistrationInvites => "registration_invites",
+            Table::Outbox => "outbox",
+            Table::FailedDeliveries => "failed_deliveries",
+            Table::Server => "server",
+            Table::Presence => "presence",
+            Table::Instances => "instances",
+            Table::AvatarBlobs => "avatar_blobs",
+            Table::Profiles => "profiles",
+        }
+    }
+}
+
+/// A key value store backing a nexus server. Implementations must be safe to share between
+/// request handlers; [`Storage::compare_and_swap`] is the only primitive that has to be atomic.
+pub trait Storage: Send + Sync {
+    fn get(&self, table: Table, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    /// Stores `value` at `key`, returning the previous value.
+    fn insert(&self, table: Table, key: &str, value: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
+    /// Removes `key`, returning the previous value.
+    fn remove(&self, table: Table, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    /// Sets `key` to `new` only if it currently holds `old`, where `None` means absent.
+    /// Returns whether the swap happened.
+    fn compare_and_swap(&self, table: Table, key: &str, old: Option<&[u8]>, new: Option<&[u8]>) -> anyhow::Result<bool>;
+    /// Every entry in `table`, in key order.
+    fn scan(&self, table: Table) -> anyhow::Result<Vec<(String, Vec<u8>)>>;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: Backend,
+    /// Where the database lives. Defaults to `sled<port>` or `nexus<port>.sqlite`.
+    pub path: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    #[default]
+    Sled,
+    /// Nothing is persisted, every restart starts empty. Meant for tests.
+    Memory,
+    Sqlite,
+}
+
+pub fn open(config: &StorageConfig, port: u16) -> anyhow::Result<Box<dyn Storage>> {
+    Ok(match config.backend {
+        Backend::Sled => Box::new(SledStorage::open(config.path.clone().unwrap_or_else(|| format!("sled{port}")))?),
+        Backend::Memory => Box::new(MemoryStorage::default()),
+        Backend::Sqlite => Box::new(SqliteStorage::open(config.path.clone().unwrap_or_else(|| format!("nexus{port}.sqlite")))?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(storage: &dyn Storage) {
+        assert_eq!(storage.get(Table::Users, "malek").unwrap(), None);
+        assert_eq!(storage.insert(Table::Users, "malek", b"1").unwrap(), None);
+        assert_eq!(storage.insert(Table::Users, "malek", b"2").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(storage.get(Table::Sessions, "malek").unwrap(), None, "tables must not share keys");
+        assert!(!storage.compare_and_swap(Table::Users, "malek", None, Some(b"3")).unwrap());
+        assert!(!storage.compare_and_swap(Table::Users, "malek", Some(b"1"), Some(b"3")).unwrap());
+        assert!(storage.compare_and_swap(Table::Users, "malek", Some(b"2"), Some(b"3")).unwrap());
+        assert!(storage.compare_and_swap(Table::Users, "lyuma", None, Some(b"4")).unwrap());
+        assert_eq!(storage.scan(Table::Users).unwrap(), vec![(String::from("lyuma"), b"4".to_vec()), (String::from("malek"), b"3".to_vec())]);
+        assert!(storage.compare_and_swap(Table::Users, "lyuma", Some(b"4"), None).unwrap());
+        assert_eq!(storage.remove(Table::Users, "malek").unwrap(), Some(b"3".to_vec()));
+        assert!(storage.scan(Table::Users).unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory() {
+        exercise(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn sled() {
+        exercise(&SledStorage::from_db(sled::Config::new().temporary(true).open().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn sqlite() {
+        exercise(&SqliteStorage::open(":memory:").unwrap());
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use sled::{Db, Tree};
+use super::{Storage, Table};
+
+pub struct SledStorage {
+    trees: HashMap<Table, Tree>,
+}
+
+impl SledStorage {
+    pub fn open(path: impl AsRef<std::path::Path>) -> anyhow::Result<Self> {
+        Self::from_db(sled::open(path)?)
+    }
+    pub fn from_db(db: Db) -> anyhow::Result<Self> {
+        let mut trees = HashMap::new();
+        for table in Table::ALL {
+            // Users predate the other tables and live in sled's default tree.
+            let tree = match table {
+                Table::Users => (*db).clone(),
+                _ => db.open_tree(table.name())?,
+            };
+            trees.insert(table, tree);
+        }
+        Ok(Self { trees })
+    }
+    fn tree(&self, table: Table) -> &Tree {
+        &self.trees[&table]
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, table: Table, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.tree(table).get(key)?.map(|v| v.to_vec()))
+    }
+    fn insert(&self, table: Table, key: &str, value: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.tree(table).insert(key, value)?.map(|v| v.to_vec()))
+    }
+    fn remove(&self, table: Table, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.tree(table).remove(key)?.map(|v| v.to_vec()))
+    }
+    fn compare_and_swap(&self, table: Table, key: &str, old: Option<&[u8]>, new: Option<&[u8]>) -> anyhow::Result<bool> {
+        Ok(self.tree(table).compare_and_swap(key, old, new)?.is_ok())
+    }
+    fn scan(&self, table: Table) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        self.tree(table)
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((String::from_utf8(key.to_vec())?, value.to_vec()))
+            })
+            .collect()
+    }
+}
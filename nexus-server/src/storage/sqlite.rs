@@ -0,0 +1,66 @@
+use std::sync::Mutex;
+use rusqlite::{params, Connection, OptionalExtension};
+use super::{Storage, Table};
+
+/// Keeps each [`Table`] as an SQL table of `(key TEXT PRIMARY KEY, value BLOB)`, so operators can
+/// inspect and back up a server with the usual SQLite tools.
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<std::path::Path>) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        for table in Table::ALL {
+            connection.execute(&format!("CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value BLOB NOT NULL)", table.name()), [])?;
+        }
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+}
+
+fn get(connection: &Connection, table: Table, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    Ok(connection
+        .query_row(&format!("SELECT value FROM {} WHERE key = ?1", table.name()), params![key], |row| row.get(0))
+        .optional()?)
+}
+
+impl Storage for SqliteStorage {
+    fn get(&self, table: Table, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        get(&self.connection.lock().unwrap(), table, key)
+    }
+    fn insert(&self, table: Table, key: &str, value: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let previous = get(&transaction, table, key)?;
+        transaction.execute(&format!("INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)", table.name()), params![key, value])?;
+        transaction.commit()?;
+        Ok(previous)
+    }
+    fn remove(&self, table: Table, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let previous = get(&transaction, table, key)?;
+        transaction.execute(&format!("DELETE FROM {} WHERE key = ?1", table.name()), params![key])?;
+        transaction.commit()?;
+        Ok(previous)
+    }
+    fn compare_and_swap(&self, table: Table, key: &str, old: Option<&[u8]>, new: Option<&[u8]>) -> anyhow::Result<bool> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        if get(&transaction, table, key)?.as_deref() != old {
+            return Ok(false);
+        }
+        match new {
+            Some(new) => transaction.execute(&format!("INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)", table.name()), params![key, new])?,
+            None => transaction.execute(&format!("DELETE FROM {} WHERE key = ?1", table.name()), params![key])?,
+        };
+        transaction.commit()?;
+        Ok(true)
+    }
+    fn scan(&self, table: Table) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!("SELECT key, value FROM {} ORDER BY key", table.name()))?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
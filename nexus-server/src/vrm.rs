@@ -0,0 +1,135 @@
+//! Reading VRM 1.0 models, which are glTF binaries whose JSON carries a `VRMC_vrm` extension.
+
+use serde_json::Value;
+use nexus_common::{ApiError, VrmMeta};
+use crate::Result;
+
+const GLB_MAGIC: &[u8] = b"glTF";
+const CHUNK_JSON: u32 = 0x4E4F534A;
+const CHUNK_BIN: u32 = 0x004E4942;
+/// Bones every VRM 1.0 humanoid must map to a node.
+const REQUIRED_BONES: &[&str] = &[
+    "hips", "spine", "head",
+    "leftUpperArm", "leftLowerArm", "leftHand", "rightUpperArm", "rightLowerArm", "rightHand",
+    "leftUpperLeg", "leftLowerLeg", "leftFoot", "rightUpperLeg", "rightLowerLeg", "rightFoot",
+];
+
+/// Checks that `bytes` is a VRM 1.0 model and returns its metadata.
+pub fn read_vrm(bytes: &[u8]) -> Result<VrmMeta> {
+    parse(bytes).map_err(|reason| ApiError::BadRequest(format!("Not a valid VRM 1.0 model: {reason}")).into())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> std::result::Result<u32, String> {
+    let word = bytes.get(offset..offset + 4).ok_or("file is truncated")?;
+    Ok(u32::from_le_bytes(word.try_into().unwrap()))
+}
+
+/// The JSON chunk of a glTF binary, after checking the container's framing.
+fn glb_json(bytes: &[u8]) -> std::result::Result<Value, String> {
+    if bytes.get(..4) != Some(GLB_MAGIC) {
+        return Err("not a glTF binary".to_string());
+    }
+    if read_u32(bytes, 4)? != 2 {
+        return Err("only glTF 2.0 is supported".to_string());
+    }
+    if read_u32(bytes, 8)? as usize != bytes.len() {
+        return Err("length in the header does not match the file".to_string());
+    }
+    let mut chunks = vec![];
+    let mut offset = 12;
+    while offset < bytes.len() {
+        let length = read_u32(bytes, offset)? as usize;
+        let kind = read_u32(bytes, offset + 4)?;
+        let data = bytes.get(offset + 8..offset + 8 + length).ok_or("a chunk runs past the end of the file")?;
+        chunks.push((kind, data));
+        offset += 8 + length;
+    }
+    match chunks.as_slice() {
+        [(CHUNK_JSON, json)] | [(CHUNK_JSON, json), (CHUNK_BIN, _)] => serde_json::from_slice(json).map_err(|e| format!("invalid JSON chunk: {e}")),
+        _ => Err("the first chunk must be JSON, optionally followed by one binary chunk".to_string()),
+    }
+}
+
+fn parse(bytes: &[u8]) -> std::result::Result<VrmMeta, String> {
+    let json = glb_json(bytes)?;
+    let count = |name: &str| json.get(name).and_then(Value::as_array).map_or(0, Vec::len);
+    let used = json["extensionsUsed"].as_array().is_some_and(|used| used.iter().any(|e| e == "VRMC_vrm"));
+    let vrm = &json["extensions"]["VRMC_vrm"];
+    if !used || !vrm.is_object() {
+        let hint = if json["extensions"]["VRM"].is_object() { " (this looks like VRM 0.x)" } else { "" };
+        return Err(format!("missing the VRMC_vrm extension{hint}"));
+    }
+    if vrm["specVersion"] != "1.0" {
+        return Err(format!("unsupported specVersion {}", vrm["specVersion"]));
+    }
+    let meta: VrmMeta = serde_json::from_value(vrm["meta"].clone()).map_err(|e| format!("invalid meta: {e}"))?;
+    if meta.authors.is_empty() {
+        return Err("meta must name at least one author".to_string());
+    }
+    if meta.thumbnail_image.is_some_and(|image| image as usize >= count("images")) {
+        return Err("thumbnailImage is not one of the model's images".to_string());
+    }
+    let bones = &vrm["humanoid"]["humanBones"];
+    for bone in REQUIRED_BONES {
+        let node = bones[bone]["node"].as_u64().ok_or_else(|| format!("humanoid is missing the {bone} bone"))?;
+        if node as usize >= count("nodes") {
+            return Err(format!("the {bone} bone points at a node that doesn't exist"));
+        }
+    }
+    Ok(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use nexus_common::fixtures::{glb, vrm_json as model};
+    use nexus_common::VrmAvatarPermission;
+    use super::*;
+
+    /// `bytes` with a chunk of `kind` appended.
+    fn with_chunk(mut bytes: Vec<u8>, kind: u32) -> Vec<u8> {
+        bytes.extend(4u32.to_le_bytes());
+        bytes.extend(kind.to_le_bytes());
+        bytes.extend([0; 4]);
+        let length = bytes.len() as u32;
+        bytes[8..12].copy_from_slice(&length.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn reads_vrm_meta() {
+        let meta = read_vrm(&glb(&model())).unwrap();
+        assert_eq!(meta.name, "Lobby Bot");
+        assert_eq!(meta.authors, vec!["malek".to_string()]);
+        assert_eq!(meta.thumbnail_image, Some(0));
+        assert_eq!(meta.avatar_permission, VrmAvatarPermission::Everyone);
+        assert!(!meta.allow_redistribution);
+    }
+
+    #[test]
+    fn rejects_malformed_models() {
+        let rejects = |bytes: &[u8], reason: &str| assert!(parse(bytes).unwrap_err().contains(reason), "expected {reason}");
+        rejects(b"not a model", "not a glTF binary");
+        let mut truncated = glb(&model());
+        truncated.truncate(40);
+        rejects(&truncated, "does not match");
+        assert!(parse(&with_chunk(glb(&model()), CHUNK_BIN)).is_ok());
+        rejects(&with_chunk(with_chunk(glb(&model()), CHUNK_BIN), CHUNK_BIN), "optionally followed by one binary chunk");
+        rejects(&with_chunk(glb(&model()), 0x12345678), "optionally followed by one binary chunk");
+        let mut old = model();
+        old["extensions"] = json!({ "VRM": {} });
+        rejects(&glb(&old), "VRM 0.x");
+        let mut no_hips = model();
+        no_hips["extensions"]["VRMC_vrm"]["humanoid"]["humanBones"].as_object_mut().unwrap().remove("hips");
+        rejects(&glb(&no_hips), "hips");
+        let mut no_authors = model();
+        no_authors["extensions"]["VRMC_vrm"]["meta"]["authors"] = json!([]);
+        rejects(&glb(&no_authors), "author");
+        let mut bad_thumbnail = model();
+        bad_thumbnail["extensions"]["VRMC_vrm"]["meta"]["thumbnailImage"] = json!(3);
+        rejects(&glb(&bad_thumbnail), "thumbnailImage");
+        let mut no_license = model();
+        no_license["extensions"]["VRMC_vrm"]["meta"].as_object_mut().unwrap().remove("licenseUrl");
+        rejects(&glb(&no_license), "licenseUrl");
+    }
+}
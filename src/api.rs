@@ -0,0 +1,205 @@
+//! Every HTTP endpoint a nexus server exposes, defined once so the server's router and the
+//! client functions are built from the same paths and types.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Bump when a change to an endpoint would break servers or clients built against the old one.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Sent by both servers and clients on every request and response.
+pub const PROTOCOL_VERSION_HEADER: &str = "x-nexus-protocol";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+pub trait Endpoint {
+    /// The route as the server declares it, with `:name` segments for path parameters.
+    const PATH: &'static str;
+    const METHOD: Method;
+    /// The JSON body. `()` for endpoints without one.
+    type Request: Serialize + DeserializeOwned;
+    type Response: Serialize + DeserializeOwned;
+
+    /// [`Self::PATH`] with its `:name` segments replaced by `params`, in order.
+    fn path(params: &[&str]) -> String {
+        let mut params = params.iter();
+        Self::PATH
+            .split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => *params.next().unwrap_or_else(|| panic!("missing path parameter {name} for {}", Self::PATH)),
+                None => segment,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+pub type Request<E> = <E as Endpoint>::Request;
+pub type Response<E> = <E as Endpoint>::Response;
+
+macro_rules! endpoints {
+    ($($(#[$meta:meta])* $name:ident: $method:ident $path:literal, $request:ty => $response:ty;)*) => {
+        $(
+            $(#[$meta])*
+            pub struct $name;
+            impl $crate::api::Endpoint for $name {
+                const PATH: &'static str = $path;
+                const METHOD: $crate::api::Method = $crate::api::Method::$method;
+                type Request = $request;
+                type Response = $response;
+            }
+        )*
+    };
+}
+
+endpoints! {
+    /// Served on the root of a user's website rather than under the server's base url.
+    GetDiscovery: Get "/.well-known/nexus", () => crate::discovery::Discovery;
+    GetServerKey: Get "/.well-known/nexus-key", () => crate::ServerKey;
+    Register: Post "/register", crate::Registration => ();
+    Login: Post "/:username/login", crate::Credentials => crate::SessionToken;
+    /// Responds with the file itself rather than JSON. A single `Range` is honoured, and since the
+    /// path is the file's hash responses can be cached forever.
+    GetAvatarBlob: Get "/avatar-blobs/:hash", () => ();
+}
+
+/// Endpoints a user's own client calls with a session token.
+pub mod client_server {
+    use crate::{AvatarBlob, AvatarBlobs, AvatarMeta, AvatarRequest, AvatarUuid, AvatarVisibility, Avatars, BlockList, BlockTarget, Delivery, Event, FriendInstance, FriendPresence, FriendRequest, FriendRequestUuid, Instance, InstanceRef, InstanceRegistration, Invite, InviteUuid, PlayerCountUpdate, Presence, PresenceUpdate, PrivacySettings, Profile, ProfileSettings, UnfriendRequest, Username};
+
+    endpoints! {
+        Logout: Post "/:username/private/post/logout", () => ();
+        /// Streamed as server-sent events, one [`Event`] per `data:` line. Send the last cursor
+        /// seen as `Last-Event-ID` to resume after it.
+        GetEvents: Get "/:username/private/events", () => Event;
+        GetOutbox: Get "/:username/private/get/outbox", () => Vec<Delivery>;
+        /// Removes a failed delivery, by id, from the outbox.
+        DismissDelivery: Post "/:username/private/post/dismiss-delivery", String => ();
+        /// Queues a delivery that failed for taking too long again. Ones the remote server
+        /// rejected can't be retried, their follow-up already happened.
+        RetryDelivery: Post "/:username/private/post/retry-delivery", String => ();
+        GetFriends: Get "/:username/private/get/friends", () => Vec<Username>;
+        GetSentInvites: Get "/:username/private/get/sent-invites", () => Vec<InviteUuid>;
+        GetRecInvites: Get "/:username/private/get/rec-invites", () => Vec<InviteUuid>;
+        GetSentFriendRequests: Get "/:username/private/get/sent-friend-requests", () => Vec<FriendRequestUuid>;
+        GetRecFriendRequests: Get "/:username/private/get/rec-friend-requests", () => Vec<FriendRequestUuid>;
+        GetInvite: Get "/:username/private/get/invite/:uuid", () => Invite;
+        /// The instance an invite leads to, looked up on the instance's server as the user.
+        ResolveInvite: Get "/:username/private/get/invite/:uuid/join", () => Instance;
+        GetFriendRequest: Get "/:username/private/get/friend-request/:uuid", () => FriendRequest;
+        GetPresence: Get "/:username/private/get/presence", () => Presence;
+        /// The presence of every friend, offline for any that haven't reported one.
+        GetFriendsPresence: Get "/:username/private/get/friends-presence", () => Vec<FriendPresence>;
+        SetPresence: Post "/:username/private/post/presence", PresenceUpdate => ();
+        /// Keeps the current presence from expiring.
+        Heartbeat: Post "/:username/private/post/heartbeat", () => ();
+        /// Instances the user hosts.
+        GetInstances: Get "/:username/private/get/instances", () => Vec<Instance>;
+        /// Instances friends are in that the user is allowed to see, wherever they are registered.
+        GetFriendsInstances: Get "/:username/private/get/friends-instances", () => Vec<FriendInstance>;
+        /// Registers an instance hosted by the user, or updates it if it is already theirs.
+        RegisterInstance: Post "/:username/private/post/register-instance", InstanceRegistration => Instance;
+        /// Also keeps the instance from expiring.
+        UpdatePlayerCount: Post "/:username/private/post/instance-players", PlayerCountUpdate => ();
+        CloseInstance: Post "/:username/private/post/close-instance", InstanceRef => ();
+        GetAvatars: Get "/:username/private/get/avatars", () => Avatars;
+        /// Adds an avatar, or replaces the one with the same uuid. The first one becomes the default.
+        PublishAvatar: Post "/:username/private/post/publish-avatar", AvatarMeta => ();
+        /// Like [`PublishAvatar`], but the server downloads the file first to fill in its hash and,
+        /// for VRMs, validate it and read its metadata.
+        IngestAvatar: Post "/:username/private/post/ingest-avatar", AvatarMeta => AvatarMeta;
+        RemoveAvatar: Post "/:username/private/post/remove-avatar", AvatarUuid => ();
+        SetDefaultAvatar: Post "/:username/private/post/default-avatar", AvatarUuid => ();
+        SetAvatarVisibility: Post "/:username/private/post/avatar-visibility", AvatarVisibility => ();
+        GetAvatarBlobs: Get "/:username/private/get/avatar-blobs", () => AvatarBlobs;
+        /// The body is the file itself rather than JSON. Uploading a file the user already stores
+        /// changes nothing.
+        UploadAvatarBlob: Post "/:username/private/post/upload-avatar-blob", () => AvatarBlob;
+        /// Takes the hash of the file. Files one of the user's avatars still uses can't be removed.
+        RemoveAvatarBlob: Post "/:username/private/post/remove-avatar-blob", String => ();
+        /// Any user's avatar, fetched from their server if they are remote.
+        GetUserAvatar: Post "/:username/private/post/user-avatar", AvatarRequest => AvatarMeta;
+        GetPrivacy: Get "/:username/private/get/privacy", () => PrivacySettings;
+        SetPrivacy: Post "/:username/private/post/privacy", PrivacySettings => ();
+        GetBlocks: Get "/:username/private/get/blocks", () => BlockList;
+        /// Also unfriends whoever it covers and drops their pending friend requests and invites.
+        Block: Post "/:username/private/post/block", BlockTarget => ();
+        Unblock: Post "/:username/private/post/unblock", BlockTarget => ();
+        GetProfile: Get "/:username/private/get/profile", () => ProfileSettings;
+        /// Replaces the profile and tells friends' servers. Setting `avatar` changes the default avatar.
+        SetProfile: Post "/:username/private/post/profile", ProfileSettings => ();
+        /// Any user's profile as the user may see it, from this server's cache for friends or
+        /// fetched from their server.
+        GetUserProfile: Post "/:username/private/post/user-profile", Username => Profile;
+        SendInvite: Post "/:username/private/post/send-invite", Invite => ();
+        RemoveInvite: Post "/:username/private/post/remove-invite", InviteUuid => ();
+        /// Answers a received invite, telling the sender, and returns the instance to join.
+        AcceptInvite: Post "/:username/private/post/accept-invite", InviteUuid => Instance;
+        DeclineInvite: Post "/:username/private/post/decline-invite", InviteUuid => ();
+        SendFriendRequest: Post "/:username/private/post/send-friend-request", FriendRequest => ();
+        AcceptFriendRequest: Post "/:username/private/post/accept-friend-request", FriendRequestUuid => ();
+        DenyFriendRequest: Post "/:username/private/post/deny-friend-request", FriendRequestUuid => ();
+        /// Takes back a sent friend request that hasn't been answered yet.
+        CancelFriendRequest: Post "/:username/private/post/cancel-friend-request", FriendRequestUuid => ();
+        Unfriend: Post "/:username/private/post/unfriend", UnfriendRequest => ();
+    }
+}
+
+/// Endpoints only the server's admins may call, with their session token.
+pub mod admin {
+    use crate::{Defederation, FederationPolicy};
+
+    endpoints! {
+        /// The policy in effect: the server config's plus any changes admins made.
+        GetFederation: Get "/:username/admin/get/federation", () => FederationPolicy;
+        /// Takes the domain. Domains the server config denies stay denied.
+        AllowDomain: Post "/:username/admin/post/allow-domain", String => ();
+        DenyDomain: Post "/:username/admin/post/deny-domain", Defederation => ();
+        /// Takes the domain, undoing what admins allowed or denied for it.
+        ResetDomain: Post "/:username/admin/post/reset-domain", String => ();
+    }
+}
+
+/// Endpoints one nexus server calls on another with a signed request.
+pub mod server_server {
+    use crate::{AvatarMeta, AvatarQuery, FriendCheck, FriendRequest, FriendRequestUuid, Instance, InstanceQuery, Invite, InviteAnswer, PresenceNotice, Profile, ProfileNotice, ProfileQuery, UnfriendRequest};
+
+    endpoints! {
+        /// `Forbidden` if the recipient doesn't take invites from the sender.
+        SendInvite: Post "/:username/friend/post/send-invite", Invite => ();
+        /// `Forbidden` if the recipient doesn't take friend requests from the sender.
+        SendFriendRequest: Post "/:username/public/post/send-friend-request", FriendRequest => ();
+        AcceptFriendRequest: Post "/:username/public/post/accept-friend-request", FriendRequestUuid => ();
+        DenyFriendRequest: Post "/:username/public/post/deny-friend-request", FriendRequestUuid => ();
+        CancelFriendRequest: Post "/:username/public/post/cancel-friend-request", FriendRequestUuid => ();
+        Unfriend: Post "/:username/friend/post/unfriend", UnfriendRequest => ();
+        AnswerInvite: Post "/:username/friend/post/answer-invite", InviteAnswer => ();
+        /// Whether the user is friends with `user`, answered only for the user's friends. Used to
+        /// find friends of friends.
+        CheckFriend: Post "/:username/friend/post/check-friend", FriendCheck => bool;
+        /// Sent to one friend per server, which stores it for every local friend of the sender.
+        UpdatePresence: Post "/:username/friend/post/presence", PresenceNotice => ();
+        /// Answers with the instance only if `from` may see it, and `NotFound` otherwise.
+        GetInstance: Post "/instances/query", InstanceQuery => Instance;
+        /// `NotFound` if `from` may not see the user's avatars or none is in a supported format.
+        GetAvatar: Post "/:username/public/post/avatar", AvatarQuery => AvatarMeta;
+        /// The profile with only the fields `from` may see.
+        GetProfile: Post "/:username/public/post/profile", ProfileQuery => Profile;
+        /// Sent to one friend per server of a user whose profile changed, naming the others there.
+        UpdateProfile: Post "/:username/friend/post/profile", ProfileNotice => ();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_path_parameters() {
+        assert_eq!(client_server::GetInvite::path(&["malek", "1"]), "/malek/private/get/invite/1");
+        assert_eq!(Register::path(&[]), "/register");
+    }
+}
@@ -0,0 +1,151 @@
+//! Finding the nexus server for a domain. A user's website only has to serve the small
+//! [`Discovery`] document at [`GetDiscovery`](crate::api::GetDiscovery), which points at wherever the server actually runs.
+
+use serde::{Deserialize, Serialize};
+
+/// Served at `/.well-known/nexus` on the root of a domain.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Discovery {
+    /// Base url every other [`api`](crate::api) path is relative to, e.g. `https://nexus.example.com/api`.
+    pub server: String,
+    pub protocol_version: u32,
+    /// Optional features the server supports, so clients can hide what it can't do.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl Discovery {
+    /// What a server from before discovery existed looks like: everything at the root of the domain.
+    pub fn legacy(scheme: &str, domain: &str) -> Self {
+        Self { server: format!("{scheme}://{domain}"), protocol_version: 1, capabilities: vec![] }
+    }
+    /// The full url of `path` on this server, where `path` comes from [`Endpoint::path`](crate::api::Endpoint::path).
+    pub fn url(&self, path: &str) -> String {
+        self.server.trim_end_matches('/').to_string() + path
+    }
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+#[cfg(feature = "resolver")]
+pub use resolver::Resolver;
+
+#[cfg(feature = "resolver")]
+mod resolver {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use reqwest::StatusCode;
+    use crate::api::{self, Endpoint};
+    use crate::ApiError;
+    use super::Discovery;
+
+    /// How long a resolved domain is trusted before its discovery document is fetched again.
+    const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+    /// How long a domain that couldn't be resolved is left alone before trying again.
+    const FAILURE_TTL: Duration = Duration::from_secs(60);
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Whether `domain` is this machine, where plain http can't be intercepted on the way.
+    fn is_local(domain: &str) -> bool {
+        let host = match domain.strip_prefix('[') {
+            Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+            None => domain.split(':').next().unwrap_or_default(),
+        };
+        host == "localhost" || host.ends_with(".localhost") || host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    }
+
+    type Cache = HashMap<String, (Result<Discovery, ApiError>, Instant)>;
+
+    /// Resolves domains to their [`Discovery`] document, caching the answers. Cheap to clone, clones
+    /// share the cache.
+    #[derive(Clone, Default)]
+    pub struct Resolver {
+        cache: Arc<Mutex<Cache>>,
+        allow_http: bool,
+    }
+
+    impl Resolver {
+        /// With `allow_http`, discovery falls back to plain http for every domain rather than only
+        /// for local ones. Anyone on the path could then point us at their own server and keys.
+        pub fn new(allow_http: bool) -> Self {
+            Self { allow_http, ..Default::default() }
+        }
+        /// Tries `https`, then `http` if the domain is local or [`Resolver::new`] allowed it. A
+        /// domain that answers but has no discovery document is assumed to host its server at the
+        /// root. Failures are remembered for a minute.
+        pub async fn resolve(&self, client: &reqwest::Client, domain: &str) -> Result<Discovery, ApiError> {
+            if let Some((result, fetched)) = self.cache.lock().unwrap().get(domain) {
+                let ttl = if result.is_ok() { CACHE_TTL } else { FAILURE_TTL };
+                if fetched.elapsed() < ttl {
+                    return result.clone();
+                }
+            }
+            let result = self.fetch(client, domain).await;
+            self.cache.lock().unwrap().insert(domain.to_string(), (result.clone(), Instant::now()));
+            result
+        }
+        async fn fetch(&self, client: &reqwest::Client, domain: &str) -> Result<Discovery, ApiError> {
+            let schemes: &[&str] = match self.allow_http || is_local(domain) {
+                true => &["https", "http"],
+                false => &["https"],
+            };
+            let mut last_error = String::new();
+            for &scheme in schemes {
+                let response = client
+                    .get(format!("{scheme}://{domain}{}", api::GetDiscovery::PATH))
+                    .timeout(REQUEST_TIMEOUT)
+                    .send()
+                    .await;
+                let discovery = match response {
+                    Ok(response) if response.status() == StatusCode::NOT_FOUND => Discovery::legacy(scheme, domain),
+                    Ok(response) if response.status().is_success() => match response.json::<Discovery>().await {
+                        Ok(discovery) => discovery,
+                        Err(error) => {
+                            last_error = format!("invalid discovery document: {error}");
+                            continue;
+                        }
+                    },
+                    Ok(response) => {
+                        last_error = response.status().to_string();
+                        continue;
+                    }
+                    Err(error) => {
+                        last_error = error.to_string();
+                        continue;
+                    }
+                };
+                return Ok(discovery);
+            }
+            Err(ApiError::RemoteUnreachable(format!("could not resolve {domain}: {last_error}")))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn only_trusts_http_locally() {
+            assert!(is_local("localhost:8000"));
+            assert!(is_local("nexus.localhost"));
+            assert!(is_local("127.0.0.1:9000"));
+            assert!(is_local("[::1]:9000"));
+            assert!(!is_local("example.com"));
+            assert!(!is_local("localhost.example.com:8000"));
+            assert!(!is_local("[2001:db8::1]:443"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_base_url_and_path() {
+        let discovery = Discovery { server: "https://nexus.example.com/api/".to_string(), protocol_version: 1, capabilities: vec![] };
+        assert_eq!(discovery.url("/register"), "https://nexus.example.com/api/register");
+    }
+}
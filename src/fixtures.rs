@@ -0,0 +1,41 @@
+//! Sample files shared by the server's and client's tests.
+
+use serde_json::{json, Map, Value};
+
+/// Wraps `json` in a glTF binary container with no binary chunk.
+pub fn glb(json: &Value) -> Vec<u8> {
+    let mut chunk = serde_json::to_vec(json).unwrap();
+    chunk.resize(chunk.len().next_multiple_of(4), b' ');
+    let mut bytes = b"glTF".to_vec();
+    bytes.extend(2u32.to_le_bytes());
+    bytes.extend((12 + 8 + chunk.len() as u32).to_le_bytes());
+    bytes.extend((chunk.len() as u32).to_le_bytes());
+    bytes.extend(0x4E4F534Au32.to_le_bytes());
+    bytes.extend(chunk);
+    bytes
+}
+
+/// The JSON of the smallest VRM 1.0 model the server accepts, to edit before wrapping it with
+/// [`glb`].
+pub fn vrm_json() -> Value {
+    let bones: Map<_, _> = ["hips", "spine", "head", "leftUpperArm", "leftLowerArm", "leftHand", "rightUpperArm", "rightLowerArm", "rightHand", "leftUpperLeg", "leftLowerLeg", "leftFoot", "rightUpperLeg", "rightLowerLeg", "rightFoot"]
+        .iter()
+        .map(|bone| (bone.to_string(), json!({ "node": 0 })))
+        .collect();
+    json!({
+        "asset": { "version": "2.0" },
+        "nodes": [{}],
+        "images": [{}],
+        "extensionsUsed": ["VRMC_vrm"],
+        "extensions": { "VRMC_vrm": {
+            "specVersion": "1.0",
+            "meta": { "name": "Lobby Bot", "authors": ["malek"], "licenseUrl": "https://vrm.dev/licenses/1.0/", "thumbnailImage": 0, "avatarPermission": "everyone" },
+            "humanoid": { "humanBones": bones },
+        } },
+    })
+}
+
+/// [`vrm_json`] as a file.
+pub fn vrm_model() -> Vec<u8> {
+    glb(&vrm_json())
+}
@@ -1,11 +1,23 @@
+pub mod api;
+pub mod discovery;
+#[cfg(feature = "fixtures")]
+pub mod fixtures;
 pub mod non_api_structs;
 
-use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Debug, Serialize, Deserialize, Default)]
 pub struct Url(pub String);
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Debug, Serialize, Deserialize, Default)]
+/// A user on some nexus server, written `alice@example.com:8000`. Parse one with [`str::parse`],
+/// which validates and normalizes both parts, and print it with `Display`.
+///
+/// Serialized as that string. The `{username, website}` struct older servers sent and stored is
+/// still accepted when deserializing, and validated and normalized the same way.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Debug, Default)]
 #[repr(C)]
 pub struct Username{ pub username: String, pub website: String}
 impl AsRef<Username> for Username {
@@ -14,22 +26,204 @@ impl AsRef<Username> for Username {
     }
 }
 impl Username {
-    pub fn from(string: impl AsRef<str>) -> Option<Self> {
-        let string = string.as_ref().to_string();
-        Some(Self {
-            username: string.split_once('.')?.0.to_string(),
-            website: string.split_once('.')?.1.to_string(),
-        })
+    /// Validates and normalizes `username` and `website` separately.
+    pub fn new(username: &str, website: &str) -> Result<Self, UsernameParseError> {
+        Ok(Self { username: normalize_username(username)?, website: normalize_website(website)? })
+    }
+}
+impl FromStr for Username {
+    type Err = UsernameParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (username, website) = s.trim().split_once('@').ok_or(UsernameParseError::MissingAt)?;
+        Self::new(username, website)
+    }
+}
+impl Display for Username {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.username, self.website)
+    }
+}
+impl Serialize for Username {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+impl<'de> Deserialize<'de> for Username {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Text(String),
+            Legacy { username: String, website: String },
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Text(text) => text.parse().map_err(serde::de::Error::custom),
+            Repr::Legacy { username, website } => Username::new(&username, &website).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Why a string isn't a valid [`Username`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UsernameParseError {
+    MissingAt,
+    InvalidUsername,
+    EmptyWebsite,
+    InvalidWebsite(String),
+    InvalidPort,
+}
+impl Display for UsernameParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsernameParseError::MissingAt => write!(f, "expected a username like alice@example.com"),
+            UsernameParseError::InvalidUsername => write!(f, "the part before the @ must be 1 to {MAX_USERNAME_LEN} characters of a-z, 0-9, '_' or '-'"),
+            UsernameParseError::EmptyWebsite => write!(f, "the part after the @ is empty"),
+            UsernameParseError::InvalidWebsite(website) => write!(f, "{website} is not a valid domain"),
+            UsernameParseError::InvalidPort => write!(f, "the port must be a number from 1 to 65535"),
+        }
     }
-    pub fn to_url(&self) -> Url {
-        Url(String::from("http://") + &self.website + "/" + &self.username)
+}
+impl std::error::Error for UsernameParseError {}
+
+fn normalize_username(username: &str) -> Result<String, UsernameParseError> {
+    let username = username.to_lowercase();
+    match is_valid_username(&username) {
+        true => Ok(username),
+        false => Err(UsernameParseError::InvalidUsername),
     }
 }
+
+/// Lowercases the host and converts internationalized domain names to their ASCII form, so every
+/// spelling of a domain compares equal. IPv6 hosts must be in brackets.
+fn normalize_website(website: &str) -> Result<String, UsernameParseError> {
+    if website.is_empty() {
+        return Err(UsernameParseError::EmptyWebsite);
+    }
+    let (host, port) = match website.rsplit_once(':') {
+        Some((host, port)) if !website.ends_with(']') => (host, Some(port)),
+        _ => (website, None),
+    };
+    let host = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        Some(ip) => format!("[{}]", ip.parse::<Ipv6Addr>().map_err(|_| UsernameParseError::InvalidWebsite(website.to_string()))?),
+        None => idna::domain_to_ascii_strict(host)
+            .ok()
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| UsernameParseError::InvalidWebsite(website.to_string()))?,
+    };
+    match port {
+        None => Ok(host),
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) if port != 0 => Ok(format!("{host}:{port}")),
+            _ => Err(UsernameParseError::InvalidPort),
+        },
+    }
+}
+/// The longest message an invite can carry, in bytes.
+pub const MAX_INVITE_MESSAGE_LEN: usize = 500;
 #[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
 pub struct Invite {
     pub from: Username,
     pub to: Username,
     pub uuid: InviteUuid,
+    /// Where the invite leads.
+    #[serde(default)]
+    pub instance: InstanceRef,
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Unix timestamps in seconds. Invites stored before these existed read as long expired.
+    #[serde(default)]
+    pub created: u64,
+    #[serde(default)]
+    pub expires: u64,
+    /// How far the invite got, as far as the server holding this copy knows.
+    #[serde(default)]
+    pub status: InviteStatus,
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InviteStatus {
+    /// Not yet delivered to the recipient's server.
+    #[default]
+    Pending,
+    Delivered,
+    Accepted,
+    Declined,
+    /// Ran out before the recipient answered.
+    Expired,
+    /// The recipient's server refused it, usually because of the recipient's privacy settings.
+    Rejected,
+}
+/// The recipient's answer to an invite, sent back to the sender's server.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct InviteAnswer {
+    pub uuid: InviteUuid,
+    pub accepted: bool,
+}
+impl Invite {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now > self.expires
+    }
+}
+/// Identifies a game instance in the registry of the nexus server at `domain`. Also where a user
+/// is in their presence, as far as their friends can follow them.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq, Hash)]
+pub struct InstanceRef {
+    pub world_id: String,
+    pub instance_id: String,
+    /// The nexus server the instance is registered with.
+    #[serde(default)]
+    pub domain: String,
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InstanceVisibility {
+    /// Anyone who knows the instance can look it up.
+    Public,
+    /// Only friends of the host.
+    #[default]
+    Friends,
+    /// Never listed, players get in through an invite.
+    Private,
+}
+/// What a game server sends to register, or re-register, one of its instances.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct InstanceRegistration {
+    pub instance: InstanceRef,
+    /// Where game clients connect to, in whatever form the game understands.
+    pub join_address: String,
+    pub capacity: u32,
+    pub visibility: InstanceVisibility,
+}
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct PlayerCountUpdate {
+    pub instance: InstanceRef,
+    pub players: u32,
+}
+/// A registered instance. `host` is the user the game server registered it as.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct Instance {
+    pub instance: InstanceRef,
+    pub host: Username,
+    pub join_address: String,
+    pub capacity: u32,
+    pub players: u32,
+    pub visibility: InstanceVisibility,
+    /// Unix timestamps in seconds. An instance that isn't updated for a while is dropped.
+    pub created: u64,
+    pub updated: u64,
+}
+/// An instance some of the user's friends are in, according to their presence.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct FriendInstance {
+    pub instance: Instance,
+    pub friends: Vec<Username>,
+}
+/// Asks an instance's server for it on behalf of `from`, who it decides visibility for.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct InstanceQuery {
+    pub from: Username,
+    pub instance: InstanceRef,
 }
 #[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
 pub struct FriendRequest {
@@ -37,26 +231,639 @@ pub struct FriendRequest {
     pub to: Username,
     pub uuid: FriendRequestUuid,
 }
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FriendRequestPolicy {
+    #[default]
+    Anyone,
+    /// Users who share a friend with the recipient.
+    FriendsOfFriends,
+    /// Users on one of [`PrivacySettings::allowed_domains`].
+    AllowedDomains,
+    Nobody,
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InvitePolicy {
+    #[default]
+    Friends,
+    Anyone,
+}
+/// Who may reach a user, enforced by the user's own server on everything it receives.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(default)]
+pub struct PrivacySettings {
+    pub friend_requests: FriendRequestPolicy,
+    pub allowed_domains: Vec<String>,
+    pub invites: InvitePolicy,
+}
+/// Someone a user doesn't want to hear from: one user, or everyone on a server.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockTarget {
+    User(Username),
+    Domain(String),
+}
+/// Friend requests, invites and presence from blocked users are dropped without telling them, and
+/// they can no longer see the user's profile or presence.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(default)]
+pub struct BlockList {
+    pub users: Vec<Username>,
+    pub domains: Vec<String>,
+}
+impl BlockList {
+    pub fn blocks(&self, user: &Username) -> bool {
+        self.users.contains(user) || self.domains.contains(&user.website)
+    }
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FederationMode {
+    /// Every server that isn't denied.
+    #[default]
+    Open,
+    /// Only the allowed servers, and never a denied one.
+    Allowlist,
+}
+/// Which other servers a nexus server talks to, in either direction.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(default)]
+pub struct FederationPolicy {
+    pub mode: FederationMode,
+    pub allowed: Vec<String>,
+    pub denied: Vec<String>,
+}
+impl FederationPolicy {
+    pub fn allows(&self, domain: &str) -> bool {
+        let listed = |list: &[String]| list.iter().any(|listed| listed == domain);
+        !listed(&self.denied) && (self.mode == FederationMode::Open || listed(&self.allowed))
+    }
+}
+/// Stops federating with a domain.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct Defederation {
+    pub domain: String,
+    /// Also ends local users' friendships and pending requests with users there, telling them
+    /// with an [`EventKind::Defederated`].
+    #[serde(default)]
+    pub sever_friendships: bool,
+}
+/// Asks a user's server whether they are friends with `user`, on behalf of their friend `from`.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct FriendCheck {
+    pub from: Username,
+    pub user: Username,
+}
 #[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
 pub struct UnfriendRequest {
     pub from: Username,
     pub to: Username,
 }
+/// Published by every nexus server at `/.well-known/nexus-key` so other servers can check the
+/// signatures on its federation requests.
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
-pub struct AvatarMeta {
-    format: AvatarFormat,
-    link: Url,
-    uuid: AvatarUuid,
+pub struct ServerKey {
+    pub domain: String,
+    /// Base64 encoded ed25519 public key.
+    pub public_key: String,
+}
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct Credentials {
+    pub password: String,
 }
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct Registration {
+    pub username: String,
+    pub password: String,
+    pub invite_code: Option<String>,
+}
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum RegisterError {
+    UsernameTaken,
+    InvalidUsername,
+    InviteCodeRequired,
+    InvalidInviteCode,
+    RegistrationClosed,
+}
+impl Display for RegisterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegisterError::UsernameTaken => write!(f, "username is already taken"),
+            RegisterError::InvalidUsername => write!(f, "usernames must be 1 to {MAX_USERNAME_LEN} characters of a-z, 0-9, '_' or '-'"),
+            RegisterError::InviteCodeRequired => write!(f, "this server requires an invite code to register"),
+            RegisterError::InvalidInviteCode => write!(f, "invite code is invalid or already used"),
+            RegisterError::RegistrationClosed => write!(f, "registration is closed on this server"),
+        }
+    }
+}
+impl std::error::Error for RegisterError {}
+
+/// The JSON body of every error response from a nexus server, apart from registration which has
+/// its own [`RegisterError`]. The variant decides the HTTP status, see [`ApiError::status`].
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(tag = "kind", content = "message")]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    AlreadyExists(String),
+    /// Sent with a `Retry-After` header saying how many seconds to wait.
+    TooManyRequests(String),
+    /// Another nexus server refused a request made on the user's behalf.
+    RemoteRejected(String),
+    /// Another nexus server could not be reached.
+    RemoteUnreachable(String),
+    Internal(String),
+}
+impl ApiError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::BadRequest(_) => 400,
+            ApiError::Unauthorized(_) => 401,
+            ApiError::Forbidden(_) => 403,
+            ApiError::NotFound(_) => 404,
+            ApiError::AlreadyExists(_) => 409,
+            ApiError::TooManyRequests(_) => 429,
+            ApiError::RemoteRejected(_) => 424,
+            ApiError::RemoteUnreachable(_) => 502,
+            ApiError::Internal(_) => 500,
+        }
+    }
+    /// The closest variant for a response that didn't carry an `ApiError` body, such as one from a
+    /// proxy in front of the server.
+    pub fn from_status(status: u16, message: String) -> Self {
+        match status {
+            401 => ApiError::Unauthorized(message),
+            403 => ApiError::Forbidden(message),
+            404 => ApiError::NotFound(message),
+            409 => ApiError::AlreadyExists(message),
+            429 => ApiError::TooManyRequests(message),
+            502..=504 => ApiError::RemoteUnreachable(message),
+            400..=499 => ApiError::BadRequest(message),
+            _ => ApiError::Internal(message),
+        }
+    }
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::AlreadyExists(message)
+            | ApiError::TooManyRequests(message)
+            | ApiError::RemoteRejected(message)
+            | ApiError::RemoteUnreachable(message)
+            | ApiError::Internal(message) => message,
+        }
+    }
+}
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            ApiError::BadRequest(_) => "bad request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not found",
+            ApiError::AlreadyExists(_) => "already exists",
+            ApiError::TooManyRequests(_) => "too many requests",
+            ApiError::RemoteRejected(_) => "rejected by remote server",
+            ApiError::RemoteUnreachable(_) => "remote server unreachable",
+            ApiError::Internal(_) => "internal server error",
+        };
+        write!(f, "{kind}: {}", self.message())
+    }
+}
+impl std::error::Error for ApiError {}
+
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/// Whether `name` may be registered as the local part of a [`Username`].
+pub fn is_valid_username(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_USERNAME_LEN
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PresenceStatus {
+    #[default]
+    Offline,
+    Online,
+    Busy,
+    /// Online, but shown to friends as offline.
+    Invisible,
+}
+/// What a client sets its user's presence to. Sending it again, or a heartbeat, keeps it alive.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct PresenceUpdate {
+    pub status: PresenceStatus,
+    pub activity: Option<String>,
+    pub instance: Option<InstanceRef>,
+}
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    pub activity: Option<String>,
+    pub instance: Option<InstanceRef>,
+    /// Unix timestamps in seconds. Past `expires` the user counts as offline.
+    pub updated: u64,
+    pub expires: u64,
+}
+impl Presence {
+    /// This presence as friends should see it at `now`: offline once expired or while invisible.
+    pub fn visible_at(&self, now: u64) -> Presence {
+        match now > self.expires || self.status == PresenceStatus::Invisible {
+            true => Presence { status: PresenceStatus::Offline, activity: None, instance: None, ..self.clone() },
+            false => self.clone(),
+        }
+    }
+}
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct FriendPresence {
+    pub user: Username,
+    pub presence: Presence,
+}
+/// A presence update one server pushes to the servers of the user's friends.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct PresenceNotice {
+    pub from: Username,
+    pub presence: Presence,
+}
+/// A change to a user's data pushed over the events stream. `cursor` increases by one per event,
+/// so a client that reconnects with the last cursor it saw gets everything it missed.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Event {
+    pub cursor: u64,
+    pub kind: EventKind,
+}
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(tag = "type", content = "data", rename_all = "kebab-case")]
+pub enum EventKind {
+    FriendRequestReceived(FriendRequest),
+    FriendRequestAccepted(FriendRequest),
+    FriendRequestDenied(FriendRequest),
+    /// The sender took back a friend request before it was answered.
+    FriendRequestCancelled(FriendRequest),
+    /// The recipient's server refused a sent friend request. The outbox says why.
+    FriendRequestRejected(FriendRequest),
+    InviteReceived(Invite),
+    InviteRemoved(InviteUuid),
+    /// A sent invite's status changed.
+    InviteUpdated(Invite),
+    Unfriended(Username),
+    /// A friend changed their profile.
+    ProfileUpdated(Username),
+    /// The user's block list changed, and with it possibly their friends and pending requests.
+    BlocksUpdated,
+    /// The server stopped federating with a domain, ending the user's friendships there.
+    Defederated(String),
+    /// The server no longer has every event after the client's cursor, so the client should
+    /// fetch its data again instead of relying on the stream.
+    Resync,
+}
+/// A federation message a user's server still owes another server, as shown by the outbox endpoint.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct Delivery {
+    pub id: String,
+    pub url: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// Unix timestamps in seconds.
+    pub created: u64,
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub enum DeliveryStatus {
+    /// Will be retried at `next_attempt`.
+    #[default]
+    Pending,
+    /// Gave up, either because the remote server rejected it or it kept failing for too long.
+    Failed,
+}
+/// One of a user's avatars. The file itself is downloaded from `link`.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct AvatarMeta {
+    pub format: AvatarFormat,
+    pub link: Url,
+    pub uuid: AvatarUuid,
+    /// Lowercase hex SHA-256 of the file, so clients can check what they downloaded and cache by it.
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// Read from the file by the user's server when it ingests a VRM, never taken from clients.
+    #[serde(default)]
+    pub vrm: Option<VrmMeta>,
+}
+/// The `meta` of a VRM 1.0 model's `VRMC_vrm` extension: who made it and how it may be used.
+/// Field names and defaults follow the VRM 1.0 specification.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VrmMeta {
+    pub name: String,
+    #[serde(default)]
+    pub version: Option<String>,
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub copyright_information: Option<String>,
+    pub license_url: String,
+    #[serde(default)]
+    pub other_license_url: Option<String>,
+    /// Index into the glTF `images` of the thumbnail.
+    #[serde(default)]
+    pub thumbnail_image: Option<u32>,
+    #[serde(default)]
+    pub avatar_permission: VrmAvatarPermission,
+    #[serde(default)]
+    pub allow_excessively_violent_usage: bool,
+    #[serde(default)]
+    pub allow_excessively_sexual_usage: bool,
+    #[serde(default)]
+    pub commercial_usage: VrmCommercialUsage,
+    #[serde(default)]
+    pub allow_political_or_religious_usage: bool,
+    #[serde(default)]
+    pub allow_antisocial_or_hate_usage: bool,
+    #[serde(default)]
+    pub credit_notation: VrmCreditNotation,
+    #[serde(default)]
+    pub allow_redistribution: bool,
+    #[serde(default)]
+    pub modification: VrmModification,
+}
+/// Who may use the model as their avatar.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum VrmAvatarPermission {
+    #[default]
+    OnlyAuthor,
+    OnlySeparatelyLicensedPerson,
+    Everyone,
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum VrmCommercialUsage {
+    #[default]
+    PersonalNonProfit,
+    PersonalProfit,
+    Corporation,
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum VrmCreditNotation {
+    #[default]
+    Required,
+    Unnecessary,
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum VrmModification {
+    #[default]
+    Prohibited,
+    AllowModification,
+    AllowModificationRedistribution,
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
 pub enum AvatarFormat {
     #[default]
     Vrm1_0,
     ReadyPlayerMe,
 }
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AvatarVisibility {
+    /// Only friends' servers can fetch the user's avatars.
+    #[default]
+    Friends,
+    Public,
+}
+/// Everything a user has published about their avatars.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct Avatars {
+    pub avatars: Vec<AvatarMeta>,
+    /// Shown when the viewer doesn't say which formats it supports, and preferred over other
+    /// avatars of the same format.
+    pub default: Option<AvatarUuid>,
+    pub visibility: AvatarVisibility,
+}
+impl Avatars {
+    /// The avatar to show a client that supports `formats`, in order of preference. The default
+    /// avatar wins within a format, and an empty list means any format.
+    pub fn negotiate(&self, formats: &[AvatarFormat]) -> Option<&AvatarMeta> {
+        let default = self.default.as_ref().and_then(|uuid| self.avatars.iter().find(|avatar| avatar.uuid == *uuid));
+        if formats.is_empty() {
+            return default.or(self.avatars.first());
+        }
+        formats.iter().find_map(|format| {
+            default
+                .filter(|avatar| avatar.format == *format)
+                .or_else(|| self.avatars.iter().find(|avatar| avatar.format == *format))
+        })
+    }
+}
+/// Asks for a user's avatar in the first of `formats` they have one in.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct AvatarRequest {
+    pub user: Username,
+    pub formats: Vec<AvatarFormat>,
+}
+/// An [`AvatarRequest`] as forwarded to the user's server, on behalf of `from`.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct AvatarQuery {
+    pub from: Username,
+    pub formats: Vec<AvatarFormat>,
+}
+/// An avatar file stored on its owner's nexus server, addressed by its hash.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct AvatarBlob {
+    /// Lowercase hex SHA-256 of the file.
+    pub hash: String,
+    pub size: u64,
+    /// Where anyone can download the file, to publish as an [`AvatarMeta::link`].
+    pub link: Url,
+}
+/// The avatar files a user stores on their server. Sizes are in bytes.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct AvatarBlobs {
+    pub blobs: Vec<AvatarBlob>,
+    pub used: u64,
+    pub quota: u64,
+}
+/// Longest profile fields, in bytes.
+pub const MAX_DISPLAY_NAME_LEN: usize = 64;
+pub const MAX_BIO_LEN: usize = 500;
+pub const MAX_PRONOUNS_LEN: usize = 32;
+/// What a user tells others about themselves. Fields the viewer may not see are `None`.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct Profile {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub pronouns: Option<String>,
+    pub image: Option<ProfileImage>,
+    /// The user's default avatar, see [`Avatars::default`].
+    pub avatar: Option<AvatarUuid>,
+    /// Unix timestamp in seconds of the last edit.
+    #[serde(default)]
+    pub updated: u64,
+}
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct ProfileImage {
+    pub link: Url,
+    /// Lowercase hex SHA-256 of the image.
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+impl Profile {
+    /// The name to show for `user`: their display name if this profile has one.
+    pub fn name_for(&self, user: &Username) -> String {
+        match &self.display_name {
+            Some(name) => name.clone(),
+            None => user.to_string(),
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FieldVisibility {
+    #[default]
+    Public,
+    Friends,
+    /// Only the user themselves.
+    Private,
+}
+/// Who may see each field of a [`Profile`].
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[serde(default)]
+pub struct ProfileVisibility {
+    pub display_name: FieldVisibility,
+    pub bio: FieldVisibility,
+    pub pronouns: FieldVisibility,
+    pub image: FieldVisibility,
+    pub avatar: FieldVisibility,
+}
+/// A user's profile as they edit it, with every field, and who may see each one.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct ProfileSettings {
+    pub profile: Profile,
+    pub visibility: ProfileVisibility,
+}
+impl ProfileSettings {
+    /// The profile as someone other than the user sees it.
+    pub fn shown_to(&self, friend: bool) -> Profile {
+        let shown = |visibility: FieldVisibility| match visibility {
+            FieldVisibility::Public => true,
+            FieldVisibility::Friends => friend,
+            FieldVisibility::Private => false,
+        };
+        let (profile, visibility) = (&self.profile, &self.visibility);
+        Profile {
+            display_name: profile.display_name.clone().filter(|_| shown(visibility.display_name)),
+            bio: profile.bio.clone().filter(|_| shown(visibility.bio)),
+            pronouns: profile.pronouns.clone().filter(|_| shown(visibility.pronouns)),
+            image: profile.image.clone().filter(|_| shown(visibility.image)),
+            avatar: profile.avatar.clone().filter(|_| shown(visibility.avatar)),
+            updated: profile.updated,
+        }
+    }
+}
+/// Asks a user's server for their profile on behalf of `from`.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ProfileQuery {
+    pub from: Username,
+}
+/// A changed profile, as its friends may see it, pushed to one friend per server.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ProfileNotice {
+    pub from: Username,
+    pub profile: Profile,
+    /// The sender's other friends on the receiving server, besides the one it is addressed to.
+    #[serde(default)]
+    pub also: Vec<String>,
+}
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize, Default)]
 pub struct InviteUuid(pub String);
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize, Default)]
 pub struct FriendRequestUuid(pub String);
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize, Default)]
-pub struct AvatarUuid(pub String);
\ No newline at end of file
+pub struct AvatarUuid(pub String);
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize, Default)]
+pub struct SessionToken(pub String);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_normalizes_usernames() {
+        let user: Username = "Alice@Example.COM:8000".parse().unwrap();
+        assert_eq!(user, Username { username: "alice".to_string(), website: "example.com:8000".to_string() });
+        assert_eq!(user.to_string(), "alice@example.com:8000");
+        assert_eq!("bob@bücher.example".parse::<Username>().unwrap().website, "xn--bcher-kva.example");
+        assert_eq!("bob@[::1]:9000".parse::<Username>().unwrap().website, "[::1]:9000");
+        assert_eq!("alice.example.com".parse::<Username>(), Err(UsernameParseError::MissingAt));
+        assert_eq!("a.b@example.com".parse::<Username>(), Err(UsernameParseError::InvalidUsername));
+        assert_eq!("alice@".parse::<Username>(), Err(UsernameParseError::EmptyWebsite));
+        assert_eq!("alice@example.com:0".parse::<Username>(), Err(UsernameParseError::InvalidPort));
+        assert!(matches!("alice@exa mple.com".parse::<Username>(), Err(UsernameParseError::InvalidWebsite(_))));
+    }
+
+    #[test]
+    fn accepts_legacy_struct_form() {
+        let user: Username = serde_json::from_str(r#"{"username":"malek","website":"localhost:8000"}"#).unwrap();
+        assert_eq!(serde_json::to_string(&user).unwrap(), r#""malek@localhost:8000""#);
+        assert_eq!(serde_json::from_str::<Username>(r#""malek@localhost:8000""#).unwrap(), user);
+        let user: Username = serde_json::from_str(r#"{"username":"Malek","website":"LocalHost:8000"}"#).unwrap();
+        assert_eq!(user.to_string(), "malek@localhost:8000");
+        assert!(serde_json::from_str::<Username>(r#"{"username":"mal ek","website":"localhost:8000"}"#).is_err());
+    }
+
+    #[test]
+    fn negotiates_avatar_formats() {
+        let avatar = |uuid: &str, format| AvatarMeta { format, link: Url(format!("https://example.com/{uuid}")), uuid: AvatarUuid(uuid.to_string()), hash: None, vrm: None };
+        let mut avatars = Avatars {
+            avatars: vec![avatar("a", AvatarFormat::ReadyPlayerMe), avatar("b", AvatarFormat::Vrm1_0), avatar("c", AvatarFormat::Vrm1_0)],
+            default: Some(AvatarUuid("c".to_string())),
+            visibility: AvatarVisibility::Public,
+        };
+        let negotiated = |avatars: &Avatars, formats: &[AvatarFormat]| avatars.negotiate(formats).map(|avatar| avatar.uuid.0.clone());
+        assert_eq!(negotiated(&avatars, &[]), Some("c".to_string()));
+        assert_eq!(negotiated(&avatars, &[AvatarFormat::Vrm1_0]), Some("c".to_string()));
+        assert_eq!(negotiated(&avatars, &[AvatarFormat::ReadyPlayerMe, AvatarFormat::Vrm1_0]), Some("a".to_string()));
+        avatars.default = None;
+        assert_eq!(negotiated(&avatars, &[AvatarFormat::Vrm1_0]), Some("b".to_string()));
+        avatars.avatars.truncate(1);
+        assert_eq!(negotiated(&avatars, &[AvatarFormat::Vrm1_0]), None);
+    }
+
+    #[test]
+    fn hides_profile_fields() {
+        let settings = ProfileSettings {
+            profile: Profile { display_name: Some(String::from("Malek")), bio: Some(String::from("builds worlds")), pronouns: Some(String::from("he/him")), ..Default::default() },
+            visibility: ProfileVisibility { bio: FieldVisibility::Friends, pronouns: FieldVisibility::Private, ..Default::default() },
+        };
+        let stranger = settings.shown_to(false);
+        assert_eq!((stranger.display_name.as_deref(), stranger.bio.as_deref(), stranger.pronouns.as_deref()), (Some("Malek"), None, None));
+        let friend = settings.shown_to(true);
+        assert_eq!((friend.bio.as_deref(), friend.pronouns.as_deref()), (Some("builds worlds"), None));
+    }
+
+    #[test]
+    fn applies_federation_policy() {
+        let mut policy = FederationPolicy { denied: vec![String::from("spam.example")], allowed: vec![String::from("friends.example"), String::from("spam.example")], ..Default::default() };
+        assert!(policy.allows("anyone.example"));
+        assert!(!policy.allows("spam.example"));
+        policy.mode = FederationMode::Allowlist;
+        assert!(policy.allows("friends.example"));
+        assert!(!policy.allows("anyone.example"));
+        assert!(!policy.allows("spam.example"));
+    }
+
+    #[test]
+    fn blocks_users_and_domains() {
+        let blocks = BlockList { users: vec!["malek@localhost:8000".parse().unwrap()], domains: vec![String::from("spam.example")] };
+        assert!(blocks.blocks(&"malek@localhost:8000".parse().unwrap()));
+        assert!(!blocks.blocks(&"malek@localhost:9000".parse().unwrap()));
+        assert!(blocks.blocks(&"anyone@spam.example".parse().unwrap()));
+        assert!(!blocks.blocks(&"anyone@sub.spam.example".parse().unwrap()));
+    }
+}
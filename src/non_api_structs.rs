@@ -1,6 +1,6 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use serde::{Deserialize, Serialize};
-use crate::{FriendRequest, FriendRequestUuid, Invite, InviteUuid, Username};
+use crate::{Avatars, BlockList, Event, FriendRequest, FriendRequestUuid, Invite, InviteUuid, PrivacySettings, ProfileSettings, Username};
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct UserData {
@@ -11,4 +11,22 @@ pub struct UserData {
     pub invites: HashMap<InviteUuid, Invite>,
     pub sent_invites: HashSet<InviteUuid>,
     pub rec_invites: HashSet<InviteUuid>,
+    /// The most recent events, oldest first, kept so reconnecting clients can catch up.
+    #[serde(default)]
+    pub events: VecDeque<Event>,
+    /// The cursor of the newest event ever recorded, even if it has since been dropped from `events`.
+    #[serde(default)]
+    pub last_event: u64,
+    #[serde(default)]
+    pub avatars: Avatars,
+    /// Hashes of the avatar files the user stores on this server, with their sizes.
+    #[serde(default)]
+    pub avatar_blobs: HashMap<String, u64>,
+    /// `profile.avatar` isn't kept here, it is always `avatars.default`.
+    #[serde(default)]
+    pub profile: ProfileSettings,
+    #[serde(default)]
+    pub privacy: PrivacySettings,
+    #[serde(default)]
+    pub blocks: BlockList,
 }
\ No newline at end of file